@@ -9,6 +9,7 @@
 // Feature flags
 #define ARDUINO_REV2 true  // Set this to false if you use the original Arduino Nano 33 BLE (Sense), not Rev2
 #define SEND_METRICS true
+#define SEND_SERIAL true  // Also send the payloads over USB, as hex lines, for `psylink --source serial:PATH`
 #define USE_INTERRUPT_TIMER false
 
 #include <ArduinoBLE.h>       // Bluetooth Low Energy
@@ -28,6 +29,7 @@
 #define BLE_NOTIFY_RATE 20 // updates per second
 #define BLE_CONNECTION_INTERVAL_MIN 8 // in steps of 1.25ms
 #define BLE_CONNECTION_INTERVAL_MAX 8 // in steps of 1.25ms
+#define SERIAL_BAUD_RATE 115200 // USB serial ignores this, but the host sets the same
 #define JUMPER_PIN_TO_DISABLE_IMU D2
 #define CHANNELS 8
 #define BUFFERS 2 // multiple buffers help with concurrency issues, if needed
@@ -58,7 +60,8 @@ NRF52_MBED_Timer samplingTimer(NRF_TIMER_3);
 #endif
 BLEDevice connectedDevice;
 BLEService sensorService("0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e4");
-BLEStringCharacteristic sensorCharacteristic("0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e5", BLERead, BLE_CHARACTERISTIC_SIZE);
+// Hosts may poll the samples or subscribe to notifications of every update
+BLEStringCharacteristic sensorCharacteristic("0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e5", BLERead | BLENotify, BLE_CHARACTERISTIC_SIZE);
 BLEIntCharacteristic channelCountCharacteristic("0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e6", BLERead);
 
 volatile bool doSampling = true;
@@ -69,6 +72,11 @@ int currentBuffer = 0;
 unsigned char tick = 1;
 char bleString[BLE_CHARACTERISTIC_SIZE] = {0};
 bool bleConnected = false;
+bool serialConnected = false;
+#if SEND_SERIAL == true
+const char HEX_DIGITS[] = "0123456789abcdef";
+char serialLine[2 * BLE_CHARACTERISTIC_SIZE + 1];
+#endif
 
 // Metrics
 #if SEND_METRICS == true
@@ -76,7 +84,9 @@ volatile unsigned long minSampleDelay, maxSampleDelay, lastSampleMicroSeconds =
 #endif
 
 void setup() {
-  //Serial.begin(115200);
+  #if SEND_SERIAL == true
+  Serial.begin(SERIAL_BAUD_RATE);
+  #endif
   analogReadResolution(12);
   pinMode(LED_BUILTIN, OUTPUT);
   pinMode(LEDR, OUTPUT);
@@ -131,12 +141,39 @@ void loop() {
     nextFrame = micros() + 1000;
     #endif
   }
+  #if SEND_SERIAL == true
+  updateSerialConnection();
+  #endif
   if (sendBuffer != NO_BUFFER) {
     updateSensorCharacteristic();
   }
   BLE.poll();
 }
 
+#if SEND_SERIAL == true
+void updateSerialConnection() {
+  // On the Nano 33 BLE, Serial is true while a host has the USB serial port open
+  bool connected = Serial;
+  if (connected && !serialConnected) {
+    // This header tells the host how to split the samples into channels
+    Serial.print("# channels: ");
+    Serial.println(CHANNELS);
+  }
+  serialConnected = connected;
+}
+
+// Sends the payload as one line of hex digits, the format of transport::LineSource
+void sendSerialLine(int length) {
+  int pos = 0;
+  for (int i = 0; i < length; i++) {
+    serialLine[pos++] = HEX_DIGITS[(bleString[i] >> 4) & 0xF];
+    serialLine[pos++] = HEX_DIGITS[bleString[i] & 0xF];
+  }
+  serialLine[pos++] = '\n';
+  Serial.write(serialLine, pos);
+}
+#endif
+
 void sensorCharacteristicRead(BLEDevice central, BLECharacteristic characteristic) {
   updateSensorCharacteristic();
 }
@@ -177,7 +214,7 @@ void readSamples() {
 }
 
 void updateSensorCharacteristic() {
-  if (sendBuffer == NO_BUFFER || !bleConnected) return;
+  if (sendBuffer == NO_BUFFER || !(bleConnected || serialConnected)) return;
   int pos = 0;
   char currentChar;
   float x, y, z;
@@ -225,7 +262,14 @@ void updateSensorCharacteristic() {
   }
 
   bleString[pos] = 0;  // End the string with 0x00
-  sensorCharacteristic.writeValue(bleString);
+  if (bleConnected) {
+    sensorCharacteristic.writeValue(bleString);
+  }
+  #if SEND_SERIAL == true
+  if (serialConnected) {
+    sendSerialLine(pos);
+  }
+  #endif
 
   // Reset values
   sendBuffer = NO_BUFFER;
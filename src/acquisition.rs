@@ -0,0 +1,776 @@
+// Keeps a signal source running.  Acquisition opens the source, decodes its payloads,
+// and when the connection breaks down, it reconnects with an increasing delay.  The
+// caller receives everything that happens as a stream of Events, so it can mark
+// outages in its recordings and tell the user what's going on.
+//
+// MultiAcquisition runs one Acquisition per device, e.g. a PsyLink on each forearm, and
+// merges their packets into packets with the channels of all devices side by side.
+
+use crate::error::{DecodeError, Error};
+use crate::prelude::*;
+use crate::transport::{Source, SourceList, SourceSpec, Transport};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: f64 = 0.5; // Seconds to wait before the first reconnection attempt
+const MAX_BACKOFF: f64 = 10.0; // The delay doubles with each attempt, up to this many seconds
+const MAX_LAG: f64 = 0.25; // Seconds that a device may fall behind before its samples are held
+const EVENT_BUFFER: usize = 64; // How many events each device may queue up
+
+pub enum Event {
+    /// The source was opened for the first time, or it came back with a different layout
+    Connected {
+        description: String,
+        layout: protocol::ChannelLayout,
+        device: session::DeviceInfo,
+    },
+    /// A packet was received and decoded.  Duplicates are passed on as well.
+    Packet(protocol::Packet),
+    /// A payload couldn't be decoded and was dropped
+    DecodeFailed(DecodeError),
+    /// The connection broke down.  If retry_in is None, the source is finished for good,
+    /// e.g. because a recording came to its end.  Otherwise, we reconnect after that delay.
+    Disconnected {
+        error: Error,
+        retry_in: Option<Duration>,
+        attempt: u32,
+    },
+    /// The source is back after an outage of the given length.  The decoder's time base
+    /// was advanced by the outage, so the timestamps of the next packet will show the gap.
+    Reconnected { description: String, outage: f64 },
+}
+
+/// What happened to a MultiAcquisition
+pub enum MultiEvent {
+    /// Something happened to one of the devices.  Their packets arrive as Packet events.
+    Device(usize, Event),
+    /// All devices are connected, and the following packets have this layout
+    Ready(protocol::ChannelLayout),
+    /// The samples of all devices, side by side.  With a single device, this is simply
+    /// the decoded packet, including duplicates.
+    Packet(protocol::Packet),
+    /// Packets of a device got lost.  This refers to the rows of the next Packet event.
+    Gap(RowGap),
+}
+
+/// Rows of one device that are missing from a merged packet, because packets got lost
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowGap {
+    pub device: usize,
+    pub offset: usize,   // The first row after the gap, or the first filled row
+    pub inserted: usize, // The rows that the decoder filled in for the lost packets
+    pub duration: f64,   // How many seconds of signal are missing
+}
+
+pub struct Acquisition {
+    app: App,
+    spec: SourceSpec,
+    source: Option<Source>,
+    decoder: Option<protocol::Decoder>,
+    gap_policy: protocol::GapPolicy,
+    attempt: u32,
+    retry_at: Option<Instant>,
+    outage_start: Option<Instant>,
+    finished: bool,
+}
+
+impl Acquisition {
+    pub fn new(app: &App, spec: SourceSpec) -> Self {
+        Self {
+            app: app.clone(),
+            spec,
+            source: None,
+            decoder: None,
+            gap_policy: protocol::GapPolicy::default(),
+            attempt: 0,
+            retry_at: None,
+            outage_start: None,
+            finished: false,
+        }
+    }
+
+    pub fn spec(&self) -> &SourceSpec {
+        &self.spec
+    }
+
+    pub fn set_gap_policy(&mut self, gap_policy: protocol::GapPolicy) {
+        self.gap_policy = gap_policy;
+        if let Some(decoder) = &mut self.decoder {
+            decoder.set_gap_policy(gap_policy);
+        }
+    }
+
+    /// Waits for the next thing that happens to the source.  Once the source is finished
+    /// for good, this never returns, so callers should also watch for other reasons to stop.
+    pub async fn next_event(
+        &mut self,
+        enable_accelerometer: bool,
+        enable_gyroscope: bool,
+    ) -> Event {
+        if self.finished {
+            return std::future::pending().await;
+        }
+
+        let Some(source) = &mut self.source else {
+            return self.connect().await;
+        };
+        let payload = match source.read().await {
+            Ok(payload) => payload,
+            Err(error) => {
+                let _ = source.disconnect().await;
+                self.source = None;
+                if let Some(decoder) = &mut self.decoder {
+                    // The tick counter of the next connection has nothing to do with this one
+                    decoder.reset();
+                }
+                self.outage_start.get_or_insert_with(Instant::now);
+                return self.fail(error);
+            }
+        };
+
+        if self.app.verbose > 1 {
+            let text = payload
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            println!("Received payload: {text};");
+        }
+
+        let decoder = self
+            .decoder
+            .as_mut()
+            .expect("Decoder should exist while connected");
+        match decoder.decode_packet(payload, enable_accelerometer, enable_gyroscope) {
+            Ok(packet) => Event::Packet(packet),
+            Err(err) => {
+                // A malformed payload only costs us this one packet, but a bogus tick
+                // would make the lost packet count of the next packet meaningless.
+                if let DecodeError::ImpossibleTickJump { .. } = err {
+                    decoder.reset();
+                }
+                Event::DecodeFailed(err)
+            }
+        }
+    }
+
+    pub async fn disconnect(&mut self) -> error::Result<()> {
+        match self.source.take() {
+            Some(mut source) => source.disconnect().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn connect(&mut self) -> Event {
+        if let Some(retry_at) = self.retry_at.take() {
+            tokio::time::sleep_until(retry_at).await;
+        }
+        let source = match self.spec.open(&self.app).await {
+            Ok(source) => source,
+            Err(error) => return self.fail(error),
+        };
+        self.attempt = 0;
+
+        let description = source.description();
+        let layout = source.layout();
+        let device = source.device_info();
+        let outage = self.outage_start.take().map(|start| start.elapsed());
+        let event = match (&mut self.decoder, outage) {
+            (Some(decoder), Some(outage)) if decoder.layout() == layout => {
+                decoder.skip(outage.as_secs_f64());
+                Event::Reconnected {
+                    description,
+                    outage: outage.as_secs_f64(),
+                }
+            }
+            _ => {
+                let mut decoder = protocol::Decoder::new(source.channel_count());
+                decoder.set_gap_policy(self.gap_policy);
+                self.decoder = Some(decoder);
+                Event::Connected {
+                    description,
+                    layout,
+                    device,
+                }
+            }
+        };
+        self.source = Some(source);
+        event
+    }
+
+    fn fail(&mut self, error: Error) -> Event {
+        self.attempt += 1;
+        let retry_in = if self.is_recoverable(&error) {
+            let delay = INITIAL_BACKOFF * 2f64.powi(self.attempt as i32 - 1);
+            let delay = Duration::from_secs_f64(delay.min(MAX_BACKOFF));
+            self.retry_at = Some(Instant::now() + delay);
+            Some(delay)
+        } else {
+            self.finished = true;
+            None
+        };
+        Event::Disconnected {
+            error,
+            retry_in,
+            attempt: self.attempt,
+        }
+    }
+
+    fn is_recoverable(&self, error: &Error) -> bool {
+        match (&self.spec, error) {
+            // Opening a recording again would only replay it from the start
+            (SourceSpec::File(_) | SourceSpec::Replay(_), _) => false,
+            (_, Error::Parse(_) | Error::Quit) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Settings that the device tasks of a MultiAcquisition pick up before each read
+struct Control {
+    enable_accelerometer: bool,
+    enable_gyroscope: bool,
+    gap_policy: protocol::GapPolicy,
+    stop: Vec<bool>, // for each device
+}
+
+/// Acquires several sources at once.  Each device gets its own task with its own
+/// Acquisition, so a device that is scanning or reconnecting doesn't hold up the others.
+pub struct MultiAcquisition {
+    events: mpsc::Receiver<(usize, Event)>,
+    control: Arc<Mutex<Control>>,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+    merger: Merger,
+    pending: VecDeque<MultiEvent>,
+    rate_meters: Vec<transport::RateMeter>,
+}
+
+impl MultiAcquisition {
+    pub fn new(app: &App, sources: &SourceList) -> Self {
+        let (sender, events) = mpsc::channel(EVENT_BUFFER * sources.len());
+        let control = Arc::new(Mutex::new(Control {
+            enable_accelerometer: true,
+            enable_gyroscope: true,
+            gap_policy: protocol::GapPolicy::default(),
+            stop: vec![false; sources.len()],
+        }));
+        let tasks = sources
+            .iter()
+            .enumerate()
+            .map(|(device, spec)| {
+                let acquisition = Acquisition::new(app, spec.clone());
+                tokio::spawn(run_device(
+                    device,
+                    acquisition,
+                    sender.clone(),
+                    control.clone(),
+                ))
+            })
+            .collect();
+        Self {
+            events,
+            control,
+            tasks,
+            merger: Merger::new(sources.len()),
+            pending: VecDeque::new(),
+            rate_meters: sources
+                .iter()
+                .map(|_| transport::RateMeter::new())
+                .collect(),
+        }
+    }
+
+    pub fn devices(&self) -> usize {
+        self.control.lock().unwrap().stop.len()
+    }
+
+    pub fn set_gap_policy(&mut self, gap_policy: protocol::GapPolicy) {
+        self.control.lock().unwrap().gap_policy = gap_policy;
+    }
+
+    /// The distinct packets per second that each device delivered recently
+    pub fn packet_rates(&self) -> Vec<f64> {
+        self.rate_meters.iter().map(|meter| meter.rate()).collect()
+    }
+
+    /// Waits for the next thing that happens to any of the devices
+    pub async fn next_event(
+        &mut self,
+        enable_accelerometer: bool,
+        enable_gyroscope: bool,
+    ) -> MultiEvent {
+        {
+            let mut control = self.control.lock().unwrap();
+            control.enable_accelerometer = enable_accelerometer;
+            control.enable_gyroscope = enable_gyroscope;
+        }
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return event;
+            }
+            if let Some((packet, gaps)) = self.merger.pop() {
+                self.pending.extend(gaps.into_iter().map(MultiEvent::Gap));
+                self.pending.push_back(MultiEvent::Packet(packet));
+                continue;
+            }
+            match self.events.recv().await {
+                Some((device, event)) => self.handle(device, event),
+                None => return std::future::pending().await,
+            }
+        }
+    }
+
+    /// Stops all devices and waits until they are disconnected
+    pub async fn disconnect(&mut self) {
+        self.control.lock().unwrap().stop.fill(true);
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    fn handle(&mut self, device: usize, event: Event) {
+        match event {
+            Event::Packet(packet) => {
+                if !packet.is_duplicate {
+                    self.rate_meters[device].tick();
+                }
+                if self.devices() == 1 {
+                    if packet.lost_packets > 0 {
+                        self.pending.push_back(MultiEvent::Gap(RowGap {
+                            device,
+                            offset: 0,
+                            inserted: packet.filled_samples as usize,
+                            duration: packet.lost_duration(),
+                        }));
+                    }
+                    self.pending.push_back(MultiEvent::Packet(packet));
+                } else if !packet.is_duplicate {
+                    self.merger.push(device, packet);
+                }
+                return;
+            }
+            Event::Connected { layout, .. } => {
+                if let Err(error) = self.merger.connect(device, layout) {
+                    // The rows of this device can't be merged with the others
+                    self.control.lock().unwrap().stop[device] = true;
+                    let event = Event::Disconnected {
+                        error,
+                        retry_in: None,
+                        attempt: 0,
+                    };
+                    self.pending.push_back(MultiEvent::Device(device, event));
+                    return;
+                }
+                self.pending.push_back(MultiEvent::Device(device, event));
+                if let Some(layout) = self.merger.layout() {
+                    self.pending.push_back(MultiEvent::Ready(layout));
+                }
+                return;
+            }
+            Event::Reconnected { .. } => self.merger.reconnect(device),
+            Event::Disconnected { .. } => self.merger.disconnect(device),
+            Event::DecodeFailed(_) => {}
+        }
+        self.pending.push_back(MultiEvent::Device(device, event));
+    }
+}
+
+impl Drop for MultiAcquisition {
+    fn drop(&mut self) {
+        // Let the device tasks disconnect on their own
+        self.control.lock().unwrap().stop.fill(true);
+    }
+}
+
+async fn run_device(
+    device: usize,
+    mut acquisition: Acquisition,
+    sender: mpsc::Sender<(usize, Event)>,
+    control: Arc<Mutex<Control>>,
+) {
+    loop {
+        let (enable_accelerometer, enable_gyroscope) = {
+            let control = control.lock().unwrap();
+            if control.stop[device] {
+                break;
+            }
+            acquisition.set_gap_policy(control.gap_policy);
+            (control.enable_accelerometer, control.enable_gyroscope)
+        };
+        let event = tokio::select! {
+            event = acquisition.next_event(enable_accelerometer, enable_gyroscope) => event,
+            _ = wait_for_stop(device, &control) => break,
+        };
+        if sender.send((device, event)).await.is_err() {
+            break;
+        }
+    }
+    let _ = acquisition.disconnect().await;
+}
+
+async fn wait_for_stop(device: usize, control: &Arc<Mutex<Control>>) {
+    while !control.lock().unwrap().stop[device] {
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+    }
+}
+
+/// A decoded sample row of one device, on the common clock
+struct Row {
+    timestamp: f64,
+    values: Vec<u8>,
+    filled: bool, // Inserted by the decoder for a lost packet
+    // On the first row after lost packets: how many rows were inserted from here on,
+    // and how many seconds of signal are missing
+    gap: Option<(usize, f64)>,
+}
+
+/// What the Merger knows about one device
+#[derive(Default)]
+struct DeviceStream {
+    layout: Option<protocol::ChannelLayout>,
+    offset: Option<f64>, // Add this to the decoder's timestamps to get the common clock
+    active: bool,
+    rows: VecDeque<Row>,
+    held: Option<Vec<u8>>, // The latest row that was merged
+    lost_packets: i32,     // Not reported in a merged packet yet
+    tick: i32,
+    min_sampling_delay: f64,
+    max_sampling_delay: f64,
+    sample_interval: f64,
+}
+
+/// Merges the packets of several devices into packets with the channels of all devices
+/// side by side.  The timestamps of each device are mapped to a common clock that starts
+/// with the Merger, based on when its first packet arrived.  The first connected device
+/// sets the pace: each of its rows is completed with the latest row of every other device.
+/// A device that lags or is disconnected has its latest row repeated.
+pub struct Merger {
+    devices: Vec<DeviceStream>,
+    start: Instant,
+    last_timestamp: f64, // of the latest merged row
+}
+
+impl Merger {
+    pub fn new(devices: usize) -> Self {
+        Self {
+            devices: (0..devices).map(|_| DeviceStream::default()).collect(),
+            start: Instant::now(),
+            last_timestamp: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Starts merging a device that was connected with a new decoder
+    pub fn connect(&mut self, device: usize, layout: protocol::ChannelLayout) -> error::Result<()> {
+        let mut layouts: Vec<protocol::ChannelLayout> = self
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != device)
+            .filter_map(|(_, stream)| stream.layout)
+            .collect();
+        layouts.push(layout);
+        protocol::ChannelLayout::merge(&layouts)?;
+
+        self.devices[device] = DeviceStream {
+            layout: Some(layout),
+            active: true,
+            ..DeviceStream::default()
+        };
+        Ok(())
+    }
+
+    /// The device is back with the same decoder, whose clock already accounts for the outage
+    pub fn reconnect(&mut self, device: usize) {
+        self.devices[device].active = true;
+    }
+
+    pub fn disconnect(&mut self, device: usize) {
+        let stream = &mut self.devices[device];
+        stream.active = false;
+        stream.rows.clear();
+    }
+
+    /// The layout of the merged packets, once every device has been connected
+    pub fn layout(&self) -> Option<protocol::ChannelLayout> {
+        let layouts: Option<Vec<protocol::ChannelLayout>> =
+            self.devices.iter().map(|stream| stream.layout).collect();
+        protocol::ChannelLayout::merge(&layouts?).ok()
+    }
+
+    pub fn push(&mut self, device: usize, packet: protocol::Packet) {
+        if self.layout().is_none() {
+            // There's nothing to merge the rows with yet
+            return;
+        }
+        let now = self.start.elapsed().as_secs_f64();
+        let stream = &mut self.devices[device];
+        let latest = packet.timestamps.last().copied().unwrap_or(0.0);
+        let offset = *stream.offset.get_or_insert(now - latest);
+
+        stream.lost_packets += packet.lost_packets;
+        stream.tick = packet.tick;
+        stream.min_sampling_delay = packet.min_sampling_delay;
+        stream.max_sampling_delay = packet.max_sampling_delay;
+        stream.sample_interval = packet.sample_interval;
+        let filled = packet.filled_samples as usize;
+        let gap = (packet.lost_packets > 0).then(|| (filled, packet.lost_duration()));
+        let rows = transpose_vec(packet.samples);
+        for (index, (values, timestamp)) in rows.into_iter().zip(packet.timestamps).enumerate() {
+            stream.rows.push_back(Row {
+                timestamp: timestamp + offset,
+                values,
+                filled: index < filled,
+                gap: gap.filter(|_| index == 0),
+            });
+        }
+    }
+
+    /// Returns the rows that can be merged so far, along with the gaps of every device
+    /// within these rows
+    pub fn pop(&mut self) -> Option<(protocol::Packet, Vec<RowGap>)> {
+        let layout = self.layout()?;
+        let reference = self.devices.iter().position(|stream| stream.active)?;
+
+        // Rows of a device that just took over the pace may already have been merged
+        let last_timestamp = self.last_timestamp;
+        let leader = &mut self.devices[reference];
+        leader.rows.retain(|row| row.timestamp > last_timestamp);
+        let newest = leader.rows.back()?.timestamp;
+
+        // Wait for the other devices to catch up, but not for too long
+        let mut cutoff = newest;
+        for (index, stream) in self.devices.iter().enumerate() {
+            if index != reference && stream.active {
+                let latest = stream.rows.back().map(|row| row.timestamp);
+                cutoff = cutoff.min(latest.unwrap_or(f64::NEG_INFINITY));
+            }
+        }
+        let cutoff = cutoff.max(newest - MAX_LAG);
+        let leader = &mut self.devices[reference];
+        let count = leader
+            .rows
+            .iter()
+            .take_while(|row| row.timestamp <= cutoff)
+            .count();
+        if count == 0 {
+            return None;
+        }
+        let rows: Vec<Row> = leader.rows.drain(..count).collect();
+
+        let mut samples = vec![Vec::with_capacity(count); layout.total_channels()];
+        let mut gaps: Vec<RowGap> = vec![];
+        for (offset, row) in rows.iter().enumerate() {
+            let mut channel = 0;
+            for (device, stream) in self.devices.iter_mut().enumerate() {
+                let (values, gap) = if device == reference {
+                    stream.held = Some(row.values.clone());
+                    (row.values.clone(), row.gap)
+                } else {
+                    stream.row_at(row.timestamp, layout.device_channels())
+                };
+                for value in values {
+                    samples[channel].push(value);
+                    channel += 1;
+                }
+                // The filled rows may reach into the next packets
+                if let Some((inserted, duration)) = gap {
+                    gaps.push(RowGap {
+                        device,
+                        offset,
+                        inserted,
+                        duration,
+                    });
+                }
+            }
+        }
+
+        let timestamps: Vec<f64> = rows.iter().map(|row| row.timestamp).collect();
+        self.last_timestamp = *timestamps.last().unwrap();
+        let filled_samples = rows.iter().filter(|row| row.filled).count() as i32;
+        let lost_packets = self
+            .devices
+            .iter_mut()
+            .map(|stream| std::mem::take(&mut stream.lost_packets))
+            .sum();
+        let leader = &self.devices[reference];
+        let packet = protocol::Packet {
+            channel_count: layout.total_channels() as i32,
+            tick: leader.tick,
+            min_sampling_delay: leader.min_sampling_delay,
+            max_sampling_delay: leader.max_sampling_delay,
+            sample_count: count as i32 - filled_samples,
+            samples,
+            filled_samples,
+            is_duplicate: false,
+            lost_packets,
+            sample_interval: leader.sample_interval,
+            timestamps,
+        };
+        Some((packet, gaps))
+    }
+}
+
+impl DeviceStream {
+    /// The latest row of this device at the given time on the common clock, and the gap
+    /// before it if it's the first row after lost packets
+    fn row_at(&mut self, timestamp: f64, channels: usize) -> (Vec<u8>, Option<(usize, f64)>) {
+        let mut gap = None;
+        while self
+            .rows
+            .front()
+            .is_some_and(|row| row.timestamp <= timestamp)
+        {
+            let row = self.rows.pop_front().unwrap();
+            gap = row.gap.or(gap);
+            self.held = Some(row.values);
+        }
+        // Before the first row of a device has been merged, its next row is the best guess
+        let values = self
+            .held
+            .clone()
+            .or_else(|| self.rows.front().map(|row| row.values.clone()))
+            .unwrap_or_else(|| vec![protocol::GAP_SENTINEL; channels]);
+        (values, gap)
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    // A serial port that returns EOF looks like a PsyLink that was unplugged
+    let mut simulator = simulator::Simulator::new(4);
+    let mut recording = vec![];
+    transport::write_header(&mut recording, 4).unwrap();
+    for _ in 0..3 {
+        transport::write_payload(&mut recording, &simulator.next_payload()).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("psylink_test_{}.txt", std::process::id()));
+    std::fs::write(&path, recording).unwrap();
+
+    let app = App {
+        verbose: 0,
+        scantime: 0.0,
+        source: SourceList::default(),
+        backend: Default::default(),
+    };
+    let mut acquisition = Acquisition::new(&app, SourceSpec::Serial(path.clone()));
+    assert!(matches!(
+        acquisition.next_event(true, true).await,
+        Event::Connected { layout, .. } if layout.emg_channels == 4
+    ));
+    let mut last_timestamp = 0.0;
+    for _ in 0..3 {
+        let Event::Packet(packet) = acquisition.next_event(true, true).await else {
+            panic!("Expected a packet");
+        };
+        last_timestamp = *packet.timestamps.last().unwrap();
+    }
+    assert!(matches!(
+        acquisition.next_event(true, true).await,
+        Event::Disconnected {
+            error: Error::EndOfStream,
+            retry_in: Some(_),
+            attempt: 1,
+        }
+    ));
+    let Event::Reconnected { outage, .. } = acquisition.next_event(true, true).await else {
+        panic!("Expected a reconnection");
+    };
+    assert!(outage >= INITIAL_BACKOFF);
+
+    // The same ticks arrive again, but the decoder must not take them for duplicates
+    let Event::Packet(packet) = acquisition.next_event(true, true).await else {
+        panic!("Expected a packet");
+    };
+    assert!(!packet.is_duplicate);
+    assert!(packet.timestamps[0] >= last_timestamp + outage);
+    std::fs::remove_file(&path).unwrap();
+
+    // A recording can't come back
+    let spec = SourceSpec::File(path);
+    let mut acquisition = Acquisition::new(&app, spec);
+    assert!(matches!(
+        acquisition.next_event(true, true).await,
+        Event::Disconnected { retry_in: None, .. }
+    ));
+}
+
+#[test]
+fn test_merger() {
+    let layout = protocol::ChannelLayout::new(4);
+    let mut merger = Merger::new(2);
+    merger.connect(0, layout).unwrap();
+    assert_eq!(merger.layout(), None);
+    assert!(merger.connect(1, protocol::ChannelLayout::new(8)).is_err());
+    merger.connect(1, layout).unwrap();
+    let merged = merger.layout().unwrap();
+    assert_eq!(merged.devices, 2);
+    assert_eq!(merged.total_channels(), 20);
+
+    let mut simulators: Vec<simulator::Simulator> = (0..2)
+        .map(|_| {
+            let mut simulator = simulator::Simulator::new(4);
+            simulator.set_realtime(false);
+            simulator
+        })
+        .collect();
+    let mut decoders = [protocol::Decoder::new(4), protocol::Decoder::new(4)];
+    decoders[1].set_gap_policy(protocol::GapPolicy::RepeatLast);
+    let mut read = |device: usize, lose: usize| {
+        for _ in 0..lose {
+            simulators[device].next_payload();
+        }
+        let payload = simulators[device].next_payload();
+        decoders[device].decode_packet(payload, true, true).unwrap()
+    };
+
+    let mut rows = 0;
+    let mut last_timestamp = f64::NEG_INFINITY;
+    for _ in 0..4 {
+        merger.push(0, read(0, 0));
+        merger.push(1, read(1, 0));
+        while let Some((packet, gaps)) = merger.pop() {
+            assert!(gaps.is_empty());
+            assert_eq!(packet.samples.len(), 20);
+            assert_eq!(packet.channel_count, 20);
+            assert!(packet.timestamps[0] > last_timestamp);
+            last_timestamp = *packet.timestamps.last().unwrap();
+            for channel in &packet.samples {
+                assert_eq!(channel.len(), packet.timestamps.len());
+                assert!(!channel.contains(&protocol::GAP_SENTINEL));
+            }
+            rows += packet.timestamps.len();
+        }
+    }
+    assert!(rows >= 3 * 25);
+
+    // The lost rows of the second device are marked where they are, not at the start of
+    // the merged packet
+    let mut gaps = vec![];
+    for lose in [0, 2, 0, 0] {
+        merger.push(0, read(0, 0));
+        merger.push(1, read(1, lose));
+        while let Some((packet, new_gaps)) = merger.pop() {
+            for gap in new_gaps {
+                gaps.push((rows + gap.offset, gap));
+            }
+            rows += packet.timestamps.len();
+        }
+    }
+    assert_eq!(gaps.len(), 1);
+    let (index, gap) = &gaps[0];
+    assert_eq!(gap.device, 1);
+    assert_eq!(gap.inserted, 50);
+    assert!(gap.duration > 0.0);
+    assert!(*index > 4 * 25);
+
+    // Without the second device, the first one goes on alone
+    merger.disconnect(1);
+    merger.push(0, read(0, 0));
+    let mut rows = 0;
+    while let Some((packet, _)) = merger.pop() {
+        rows += packet.timestamps.len();
+    }
+    assert!(rows >= 25);
+}
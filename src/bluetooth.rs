@@ -1,25 +1,46 @@
+use crate::error::{Error, Result};
 use crate::prelude::*;
 use btleplug::api::{
-    Central, Characteristic, Manager as _, Peripheral, PeripheralProperties, ScanFilter,
+    Central, CharPropFlags, Characteristic, Manager as _, Peripheral, PeripheralProperties,
+    ScanFilter, ValueNotification,
 };
-use btleplug::platform::Manager;
-use std::error::Error;
-use std::io;
+use btleplug::platform::{Adapter, Manager};
+use futures::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::time;
 use uuid::Uuid;
 
+const DEFAULT_NAME: &str = "PsyLink";
+
 //pub struct State {
 //    manager: Manager,
 //}
 
-#[derive(Clone)]
 pub struct Device {
     pub name: String,
     pub address: String,
+    /// Number of EMG channels, as reported by the device. Only valid after find_characteristics
+    pub channel_count: i32,
+    pub mode: ReadMode,
     peripheral: btleplug::platform::Peripheral,
     characteristics: Option<Characteristics>,
+    notifications: Option<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>>,
+}
+
+/// How the sensor data gets from the PsyLink to us
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReadMode {
+    /// The PsyLink pushes every new payload to us as soon as it's ready
+    Notify,
+    /// We keep reading the characteristic, and get the same payload again if it's not updated yet
+    #[default]
+    Poll,
 }
 
 #[derive(Clone)]
@@ -29,7 +50,7 @@ pub struct Characteristics {
 }
 
 impl Device {
-    pub async fn find_characteristics(&mut self) {
+    pub async fn find_characteristics(&mut self) -> Result<()> {
         let uuid_sensor = Uuid::parse_str(firmware::SENSOR_CHARACTERISTICS_UUID).unwrap();
         let uuid_channel_count =
             Uuid::parse_str(firmware::CHANNEL_COUNT_CHARACTERISTICS_UUID).unwrap();
@@ -40,112 +61,407 @@ impl Device {
         let chr_sensor = characteristics
             .iter()
             .find(|c| c.uuid == uuid_sensor)
-            .unwrap();
+            .ok_or(Error::MissingCharacteristic(
+                firmware::SENSOR_CHARACTERISTICS_UUID,
+            ))?;
         let chr_channel_count = characteristics
             .iter()
             .find(|c| c.uuid == uuid_channel_count)
-            .unwrap();
+            .ok_or(Error::MissingCharacteristic(
+                firmware::CHANNEL_COUNT_CHARACTERISTICS_UUID,
+            ))?;
+
+        // The firmware announces its channel count as a little-endian 32-bit integer
+        let raw = self.peripheral.read(chr_channel_count).await?;
+        let channel_count = match raw.get(0..4) {
+            Some(bytes) => i32::from_le_bytes(bytes.try_into().unwrap()),
+            None => return Err(Error::InvalidChannelCount(-1)),
+        };
+        if channel_count <= 0 || channel_count > u8::MAX as i32 {
+            return Err(Error::InvalidChannelCount(channel_count));
+        }
+        self.channel_count = channel_count;
+
+        // Firmware versions that don't offer notifications can still be polled
+        self.mode = ReadMode::Poll;
+        self.notifications = None;
+        if chr_sensor.properties.contains(CharPropFlags::NOTIFY) {
+            match Self::subscribe(&self.peripheral, chr_sensor).await {
+                Ok(notifications) => {
+                    self.notifications = Some(notifications);
+                    self.mode = ReadMode::Notify;
+                }
+                Err(err) => {
+                    println!("Failed to subscribe to notifications, polling instead: {err}")
+                }
+            }
+        }
 
         self.characteristics = Some(Characteristics {
             _channel_count: chr_channel_count.clone(),
             sensor: chr_sensor.clone(),
         });
+        Ok(())
     }
 
-    pub async fn read(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        if let Some(chr) = &self.characteristics {
-            Ok(self.peripheral.read(&chr.sensor).await?)
-        } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Must load characteristics before calling read()",
-            )))
+    async fn subscribe(
+        peripheral: &btleplug::platform::Peripheral,
+        characteristic: &Characteristic,
+    ) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        // Get the stream first, so that no notification slips through in between
+        let notifications = peripheral.notifications().await?;
+        peripheral.subscribe(characteristic).await?;
+        Ok(notifications)
+    }
+
+    pub fn layout(&self) -> protocol::ChannelLayout {
+        protocol::ChannelLayout::new(self.channel_count as usize)
+    }
+
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        let Some(chr) = &self.characteristics else {
+            return Err(Error::NotConnected);
+        };
+        match &mut self.notifications {
+            Some(notifications) => loop {
+                // The stream only ends when the connection is gone
+                let notification = notifications.next().await.ok_or(Error::Disconnected)?;
+                if notification.uuid == chr.sensor.uuid {
+                    return Ok(notification.value);
+                }
+            },
+            None => Ok(self.peripheral.read(&chr.sensor).await?),
         }
     }
 
-    pub async fn disconnect(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn disconnect(&mut self) -> Result<()> {
         self.peripheral.disconnect().await?;
         Ok(())
     }
 }
 
-pub async fn scan(app: App) -> Result<(), Box<dyn Error>> {
-    println!("Scanning Bluetooth for PsyLink device...");
+impl fmt::Display for ReadMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadMode::Notify => write!(f, "notifications"),
+            ReadMode::Poll => write!(f, "polling"),
+        }
+    }
+}
+
+/// Which PsyLink to connect to, if several are in reach
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceFilter {
+    pub address: Option<String>, // MAC address, case-insensitive
+    pub name: String,            // Pattern for the local name, may contain * and ?
+    pub adapter: Option<String>, // Index or part of the description of the Bluetooth adapter
+    pub last: bool,              // Only accept the device that we connected to last time
+}
+
+/// A device that was found while scanning
+#[derive(Clone, Debug)]
+pub struct ScanResult {
+    pub address: String,
+    pub name: String,
+    pub rssi: Option<i16>,
+    pub tx_power: Option<i16>,
+    pub services: Vec<Uuid>, // Advertised services
+    pub adapter: String,
+    pub first_seen: SystemTime,
+}
+
+/// How `psylink scan` prints the devices it finds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScanFormat {
+    #[default]
+    Text,
+    Json, // One JSON object per line
+    Csv,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self {
+            address: None,
+            name: DEFAULT_NAME.into(),
+            adapter: None,
+            last: false,
+        }
+    }
+}
+
+impl DeviceFilter {
+    /// Replaces the `last` flag with the address of the last used device, if we know it
+    fn resolve(&self) -> Self {
+        let mut filter = self.clone();
+        if self.last {
+            match load_last_device() {
+                Some(address) => filter.address = Some(address),
+                None => println!("No PsyLink was used before, accepting any device."),
+            }
+            filter.last = false;
+        }
+        filter
+    }
+
+    pub fn matches_device(&self, address: &str, name: &str) -> bool {
+        let address_ok = self
+            .address
+            .as_ref()
+            .map_or(true, |wanted| wanted.eq_ignore_ascii_case(address));
+        address_ok && glob_match(&self.name, name)
+    }
+
+    pub fn matches_adapter(&self, index: usize, description: &str) -> bool {
+        match &self.adapter {
+            Some(wanted) => {
+                *wanted == index.to_string()
+                    || description.to_lowercase().contains(&wanted.to_lowercase())
+            }
+            None => true,
+        }
+    }
+}
+
+impl ScanResult {
+    pub const CSV_HEADER: &'static str =
+        "address,name,rssi,tx_power,services,sensor_service,adapter,first_seen";
+
+    /// Whether the device advertises the service that the PsyLink firmware streams through
+    pub fn has_sensor_service(&self) -> bool {
+        let sensor_service = Uuid::parse_str(firmware::SENSOR_SERVICE_UUID).unwrap();
+        self.services.contains(&sensor_service)
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn first_seen_timestamp(&self) -> f64 {
+        self.first_seen
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64())
+    }
+
+    pub fn to_json(&self) -> String {
+        let services: Vec<String> = self.services.iter().map(Uuid::to_string).collect();
+        serde_json::json!({
+            "address": self.address,
+            "name": self.name,
+            "rssi": self.rssi,
+            "tx_power": self.tx_power,
+            "services": services,
+            "sensor_service": self.has_sensor_service(),
+            "adapter": self.adapter,
+            "first_seen": (self.first_seen_timestamp() * 1000.0).round() / 1000.0,
+        })
+        .to_string()
+    }
+
+    /// A line matching CSV_HEADER.  The services are separated by spaces.
+    pub fn to_csv(&self) -> String {
+        let optional = |value: Option<i16>| value.map_or(String::new(), |v| v.to_string());
+        let services: Vec<String> = self.services.iter().map(|uuid| uuid.to_string()).collect();
+        [
+            recording::csv_field(&self.address),
+            recording::csv_field(&self.name),
+            optional(self.rssi),
+            optional(self.tx_power),
+            recording::csv_field(&services.join(" ")),
+            self.has_sensor_service().to_string(),
+            recording::csv_field(&self.adapter),
+            format!("{:.3}", self.first_seen_timestamp()),
+        ]
+        .join(",")
+    }
+
+    pub fn to_text(&self) -> String {
+        let rssi = self
+            .rssi
+            .map_or("unknown".into(), |rssi| format!("{rssi} dBm"));
+        format!(
+            "Found {} with address {}, RSSI {rssi}, on adapter {}",
+            self.name, self.address, self.adapter
+        )
+    }
+
+    pub fn format(&self, format: ScanFormat) -> String {
+        match format {
+            ScanFormat::Text => self.to_text(),
+            ScanFormat::Json => self.to_json(),
+            ScanFormat::Csv => self.to_csv(),
+        }
+    }
+}
+
+impl FromStr for ScanFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> std::result::Result<Self, Self::Err> {
+        match text.to_lowercase().as_str() {
+            "text" => Ok(ScanFormat::Text),
+            "json" => Ok(ScanFormat::Json),
+            "csv" => Ok(ScanFormat::Csv),
+            _ => Err(format!(
+                "Unknown format \"{text}\", expected text, json or csv"
+            )),
+        }
+    }
+}
+
+impl FromStr for DeviceFilter {
+    type Err = String;
+
+    /// Parses comma-separated settings like "address=AA:BB:CC:DD:EE:FF,adapter=hci1"
+    /// or "name=PsyLink*,last"
+    fn from_str(text: &str) -> std::result::Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        for setting in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match setting.split_once('=') {
+                Some(("address", address)) => filter.address = Some(address.into()),
+                Some(("name", name)) => filter.name = name.into(),
+                Some(("adapter", adapter)) => filter.adapter = Some(adapter.into()),
+                None if setting == "last" => filter.last = true,
+                _ => return Err(format!("Unknown Bluetooth device setting \"{setting}\"")),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut settings = vec![];
+        if let Some(address) = &self.address {
+            settings.push(format!("address={address}"));
+        }
+        if self.name != DEFAULT_NAME {
+            settings.push(format!("name={}", self.name));
+        }
+        if let Some(adapter) = &self.adapter {
+            settings.push(format!("adapter={adapter}"));
+        }
+        if self.last {
+            settings.push("last".into());
+        }
+        write!(f, "{}", settings.join(","))
+    }
+}
 
+/// Starts scanning on all adapters that match the filter
+async fn start_scanning(app: &App, filter: &DeviceFilter) -> Result<Vec<(Adapter, String)>> {
     let manager = Manager::new().await?;
     let adapter_list = manager.adapters().await?;
     if adapter_list.is_empty() {
         eprintln!("No Bluetooth adapters found");
     }
 
-    for adapter in adapter_list.iter() {
+    let mut adapters = vec![];
+    for (index, adapter) in adapter_list.into_iter().enumerate() {
+        let adapter_info = adapter.adapter_info().await?;
+        if !filter.matches_adapter(index, &adapter_info) {
+            continue;
+        }
         if app.verbose > 0 {
-            println!(
-                "Trying bluetooth adapter {}...",
-                adapter.adapter_info().await?
-            );
-        }
-        adapter
-            .start_scan(ScanFilter::default())
-            .await
-            .expect("Can't scan BLE adapter for connected devices...");
-        time::sleep(Duration::from_secs_f32(app.scantime)).await;
+            eprintln!("Trying bluetooth adapter {adapter_info}...");
+        }
+        if let Err(error) = adapter.start_scan(ScanFilter::default()).await {
+            eprintln!("Can't scan with bluetooth adapter {adapter_info}: {error}");
+            continue;
+        }
+        adapters.push((adapter, adapter_info));
+    }
+    Ok(adapters)
+}
 
+/// Lists the PsyLinks that the adapters have seen so far.  `first_seen` remembers when
+/// each address showed up for the first time.
+async fn collect_results(
+    app: &App,
+    adapters: &[(Adapter, String)],
+    filter: &DeviceFilter,
+    first_seen: &mut HashMap<String, SystemTime>,
+) -> Result<Vec<ScanResult>> {
+    let mut results = vec![];
+    for (adapter, adapter_info) in adapters {
         let peripherals = adapter.peripherals().await?;
         if peripherals.is_empty() {
             eprintln!("No BLE peripheral devices found.");
-        } else {
-            for peripheral in peripherals.iter() {
-                let properties = peripheral.properties().await?;
-                if app.verbose > 1 {
-                    dbg!(&properties);
-                }
-                if let Some(PeripheralProperties {
-                    address,
-                    local_name: Some(name),
-                    ..
-                }) = &properties
-                {
-                    if name == "PsyLink" {
-                        println!("Found PsyLink device with address {address}");
-                    }
+        }
+        for peripheral in peripherals.iter() {
+            let properties = peripheral.properties().await?;
+            if app.verbose > 1 {
+                dbg!(&properties);
+            }
+            if let Some(PeripheralProperties {
+                address,
+                local_name: Some(name),
+                rssi,
+                tx_power_level,
+                services,
+                ..
+            }) = properties
+            {
+                let address = address.to_string();
+                if filter.matches_device(&address, &name) {
+                    let first_seen = *first_seen
+                        .entry(address.clone())
+                        .or_insert_with(SystemTime::now);
+                    results.push(ScanResult {
+                        address,
+                        name,
+                        rssi,
+                        tx_power: tx_power_level,
+                        services,
+                        adapter: adapter_info.clone(),
+                        first_seen,
+                    });
                 }
             }
         }
     }
-    Ok(())
+    Ok(results)
 }
 
-pub async fn stream(app: App) -> Result<(), Box<dyn Error>> {
-    println!("Scanning Bluetooth for PsyLink device...");
-    let manager = Manager::new().await?;
-    let adapter_list = manager.adapters().await?;
-    if adapter_list.is_empty() {
-        eprintln!("No Bluetooth adapters found");
-    }
-    let sensor_uuid = Uuid::parse_str(firmware::SENSOR_CHARACTERISTICS_UUID).unwrap();
-
-    let psylink = find_peripheral(app, None).await?;
+/// Lists all PsyLinks that match the filter, on all matching adapters
+pub async fn discover(app: &App, filter: &DeviceFilter) -> Result<Vec<ScanResult>> {
+    let filter = filter.resolve();
+    let adapters = start_scanning(app, &filter).await?;
+    time::sleep(Duration::from_secs_f32(app.scantime)).await;
+    collect_results(app, &adapters, &filter, &mut HashMap::new()).await
+}
 
-    let _ = psylink.peripheral.connect().await;
-    let _ = psylink.peripheral.discover_services().await;
-    let characteristics = psylink.peripheral.characteristics();
+/// Prints the PsyLinks in reach.  With `watch`, this keeps scanning and prints all of
+/// them again after every `scantime` seconds, with their current signal strength.
+pub async fn scan(app: &App, filter: &DeviceFilter, format: ScanFormat, watch: bool) -> Result<()> {
+    // Keep stdout clean for the machine-readable formats
+    eprintln!("Scanning Bluetooth for PsyLink device...");
+    let filter = filter.resolve();
+    let adapters = start_scanning(app, &filter).await?;
+    let mut first_seen = HashMap::new();
+    if format == ScanFormat::Csv {
+        println!("{}", ScanResult::CSV_HEADER);
+    }
 
-    let sensor_characteristic = characteristics
-        .iter()
-        .find(|c| c.uuid == sensor_uuid)
-        .unwrap();
     loop {
-        let data = psylink.peripheral.read(sensor_characteristic).await?;
-        dbg!(data);
+        time::sleep(Duration::from_secs_f32(app.scantime)).await;
+        let results = collect_results(app, &adapters, &filter, &mut first_seen).await?;
+        for result in &results {
+            println!("{}", result.format(format));
+        }
+        if !watch {
+            if results.is_empty() {
+                eprintln!("No matching PsyLink found.");
+            }
+            return Ok(());
+        }
     }
 }
 
 pub async fn find_peripheral(
-    app: App,
+    app: &App,
+    filter: &DeviceFilter,
     mutex_quit: Option<Arc<Mutex<bool>>>,
-) -> Result<Device, Box<dyn Error>> {
+) -> Result<Device> {
     println!("Scanning Bluetooth for PsyLink device...");
+    let filter = filter.resolve();
 
     let manager = Manager::new().await?;
     let adapter_list = manager.adapters().await?;
@@ -154,12 +470,13 @@ pub async fn find_peripheral(
     }
 
     loop {
-        for adapter in adapter_list.iter() {
+        for (index, adapter) in adapter_list.iter().enumerate() {
+            let adapter_info = adapter.adapter_info().await?;
+            if !filter.matches_adapter(index, &adapter_info) {
+                continue;
+            }
             if app.verbose > 0 {
-                println!(
-                    "Trying Bluetooth adapter {}...",
-                    adapter.adapter_info().await?
-                );
+                println!("Trying Bluetooth adapter {adapter_info}...");
             }
             let _ = adapter.start_scan(ScanFilter::default()).await;
             //.expect("Can't scan BLE adapter for connected devices...");
@@ -180,13 +497,18 @@ pub async fn find_peripheral(
                         ..
                     }) = &properties
                     {
-                        if name == "PsyLink" {
+                        // Another acquisition may already be streaming from this one
+                        let connected = peripheral.is_connected().await.unwrap_or(false);
+                        if filter.matches_device(&address.to_string(), name) && !connected {
                             println!("Found PsyLink device with address {address}");
                             return Ok(Device {
                                 name: name.to_string(),
                                 address: address.to_string(),
+                                channel_count: firmware::DEFAULT_CHANNEL_COUNT,
+                                mode: ReadMode::default(),
                                 peripheral: peripheral.clone(),
                                 characteristics: None,
+                                notifications: None,
                             });
                         }
                     }
@@ -196,8 +518,116 @@ pub async fn find_peripheral(
         if let Some(mutex_quit) = &mutex_quit {
             if *(mutex_quit.lock().unwrap()) {
                 println!("Quitting bluetooth::Device::find_peripheral");
-                return Err(Box::new(io::Error::new(io::ErrorKind::Other, "The bluetooth::Device::find_peripheral function received a quit command while scanning for peripherals")));
+                return Err(Error::Quit);
             }
         }
     }
 }
+
+fn last_device_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("psylink").join("last_device"))
+}
+
+/// Returns the address of the PsyLink that we connected to last time
+pub fn load_last_device() -> Option<String> {
+    let address = std::fs::read_to_string(last_device_path()?).ok()?;
+    Some(address.trim().to_string()).filter(|address| !address.is_empty())
+}
+
+pub fn save_last_device(address: &str) -> Result<()> {
+    let path = last_device_path().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "No home directory to save to")
+    })?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, address)?;
+    Ok(())
+}
+
+/// Matches text against a pattern where * stands for any text and ? for any character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None; // Position of the last * and the text position it matched up to
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[test]
+fn test_device_filter() {
+    let filter: DeviceFilter = "address=aa:bb:cc:dd:ee:ff,name=PsyLink*,adapter=hci1"
+        .parse()
+        .unwrap();
+    assert!(filter.matches_device("AA:BB:CC:DD:EE:FF", "PsyLink 2"));
+    assert!(!filter.matches_device("AA:BB:CC:DD:EE:00", "PsyLink 2"));
+    assert!(!filter.matches_device("AA:BB:CC:DD:EE:FF", "Headphones"));
+    assert!(filter.matches_adapter(3, "hci1 (usb:v1D6Bp0246d0552)"));
+    assert!(!filter.matches_adapter(1, "hci0 (usb:v1D6Bp0246d0552)"));
+    assert_eq!(filter.to_string().parse::<DeviceFilter>().unwrap(), filter);
+
+    let filter = DeviceFilter::default();
+    assert!(filter.matches_device("AA:BB:CC:DD:EE:FF", "PsyLink"));
+    assert!(!filter.matches_device("AA:BB:CC:DD:EE:FF", "PsyLink 2"));
+    assert!(filter.matches_adapter(0, "hci0"));
+    assert_eq!(filter.to_string(), "");
+    assert!("last".parse::<DeviceFilter>().unwrap().last);
+    assert!("color=red".parse::<DeviceFilter>().is_err());
+
+    assert!(glob_match("Psy?ink*", "PsyLink armband"));
+    assert!(glob_match("*Link", "PsyLink"));
+    assert!(!glob_match("*Link", "PsyLinks"));
+}
+
+#[test]
+fn test_scan_result() {
+    let mut result = ScanResult {
+        address: "AA:BB:CC:DD:EE:FF".into(),
+        name: "PsyLink \"left\"\r".into(),
+        rssi: Some(-60),
+        tx_power: None,
+        services: vec![Uuid::parse_str(firmware::SENSOR_SERVICE_UUID).unwrap()],
+        adapter: "hci0 (usb:v1D6Bp0246d0540)".into(),
+        first_seen: UNIX_EPOCH + Duration::from_millis(1500),
+    };
+    assert!(result.has_sensor_service());
+    assert_eq!(
+        result.to_json(),
+        "{\"address\":\"AA:BB:CC:DD:EE:FF\",\"name\":\"PsyLink \\\"left\\\"\\r\",\"rssi\":-60,\"tx_power\":null,\"services\":[\"0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e4\"],\"sensor_service\":true,\"adapter\":\"hci0 (usb:v1D6Bp0246d0540)\",\"first_seen\":1.5}"
+    );
+    assert_eq!(
+        result.to_csv(),
+        "AA:BB:CC:DD:EE:FF,\"PsyLink \"\"left\"\"\r\",-60,,0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e4,true,hci0 (usb:v1D6Bp0246d0540),1.500"
+    );
+    assert_eq!(
+        result.to_csv().split(',').count(),
+        ScanResult::CSV_HEADER.split(',').count()
+    );
+
+    result.name = "PsyLink\r".into();
+    assert!(result.to_csv().contains(",\"PsyLink\r\","));
+
+    result.services.clear();
+    assert!(!result.has_sensor_service());
+    assert_eq!("JSON".parse(), Ok(ScanFormat::Json));
+    assert!("xml".parse::<ScanFormat>().is_err());
+}
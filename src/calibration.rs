@@ -1,8 +1,16 @@
 // This should be the *only* file that interfaces with the burn library.
 
-use burn::backend::{Autodiff, Wgpu};
+use crate::classifier::{self, Classifier, ClassifierKind};
+use crate::dsp;
+use crate::error;
+use crate::features;
+use crate::firmware;
+use crate::protocol;
+use crate::session::{self, Chunk};
+use burn::backend::Autodiff;
 use burn::data::dataloader::batcher::Batcher;
 use burn::data::dataloader::{DataLoaderBuilder, Dataset};
+use burn::module::AutodiffModule;
 use burn::nn::{
     conv::{Conv2d, Conv2dConfig},
     loss::CrossEntropyLoss,
@@ -23,11 +31,20 @@ use burn::train::{
 };
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
 
 pub const DEFAULT_MAX_DATAPOINTS: usize = 4000;
 pub const DEFAULT_EPOCHS: usize = 6;
+pub const ARTIFACT_DIR: &str = "/tmp/psylink"; // Where training saves the latest model
 const VALIDATION_SET_PERCENTAGE: usize = 20;
 const SAMPLE_TIMESPAN: usize = 250; // How many time frames should a training sample contain?
+const MIN_CHANNELS: usize = 9; // The two 5x5 convolutions need a sample at least this wide
+const SESSION_BLOCK: usize = 1000; // How many packets to put into each chunk of a session file
 pub const TEST_DATASET: ([(usize, u8); 31100], [[u8; 14]; 59925]) =
     include!("data/test_dataset.rs");
 pub const TEST_MODEL: &[u8] = include_bytes!("data/test_model.bin");
@@ -36,14 +53,137 @@ pub const TEST_MODEL: &[u8] = include_bytes!("data/test_model.bin");
 #[derive(Clone, Default, Debug)]
 pub struct CalibController {
     pub dataset: PsyLinkDataset,
+    pub backend: BackendKind, // Where train trains the neural network
+    input_filter: Option<InputFilter>,
 }
 
-pub type DefaultBackend = Autodiff<Wgpu>;
-pub type DefaultModel = Model<Autodiff<Wgpu>>;
+// Filters the packets of the dataset for the classifier as they come in, with the filters
+// that the model was trained with
+#[derive(Clone, Debug)]
+struct InputFilter {
+    filter: dsp::StreamFilter,
+    position: usize,           // The next packet of the dataset to filter
+    window: VecDeque<Vec<u8>>, // The last SAMPLE_TIMESPAN filtered packets
+}
+
+/// The backends that burn can train and run the neural network on.  Only those that were
+/// enabled as cargo features can be used, the others fail with an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Wgpu,    // On the GPU
+    NdArray, // On the CPU, works everywhere
+}
+
+#[cfg(not(any(feature = "wgpu", feature = "ndarray")))]
+compile_error!("PsyLink needs a backend for burn, enable the feature wgpu or ndarray");
+
+// Evaluates the body with the type alias B set to the backend of the given kind, which
+// must have been compiled in.  The body returns an error::Result.
+macro_rules! with_backend {
+    ($kind:expr, $B:ident => $body:expr) => {
+        match $kind {
+            #[cfg(feature = "wgpu")]
+            BackendKind::Wgpu => {
+                type $B = burn::backend::Wgpu;
+                $body
+            }
+            #[cfg(feature = "ndarray")]
+            BackendKind::NdArray => {
+                type $B = burn::backend::NdArray;
+                $body
+            }
+            #[allow(unreachable_patterns)]
+            kind => Err(error::Error::Calibration(format!(
+                "PsyLink was built without the {kind} backend, rebuild it with --features {kind}"
+            ))),
+        }
+    };
+}
+
+impl BackendKind {
+    pub const ALL: [BackendKind; 2] = [BackendKind::Wgpu, BackendKind::NdArray];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendKind::Wgpu => "wgpu",
+            BackendKind::NdArray => "ndarray",
+        }
+    }
+
+    /// Whether this backend was compiled in
+    pub fn is_available(&self) -> bool {
+        match self {
+            BackendKind::Wgpu => cfg!(feature = "wgpu"),
+            BackendKind::NdArray => cfg!(feature = "ndarray"),
+        }
+    }
+}
+
+impl Default for BackendKind {
+    /// The GPU if it was compiled in, otherwise the CPU
+    fn default() -> Self {
+        BackendKind::ALL
+            .into_iter()
+            .find(BackendKind::is_available)
+            .unwrap_or(BackendKind::Wgpu)
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = error::Error;
+
+    fn from_str(text: &str) -> error::Result<Self> {
+        BackendKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == text.trim())
+            .ok_or_else(|| {
+                error::Error::Parse(format!(
+                    "Unknown backend \"{text}\", expected wgpu or ndarray"
+                ))
+            })
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 impl CalibController {
-    pub fn add_packet(&mut self, sample: Vec<u8>) {
+    pub fn new(dataset: PsyLinkDataset) -> Self {
+        Self {
+            dataset,
+            ..Self::default()
+        }
+    }
+
+    /// Replaces the recorded data, e.g. with a loaded session
+    pub fn set_dataset(&mut self, dataset: PsyLinkDataset) {
+        self.dataset = dataset;
+        self.input_filter = None;
+    }
+
+    /// Switches the dataset to a different channel layout. Packets of different layouts
+    /// can't be mixed, so this discards all recorded data if the layout changes.
+    /// Returns true if data was discarded.
+    pub fn set_layout(&mut self, layout: protocol::ChannelLayout) -> bool {
+        if self.dataset.layout == layout {
+            return false;
+        }
+        let had_data = !self.dataset.all_packets.is_empty();
+        self.reset();
+        self.dataset.layout = layout;
+        had_data
+    }
+
+    pub fn add_packet(&mut self, sample: Vec<u8>, timestamp: f64) {
         self.dataset.all_packets.push(sample);
+        self.dataset.timestamps.push(timestamp);
+    }
+
+    pub fn add_gap(&mut self, gap: Gap) {
+        self.dataset.gaps.push(gap);
     }
 
     pub fn add_datapoint(&mut self, datapoint: Datapoint) {
@@ -55,17 +195,21 @@ impl CalibController {
     }
 
     pub fn has_datapoints(&self) -> bool {
-        return !self.dataset.datapoints.is_empty();
+        !self.dataset.datapoints.is_empty()
     }
 
     pub fn reset(&mut self) {
         self.dataset.datapoints.clear();
         self.dataset.all_packets.clear();
+        self.dataset.timestamps.clear();
+        self.dataset.gaps.clear();
+        self.dataset.info.action_names.clear();
+        self.input_filter = None;
     }
 
     // When you use this method, make sure to add the packet first.
     pub fn get_current_index(&self) -> usize {
-        return self.dataset.all_packets.len();
+        self.dataset.all_packets.len()
     }
 
     fn create_artifact_dir(artifact_dir: &str) {
@@ -74,60 +218,142 @@ impl CalibController {
         std::fs::create_dir_all(artifact_dir).ok();
     }
 
-    pub fn infer_latest(&self, model: DefaultModel) -> Option<i32> {
-        let item = self.dataset.get_latest()?;
-        Some(infer_item(model, item))
+    /// Predicts the action of the latest packets with the neural network or any other
+    /// classifier
+    pub fn infer_latest(&mut self, classifier: &dyn Classifier) -> error::Result<Option<i32>> {
+        let expected = classifier.channel_count();
+        let found = self.dataset.layout.total_channels();
+        if expected != found {
+            return Err(error::Error::ChannelMismatch { expected, found });
+        }
+        let filter = classifier.filter_chain()?;
+        let latest = match filter.is_empty() {
+            true => self.dataset.get_latest(),
+            false => self.get_latest_filtered(&filter),
+        };
+        let layout = self.dataset.layout;
+        latest
+            .map(|item| classifier.predict(&item.features, layout))
+            .transpose()
     }
 
+    /// Like PsyLinkDataset::get_latest, but with filtered packets.  Only the packets that
+    /// were added since the last call get filtered, continuing where the filter stopped.
+    fn get_latest_filtered(&mut self, filter: &dsp::FilterChain) -> Option<TrainingSample> {
+        let len = self.dataset.all_packets.len();
+        let outdated = match &self.input_filter {
+            Some(input) => input.filter.chain() != filter || input.position > len,
+            None => true,
+        };
+        if outdated {
+            let sample_rate = protocol::estimate_sample_rate(&self.dataset.timestamps);
+            self.input_filter = Some(InputFilter {
+                filter: dsp::StreamFilter::new(filter, self.dataset.layout, sample_rate),
+                position: 0,
+                window: VecDeque::with_capacity(SAMPLE_TIMESPAN + 1),
+            });
+        }
+        let input = self.input_filter.as_mut()?;
+        for packet in &self.dataset.all_packets[input.position..] {
+            input.window.push_back(input.filter.filter_row(packet));
+            if input.window.len() > SAMPLE_TIMESPAN {
+                input.window.pop_front();
+            }
+        }
+        input.position = len;
+        if input.window.len() < SAMPLE_TIMESPAN {
+            return None;
+        }
+        Some(TrainingSample {
+            features: input.window.iter().cloned().collect(),
+            label: 0,
+        })
+    }
+
+    /// Trains a model on the recorded data after passing it through the given filters,
+    /// with the given representation of the samples as model input.  The model remembers
+    /// both, so that infer_latest prepares its input in the same way.
     pub fn train(
         &self,
         action_count: usize,
         epochs: usize,
         max_datapoints: usize,
-    ) -> Result<DefaultModel, Box<dyn std::error::Error>> {
-        // Create a default Wgpu device
-        let device = burn::backend::wgpu::WgpuDevice::default();
-
-        // All the training artifacts will be saved in this directory
-        let artifact_dir = "/tmp/psylink";
+        exclude_gaps: bool,
+        filter: &dsp::FilterChain,
+        input: &features::Input,
+    ) -> error::Result<Box<dyn Classifier>> {
+        let channel_count = self.dataset.layout.total_channels();
+        let [_, columns] = input.dims(self.dataset.layout, SAMPLE_TIMESPAN);
+        if columns < MIN_CHANNELS {
+            return Err(error::Error::Calibration(match input {
+                features::Input::Raw => format!(
+                    "Need at least {MIN_CHANNELS} channels to train a model, got {channel_count}"
+                ),
+                features::Input::Features(_) => format!(
+                    "Need at least {MIN_CHANNELS} input columns to train a model, got {columns}, \
+                    try more features"
+                ),
+            }));
+        }
 
         let mut model_config = ModelConfig::new();
         model_config.num_classes = action_count + 1; // + "null action"
+        model_config.channel_count = channel_count;
+        if *input != features::Input::Raw {
+            model_config.input = Some(input.to_string());
+        }
+
+        // Inference must use filters designed for the sample rate of the training data
+        let filter = filter.with_sample_rate(self.dataset.sample_rate());
+        let filtered;
+        let dataset = match filter.is_empty() {
+            true => &self.dataset,
+            false => {
+                model_config.filter = Some(filter.to_string());
+                filtered = self.dataset.filtered(&filter);
+                &filtered
+            }
+        };
 
         let mut training_config = TrainingConfig::new(model_config, AdamConfig::new());
         training_config.num_epochs = epochs;
+        training_config.exclude_gaps = exclude_gaps;
 
-        // Train the model
-        self.train2::<DefaultBackend>(
-            artifact_dir,
+        // Train the model, and run it without the overhead of autodiff afterwards
+        with_backend!(self.backend, B => Self::train2::<Autodiff<B>>(
+            dataset,
+            ARTIFACT_DIR,
             training_config,
             max_datapoints,
-            device.clone(),
+            Default::default(),
         )
+        .map(|model| Box::new(model.valid()) as Box<dyn Classifier>))
     }
 
     fn train2<B: AutodiffBackend>(
-        &self,
+        dataset: &PsyLinkDataset,
         artifact_dir: &str,
         config: TrainingConfig,
         max_datapoints: usize,
         device: B::Device,
-    ) -> Result<Model<B>, Box<dyn std::error::Error>> {
+    ) -> error::Result<Model<B>> {
         Self::create_artifact_dir(artifact_dir);
-        config
-            .save(format!("{artifact_dir}/config.json"))
-            .expect("Config should be saved successfully");
+        config.save(format!("{artifact_dir}/config.json"))?;
 
         B::seed(config.seed);
 
-        println!("Dataset length: {}", self.dataset.len());
+        println!("Dataset length: {}", dataset.len());
 
         // Build dataset
-        let (dataset_train, dataset_valid) = self.dataset.split_train_validate(max_datapoints);
+        let (dataset_train, dataset_valid) =
+            dataset.split_train_validate(max_datapoints, config.exclude_gaps);
 
         // Build batchers
-        let batcher_train = TrainingBatcher::<B>::new(device.clone());
-        let batcher_valid = TrainingBatcher::<B::InnerBackend>::new(device.clone());
+        let input = config.model.input()?;
+        let batcher_train =
+            TrainingBatcher::<B>::new(device.clone(), input.clone(), dataset.layout);
+        let batcher_valid =
+            TrainingBatcher::<B::InnerBackend>::new(device.clone(), input, dataset.layout);
 
         // Build data loaders
         let dataloader_train = DataLoaderBuilder::new(batcher_train)
@@ -164,13 +390,13 @@ impl CalibController {
         model_trained
             .clone()
             .save_file(format!("{artifact_dir}/model"), &CompactRecorder::new())
-            .expect("Trained model should be saved successfully");
+            .map_err(|err| error::Error::Calibration(format!("Failed to save model: {err}")))?;
 
         let recorder = BinFileRecorder::<FullPrecisionSettings>::new();
         model_trained
             .clone()
             .save_file(format!("{artifact_dir}/model_bin"), &recorder)
-            .expect("Should be able to save the model");
+            .map_err(|err| error::Error::Calibration(format!("Failed to save model: {err}")))?;
         Ok(model_trained)
     }
 }
@@ -184,6 +410,9 @@ pub struct Model<B: Backend> {
     linear1: Linear<B>,
     linear2: Linear<B>,
     activation: Relu,
+    channel_count: usize,
+    filter: String, // The dsp::FilterChain that was applied to the training data
+    input: String,  // The features::Input that the model takes
 }
 
 impl<B: AutodiffBackend> TrainStep<TrainingBatch<B>, ClassificationOutput<B>> for Model<B> {
@@ -201,7 +430,49 @@ impl<B: Backend> ValidStep<TrainingBatch<B>, ClassificationOutput<B>> for Model<
     }
 }
 
+impl<B: Backend> Classifier for Model<B> {
+    fn describe(&self) -> String {
+        format!("{self:?}")
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channel_count
+    }
+
+    fn action_count(&self) -> usize {
+        let [_, classes] = self.linear2.weight.val().dims();
+        classes - 1 // Without the "null action"
+    }
+
+    fn filter_chain(&self) -> error::Result<dsp::FilterChain> {
+        self.filter.parse()
+    }
+
+    fn predict(&self, window: &[Vec<u8>], layout: protocol::ChannelLayout) -> error::Result<i32> {
+        let item = TrainingSample {
+            features: window.to_vec(),
+            label: 0,
+        };
+        infer_item(self.clone(), item, layout)
+    }
+}
+
 impl<B: Backend> Model<B> {
+    /// The number of channels (EMG + IMU) that this model was trained on
+    pub fn channel_count(&self) -> usize {
+        self.channel_count
+    }
+
+    /// The filters that the input of this model has to pass through
+    pub fn filter_chain(&self) -> error::Result<dsp::FilterChain> {
+        self.filter.parse()
+    }
+
+    /// How the samples of a window are presented to this model
+    pub fn input(&self) -> error::Result<features::Input> {
+        self.input.parse()
+    }
+
     /// # Shapes
     ///   - Features [batch_size, height, width]
     ///   - Output [batch_size, num_classes]
@@ -247,6 +518,10 @@ pub struct ModelConfig {
     hidden_size: usize,
     #[config(default = "0.5")]
     dropout: f64,
+    #[config(default = "14")]
+    pub channel_count: usize, // EMG + IMU channels of the training data
+    pub filter: Option<String>, // The dsp::FilterChain applied to the training data
+    pub input: Option<String>,  // The features::Input of the model, raw samples if None
 }
 
 impl ModelConfig {
@@ -260,8 +535,15 @@ impl ModelConfig {
             linear1: LinearConfig::new(16 * 8 * 8, self.hidden_size).init(device),
             linear2: LinearConfig::new(self.hidden_size, self.num_classes).init(device),
             dropout: DropoutConfig::new(self.dropout).init(),
+            channel_count: self.channel_count,
+            filter: self.filter.clone().unwrap_or_default(),
+            input: self.input.clone().unwrap_or_default(),
         }
     }
+
+    pub fn input(&self) -> error::Result<features::Input> {
+        self.input.as_deref().unwrap_or_default().parse()
+    }
 }
 
 #[derive(Config)]
@@ -278,17 +560,30 @@ pub struct TrainingConfig {
     pub seed: u64,
     #[config(default = 1.0e-4)]
     pub learning_rate: f64,
+    // Skip training samples whose time window contains lost packets
+    #[config(default = true)]
+    pub exclude_gaps: bool,
 }
 
 // This is a slim variant of a TrainingSample. It's faster to work with, but can't be
 // used to train a NN directly. It's only valid in the context of a PsyLinkDataset,
 // and PsyLinkDataset.get() will turn it into a TrainingSample when needed.
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq)]
 pub struct Datapoint {
     pub packet_index: usize,
     pub label: u8,
 }
 
+// A place in the dataset where the packets are not contiguous, usually because packets got
+// lost on the way.  Depending on the protocol::GapPolicy, the gap was either left as it is,
+// or filled with `inserted` made-up packets, starting at packet_index.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Gap {
+    pub packet_index: usize, // The first packet after the gap, or the first inserted packet
+    pub inserted: usize,
+    pub duration: f64, // How many seconds of signal are missing
+}
+
 // This is a pair of features+labels that will be used for training the NN.
 // It has a one-to-one mapping to a Datapoint struct.
 #[derive(Clone, Default, Debug)]
@@ -300,10 +595,14 @@ pub struct TrainingSample {
 // The dataset contains a list of all received packets in this session,
 // along with datapoints which were recorded when the user was asked to
 // perform a particular movement.
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq)]
 pub struct PsyLinkDataset {
     pub datapoints: Vec<Datapoint>,
     pub all_packets: Vec<Vec<u8>>,
+    pub timestamps: Vec<f64>, // timestamps[i] = seconds at which all_packets[i] was sampled
+    pub gaps: Vec<Gap>,
+    pub layout: protocol::ChannelLayout, // The columns of each packet in all_packets
+    pub info: session::SessionInfo,
 }
 
 impl Dataset<TrainingSample> for PsyLinkDataset {
@@ -334,13 +633,38 @@ impl PsyLinkDataset {
         let packet = self.all_packets.get(start..=end)?;
 
         Some(TrainingSample {
-            features: (*packet).iter().cloned().collect(),
+            features: packet.to_vec(),
             label,
         })
     }
 
-    fn split_train_validate(&self, max_datapoints: usize) -> (Self, Self) {
-        let mut datapoints = self.datapoints.clone();
+    /// Returns true if the time window of a training sample ending at packet_index
+    /// contains a gap, or if it contains packets that were inserted to fill a gap.
+    pub fn window_has_gap(&self, packet_index: usize) -> bool {
+        let start = (packet_index + 1).saturating_sub(SAMPLE_TIMESPAN);
+        self.gaps.iter().any(|gap| {
+            if gap.inserted > 0 {
+                gap.packet_index <= packet_index && gap.packet_index + gap.inserted > start
+            } else {
+                gap.packet_index > start && gap.packet_index <= packet_index
+            }
+        })
+    }
+
+    /// Splits the datapoints randomly into a training set and a validation set, leaving
+    /// out those whose window is incomplete or, if asked to, has a gap
+    pub fn split_train_validate(&self, max_datapoints: usize, exclude_gaps: bool) -> (Self, Self) {
+        // Drop datapoints that can't be turned into a full training sample
+        let mut datapoints: Vec<Datapoint> = self
+            .datapoints
+            .iter()
+            .filter(|datapoint| {
+                datapoint.packet_index >= SAMPLE_TIMESPAN
+                    && datapoint.packet_index < self.all_packets.len()
+                    && !(exclude_gaps && self.window_has_gap(datapoint.packet_index))
+            })
+            .cloned()
+            .collect();
         let mut rng = thread_rng();
         datapoints.shuffle(&mut rng);
         datapoints.truncate(max_datapoints);
@@ -355,35 +679,27 @@ impl PsyLinkDataset {
         let train_dataset = PsyLinkDataset {
             datapoints: training_datapoints,
             all_packets: self.all_packets.clone(),
+            timestamps: self.timestamps.clone(),
+            gaps: self.gaps.clone(),
+            layout: self.layout,
+            info: self.info.clone(),
         };
         let validation_dataset = PsyLinkDataset {
             datapoints,
             all_packets: self.all_packets.clone(),
+            timestamps: self.timestamps.clone(),
+            gaps: self.gaps.clone(),
+            layout: self.layout,
+            info: self.info.clone(),
         };
 
         (train_dataset, validation_dataset)
     }
 
-    pub fn to_string(&self) -> String {
-        let mut string = String::new();
-        string += "([\n";
-        for datapoint in &self.datapoints {
-            string += format!("({},{}),", datapoint.packet_index, datapoint.label).as_str();
-        }
-        string += "],\n[\n";
-        for packet in &self.all_packets {
-            string += "[";
-            for byte in packet {
-                string += byte.to_string().as_str();
-                string += ",";
-            }
-            string += "],\n";
-        }
-        string += "])\n";
-        string
-    }
-
-    pub fn from_arrays(datapoints: &[(usize, u8)], all_packets: &[[u8; 14]]) -> Self {
+    pub fn from_arrays<const N: usize>(
+        datapoints: &[(usize, u8)],
+        all_packets: &[[u8; N]],
+    ) -> Self {
         let datapoints: Vec<Datapoint> = datapoints
             .iter()
             .map(|d| Datapoint {
@@ -392,21 +708,327 @@ impl PsyLinkDataset {
             })
             .collect();
 
-        let all_packets: Vec<Vec<u8>> = all_packets
-            .iter()
-            .map(|packet| packet.iter().map(|&byte| byte).collect())
-            .collect();
+        let all_packets: Vec<Vec<u8>> = all_packets.iter().map(|packet| packet.to_vec()).collect();
+
+        // The arrays carry no timing information, so assume the nominal sample rate
+        let timestamps = nominal_timestamps(0..all_packets.len());
 
         Self {
             datapoints,
             all_packets,
+            timestamps,
+            gaps: vec![],
+            layout: protocol::ChannelLayout::new(N - firmware::IMU_CHANNELS as usize),
+            info: Default::default(),
         }
     }
 
+    /// Parses a dataset that was saved by older versions of PsyLink, which wrote a
+    /// Rust tuple literal of the same shape as TEST_DATASET
+    pub fn from_rust_dump(text: &str) -> error::Result<Self> {
+        let invalid = |what: &str| error::Error::Parse(format!("Invalid dataset dump: {what}"));
+        let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let body = text
+            .strip_prefix("([")
+            .and_then(|body| body.strip_suffix("])"))
+            .ok_or_else(|| invalid("expected a tuple of two arrays"))?;
+        // Datapoints contain no brackets, so the first "],[" separates the two arrays
+        let (datapoints, packets) = body
+            .split_once("],[")
+            .ok_or_else(|| invalid("expected a tuple of two arrays"))?;
+
+        let datapoints = datapoints
+            .split(')')
+            .map(|item| item.trim_start_matches(','))
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                let (index, label) = item
+                    .strip_prefix('(')
+                    .and_then(|item| item.split_once(','))
+                    .ok_or_else(|| invalid(&format!("bad datapoint \"{item}\"")))?;
+                Ok(Datapoint {
+                    packet_index: index.parse().map_err(|_| invalid("bad packet index"))?,
+                    label: label.parse().map_err(|_| invalid("bad label"))?,
+                })
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+
+        let all_packets = packets
+            .split(']')
+            .map(|item| item.trim_start_matches(','))
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.strip_prefix('[')
+                    .ok_or_else(|| invalid(&format!("bad packet \"{item}\"")))?
+                    .split(',')
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.parse().map_err(|_| invalid("bad sample value")))
+                    .collect()
+            })
+            .collect::<error::Result<Vec<Vec<u8>>>>()?;
+
+        let layout = match all_packets.first() {
+            Some(packet) if packet.len() > firmware::IMU_CHANNELS as usize => {
+                protocol::ChannelLayout::new(packet.len() - firmware::IMU_CHANNELS as usize)
+            }
+            Some(packet) => return Err(error::Error::InvalidChannelCount(packet.len() as i32)),
+            None => protocol::ChannelLayout::default(),
+        };
+        if let Some(packet) = all_packets
+            .iter()
+            .find(|packet| packet.len() != layout.total_channels())
+        {
+            return Err(error::Error::ChannelMismatch {
+                expected: layout.total_channels(),
+                found: packet.len(),
+            });
+        }
+
+        Ok(Self {
+            datapoints,
+            timestamps: nominal_timestamps(0..all_packets.len()),
+            all_packets,
+            gaps: vec![],
+            layout,
+            info: Default::default(),
+        })
+    }
+
+    /// Writes the dataset in the session file format, see the session module
+    pub fn write_to<W: Write>(&self, out: W) -> error::Result<()> {
+        SessionStream::new(out, self).map(|_| ())
+    }
+
+    /// Reads a dataset from a session file, see the session module
+    pub fn read_from<R: Read>(input: R) -> error::Result<Self> {
+        let mut dataset = Self::default();
+        for chunk in session::SessionReader::new(input)? {
+            dataset.append_chunk(chunk?)?;
+        }
+        Ok(dataset)
+    }
+
+    /// Adds a piece of a session file to the dataset
+    pub fn append_chunk(&mut self, chunk: Chunk) -> error::Result<()> {
+        match chunk {
+            Chunk::Layout(layout) => self.layout = layout,
+            Chunk::Device(device) => self.info.devices.push(device),
+            Chunk::Actions(names) => self.info.action_names = names,
+            Chunk::Metadata(key, value) => self.info.set_metadata(&key, value),
+            Chunk::Samples { timestamps, rows } => {
+                let expected = self.layout.total_channels();
+                if let Some(row) = rows.iter().find(|row| row.len() != expected) {
+                    return Err(error::Error::ChannelMismatch {
+                        expected,
+                        found: row.len(),
+                    });
+                }
+                self.timestamps.extend(timestamps);
+                self.all_packets.extend(rows);
+            }
+            Chunk::Datapoints(datapoints) => {
+                let len = self.all_packets.len();
+                if let Some(datapoint) = datapoints.iter().find(|d| d.packet_index >= len) {
+                    return Err(error::Error::Parse(format!(
+                        "Datapoint at packet {}, but there are only {len} packets",
+                        datapoint.packet_index
+                    )));
+                }
+                self.datapoints.extend(datapoints);
+            }
+            Chunk::Gap(gap) => self.gaps.push(gap),
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> error::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// The sample rate in Hz, judging by the timestamps
+    pub fn sample_rate(&self) -> f64 {
+        protocol::estimate_sample_rate(&self.timestamps)
+    }
+
+    /// A copy of the dataset whose packets passed through the given filters, in order
+    pub fn filtered(&self, filter: &dsp::FilterChain) -> Self {
+        let mut stream = dsp::StreamFilter::new(filter, self.layout, self.sample_rate());
+        Self {
+            all_packets: self
+                .all_packets
+                .iter()
+                .map(|packet| stream.filter_row(packet))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The training samples of all datapoints that have a full window
+    pub fn samples(&self) -> impl Iterator<Item = TrainingSample> + '_ {
+        self.datapoints.iter().filter_map(|datapoint| {
+            self.get_sample_from_packet_index(datapoint.packet_index, datapoint.label)
+        })
+    }
+
     pub fn get_latest(&self) -> Option<TrainingSample> {
         let last = self.all_packets.len().saturating_sub(1);
         self.get_sample_from_packet_index(last, 0)
     }
+
+    /// The number of actions besides the null action, judging by the action names
+    /// and by the labels of the datapoints
+    pub fn action_count(&self) -> usize {
+        let named = self.info.action_names.len().saturating_sub(1);
+        let labeled = self.datapoints.iter().map(|d| d.label as usize).max();
+        named.max(labeled.unwrap_or(0))
+    }
+
+    /// Appends the packets and datapoints of another session, e.g. to train on the data
+    /// of several sessions at once.  Actions with the same name get the same label.
+    pub fn append(&mut self, mut other: PsyLinkDataset) -> error::Result<()> {
+        if self.all_packets.is_empty() {
+            self.layout = other.layout;
+        } else if self.layout != other.layout {
+            return Err(error::Error::ChannelMismatch {
+                expected: self.layout.total_channels(),
+                found: other.layout.total_channels(),
+            });
+        }
+        let offset = self.all_packets.len();
+
+        // Translate the labels of the other session into ours
+        let mut labels: Vec<u8> = (0..=u8::MAX).collect();
+        if self.info.action_names.is_empty() {
+            self.info.action_names = other.info.action_names.clone();
+        }
+        for (label, name) in other
+            .info
+            .action_names
+            .iter()
+            .enumerate()
+            .take(labels.len())
+        {
+            labels[label] = self.info.label_for(name)?;
+        }
+        for datapoint in &mut other.datapoints {
+            datapoint.packet_index += offset;
+            datapoint.label = labels[datapoint.label as usize];
+        }
+        self.datapoints.append(&mut other.datapoints);
+
+        // Training samples must not span both sessions
+        if offset > 0 {
+            self.gaps.push(Gap {
+                packet_index: offset,
+                inserted: 0,
+                duration: 0.0,
+            });
+        }
+        for gap in &mut other.gaps {
+            gap.packet_index += offset;
+        }
+        self.gaps.append(&mut other.gaps);
+
+        // Keep the timestamps increasing, as if the sessions were recorded back to back
+        let shift = match (self.timestamps.last(), other.timestamps.first()) {
+            (Some(last), Some(first)) => last + 1.0 / firmware::SAMPLE_RATE - first,
+            _ => 0.0,
+        };
+        self.timestamps
+            .extend(other.timestamps.iter().map(|timestamp| timestamp + shift));
+        self.all_packets.append(&mut other.all_packets);
+
+        for device in other.info.devices {
+            if !self.info.devices.contains(&device) {
+                self.info.devices.push(device);
+            }
+        }
+        for (key, value) in other.info.metadata {
+            if self.info.get_metadata(&key).is_none() {
+                self.info.metadata.push((key, value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a dataset to a session file while it grows, e.g. while recording.  Each update
+/// writes what was added to the dataset since the previous update, and flushes it, so a
+/// crash loses at most the chunks that were being written.  Devices and metadata are
+/// only written at the start.
+pub struct SessionStream<W: Write> {
+    writer: session::SessionWriter<W>,
+    // How much of the dataset was written so far
+    packets: usize,
+    datapoints: usize,
+    gaps: usize,
+    actions: usize,
+}
+
+impl<W: Write> SessionStream<W> {
+    /// Starts a session file with everything that the dataset holds so far
+    pub fn new(out: W, dataset: &PsyLinkDataset) -> error::Result<Self> {
+        let mut writer = session::SessionWriter::new(out)?;
+        writer.write(&Chunk::Layout(dataset.layout))?;
+        for device in &dataset.info.devices {
+            writer.write(&Chunk::Device(device.clone()))?;
+        }
+        for (key, value) in &dataset.info.metadata {
+            writer.write(&Chunk::Metadata(key.clone(), value.clone()))?;
+        }
+        let mut stream = Self {
+            writer,
+            packets: 0,
+            datapoints: 0,
+            gaps: 0,
+            actions: 0,
+        };
+        stream.update(dataset)?;
+        Ok(stream)
+    }
+
+    /// Writes the action names, packets, datapoints and gaps that were added to the dataset
+    pub fn update(&mut self, dataset: &PsyLinkDataset) -> error::Result<()> {
+        let names = &dataset.info.action_names;
+        if names.len() != self.actions {
+            self.writer.write(&Chunk::Actions(names.clone()))?;
+            self.actions = names.len();
+        }
+        let len = dataset.all_packets.len();
+        for start in (self.packets..len).step_by(SESSION_BLOCK) {
+            let end = (start + SESSION_BLOCK).min(len);
+            // Datasets built without timing information get the nominal sample rate
+            let timestamps = match dataset.timestamps.get(start..end) {
+                Some(timestamps) => timestamps.to_vec(),
+                None => nominal_timestamps(start..end),
+            };
+            self.writer.write(&Chunk::Samples {
+                timestamps,
+                rows: dataset.all_packets[start..end].to_vec(),
+            })?;
+        }
+        self.packets = len;
+        // Datapoints may only refer to packets that were written before
+        if let Some(datapoints) = dataset.datapoints.get(self.datapoints..) {
+            if !datapoints.is_empty() {
+                self.writer.write(&Chunk::Datapoints(datapoints.to_vec()))?;
+            }
+        }
+        self.datapoints = dataset.datapoints.len();
+        for gap in dataset.gaps.iter().skip(self.gaps) {
+            self.writer.write(&Chunk::Gap(gap.clone()))?;
+        }
+        self.gaps = dataset.gaps.len();
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -421,11 +1043,17 @@ pub struct TrainingBatch<B: Backend> {
 #[derive(Clone)]
 pub struct TrainingBatcher<B: Backend> {
     device: B::Device,
+    input: features::Input,
+    layout: protocol::ChannelLayout, // The columns of the packets in a TrainingSample
 }
 
 impl<B: Backend> TrainingBatcher<B> {
-    pub fn new(device: B::Device) -> Self {
-        Self { device }
+    pub fn new(device: B::Device, input: features::Input, layout: protocol::ChannelLayout) -> Self {
+        Self {
+            device,
+            input,
+            layout,
+        }
     }
 }
 
@@ -433,12 +1061,14 @@ impl<B: Backend> Batcher<TrainingSample, TrainingBatch<B>> for TrainingBatcher<B
     fn batch(&self, items: Vec<TrainingSample>) -> TrainingBatch<B> {
         let features = items
             .iter()
-            .map(|item| Data::<u8, 2> {
-                value: item.features.concat().iter().map(|&n| n).collect(),
-                shape: Shape::<2> { dims: [250, 14] },
-            })
-            .map(|data| {
-                Tensor::<B, 2>::from_data(data.convert(), &self.device).reshape([1, 250, 14])
+            .map(|item| {
+                let dims = self.input.dims(self.layout, item.features.len());
+                let data = Data::<f32, 2> {
+                    value: self.input.matrix(&item.features, self.layout),
+                    shape: Shape::<2> { dims },
+                };
+                Tensor::<B, 2>::from_data(data.convert(), &self.device)
+                    .reshape([1, dims[0], dims[1]])
             })
             .collect();
 
@@ -452,50 +1082,371 @@ impl<B: Backend> Batcher<TrainingSample, TrainingBatch<B>> for TrainingBatcher<B
         let features = Tensor::cat(features, 0).to_device(&self.device);
         let targets = Tensor::cat(targets, 0).to_device(&self.device);
 
-        let batch = TrainingBatch { features, targets };
-        return batch;
+        TrainingBatch { features, targets }
     }
 }
 
-pub fn load_test_model() -> Model<DefaultBackend> {
-    let device = burn::backend::wgpu::WgpuDevice::default();
+/// The timestamps of packets that were sampled at exactly the nominal sample rate
+fn nominal_timestamps(indices: std::ops::Range<usize>) -> Vec<f64> {
+    indices.map(|i| i as f64 / firmware::SAMPLE_RATE).collect()
+}
+
+// Builds a model from its config and the weights that BinFileRecorder saved.  The weights
+// are stored in full precision, whatever the backend was, so a model trained with one
+// backend runs on any other.
+fn load_model_bytes<B: Backend>(
+    config: &TrainingConfig,
+    weights: Vec<u8>,
+) -> error::Result<Model<B>> {
+    let device = B::Device::default();
+    let record = BinBytesRecorder::<FullPrecisionSettings>::default()
+        .load(weights, &device)
+        .map_err(|err| error::Error::Calibration(format!("Failed to load model: {err}")))?;
+    Ok(config.model.init::<B>(&device).load_record(record))
+}
+
+pub fn load_test_model(backend: BackendKind) -> error::Result<Box<dyn Classifier>> {
     let config = TrainingConfig::load_binary(include_bytes!("data/test_model_config.json"))
         .expect("Config should exist for the model");
-    let record = BinBytesRecorder::<FullPrecisionSettings>::default()
-        .load(TEST_MODEL.to_vec(), &device)
-        .expect("Should be able to load model the model weights from bytes");
-    let model = config
-        .model
-        .init::<DefaultBackend>(&device)
-        .load_record(record);
-    model
+    with_backend!(backend, B => load_model_bytes::<B>(&config, TEST_MODEL.to_vec())
+        .map(|model| Box::new(model) as Box<dyn Classifier>))
 }
 
-pub fn infer_item(model: Model<DefaultBackend>, item: TrainingSample) -> i32 {
-    let device = burn::backend::wgpu::WgpuDevice::default();
-    let batcher = TrainingBatcher::<DefaultBackend>::new(device.clone());
+/// Saves a classical classifier to ARTIFACT_DIR, in place of the model saved there before
+pub fn save_classifier(trained: &classifier::Classical) -> error::Result<()> {
+    CalibController::create_artifact_dir(ARTIFACT_DIR);
+    trained.save(ARTIFACT_DIR)
+}
+
+/// Loads a model from the artifact directory that CalibController::train or
+/// save_classifier saved it to.  Classical classifiers ignore the backend.
+pub fn load_model<P: AsRef<Path>>(
+    artifact_dir: P,
+    backend: BackendKind,
+) -> error::Result<Box<dyn Classifier>> {
+    let artifact_dir = artifact_dir.as_ref();
+    if classifier::is_saved(artifact_dir) {
+        return classifier::Classical::load(artifact_dir)
+            .map(|trained| Box::new(trained) as Box<dyn Classifier>);
+    }
+    let config = TrainingConfig::load(artifact_dir.join("config.json"))
+        .map_err(|err| error::Error::Calibration(format!("Failed to load model config: {err}")))?;
+    let weights = std::fs::read(artifact_dir.join("model_bin.bin"))?;
+    with_backend!(backend, B => load_model_bytes::<B>(&config, weights)
+        .map(|model| Box::new(model) as Box<dyn Classifier>))
+}
+
+pub fn infer_item<B: Backend>(
+    model: Model<B>,
+    item: TrainingSample,
+    layout: protocol::ChannelLayout,
+) -> error::Result<i32> {
+    let device = B::Device::default();
+    let batcher = TrainingBatcher::<B>::new(device.clone(), model.input()?, layout);
     let batch = batcher.batch(vec![item]);
     let output = model.forward(batch.features);
-    let predicted = output.argmax(1).flatten::<1>(0, 1).into_scalar();
-    return predicted;
+    Ok(output.argmax(1).flatten::<1>(0, 1).into_scalar().elem())
 }
 
-pub fn train() -> Result<(), Box<dyn std::error::Error>> {
-    let mut calib = CalibController::default();
-    calib.dataset = PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1);
-    calib.train(2, DEFAULT_EPOCHS, DEFAULT_MAX_DATAPOINTS)?;
+pub fn train(kind: ClassifierKind, backend: BackendKind) -> error::Result<()> {
+    let mut calib = CalibController::new(PsyLinkDataset::from_arrays(
+        &TEST_DATASET.0,
+        &TEST_DATASET.1,
+    ));
+    calib.backend = backend;
+    let filter = dsp::FilterChain::default();
+    if kind != ClassifierKind::NeuralNetwork {
+        let features = features::DEFAULT_FEATURES;
+        let trained = classifier::train(
+            &calib.dataset,
+            kind,
+            &filter,
+            &features,
+            DEFAULT_MAX_DATAPOINTS,
+            true,
+        )?;
+        println!("Trained {}", trained.describe());
+        save_classifier(&trained)?;
+        println!("Saved the classifier to {ARTIFACT_DIR}");
+        return Ok(());
+    }
+    let input = features::Input::Raw;
+    calib.train(
+        calib.dataset.action_count(),
+        DEFAULT_EPOCHS,
+        DEFAULT_MAX_DATAPOINTS,
+        true,
+        &filter,
+        &input,
+    )?;
 
     Ok(())
 }
 
-pub fn infer() -> Result<(), Box<dyn std::error::Error>> {
-    let model = load_test_model();
+/// Runs the test model, or the model in the given artifact directory, on the test dataset
+pub fn infer(backend: BackendKind, artifact_dir: Option<&Path>) -> error::Result<()> {
+    let model = match artifact_dir {
+        Some(artifact_dir) => load_model(artifact_dir, backend)?,
+        None => load_test_model(backend)?,
+    };
     let dataset = PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1);
 
     for item in dataset.iter() {
-        let predicted = infer_item(model.clone(), item);
+        let predicted = model.predict(&item.features, dataset.layout)?;
         dbg!(predicted);
     }
 
     Ok(())
 }
+
+#[test]
+fn test_window_has_gap() {
+    let mut dataset = PsyLinkDataset {
+        all_packets: vec![vec![127; 14]; 1000],
+        ..Default::default()
+    };
+    dataset.gaps.push(Gap {
+        packet_index: 500,
+        inserted: 0,
+        duration: 0.1,
+    });
+    dataset.gaps.push(Gap {
+        packet_index: 800,
+        inserted: 50,
+        duration: 0.1,
+    });
+    assert!(!dataset.window_has_gap(499));
+    assert!(dataset.window_has_gap(500));
+    assert!(dataset.window_has_gap(500 + SAMPLE_TIMESPAN - 2));
+    assert!(!dataset.window_has_gap(500 + SAMPLE_TIMESPAN - 1));
+    assert!(!dataset.window_has_gap(799));
+    assert!(dataset.window_has_gap(800));
+    assert!(dataset.window_has_gap(849 + SAMPLE_TIMESPAN - 1));
+    assert!(!dataset.window_has_gap(850 + SAMPLE_TIMESPAN - 1));
+}
+
+#[test]
+fn test_load_test_model_config() {
+    let config = TrainingConfig::load_binary(include_bytes!("data/test_model_config.json"));
+    assert!(config.is_ok());
+    let config = config.unwrap();
+    assert_eq!(config.model.channel_count, 14);
+    // Models from before the filters were introduced take their input unfiltered and raw
+    assert_eq!(config.model.filter, None);
+    assert_eq!(config.model.input().unwrap(), features::Input::Raw);
+}
+
+#[test]
+#[cfg(feature = "ndarray")]
+fn test_backends() {
+    assert_eq!(
+        "ndarray".parse::<BackendKind>().unwrap(),
+        BackendKind::NdArray
+    );
+    assert!("cuda".parse::<BackendKind>().is_err());
+    for backend in BackendKind::ALL {
+        assert_eq!(backend.to_string().parse::<BackendKind>().unwrap(), backend);
+        if !backend.is_available() {
+            assert!(load_test_model(backend).is_err());
+        }
+    }
+
+    // Save the test model like train does, and load it again
+    let config =
+        TrainingConfig::load_binary(include_bytes!("data/test_model_config.json")).unwrap();
+    let model = load_model_bytes::<burn::backend::NdArray>(&config, TEST_MODEL.to_vec()).unwrap();
+    let artifact_dir = std::env::temp_dir().join(format!("psylink_test_{}", std::process::id()));
+    std::fs::create_dir_all(&artifact_dir).unwrap();
+    config.save(artifact_dir.join("config.json")).unwrap();
+    model
+        .clone()
+        .save_file(
+            artifact_dir.join("model_bin"),
+            &BinFileRecorder::<FullPrecisionSettings>::new(),
+        )
+        .unwrap();
+
+    let dataset = PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1);
+    let windows: Vec<_> = (SAMPLE_TIMESPAN..dataset.all_packets.len())
+        .step_by(5000)
+        .map(|end| dataset.all_packets[end - SAMPLE_TIMESPAN..end].to_vec())
+        .collect();
+    let expected: Vec<i32> = windows
+        .iter()
+        .map(|window| model.predict(window, dataset.layout).unwrap())
+        .collect();
+    let loaded = load_model(&artifact_dir, BackendKind::NdArray).unwrap();
+    assert_eq!(loaded.channel_count(), 14);
+    assert_eq!(loaded.action_count(), 3);
+    let predicted: Vec<i32> = windows
+        .iter()
+        .map(|window| loaded.predict(window, dataset.layout).unwrap())
+        .collect();
+    assert_eq!(predicted, expected);
+    assert!(expected.contains(&0) && expected.iter().any(|action| *action != 0));
+    std::fs::remove_dir_all(&artifact_dir).unwrap();
+}
+
+#[test]
+fn test_filtered_input() {
+    let dataset = PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1[..1000]);
+    let filter: dsp::FilterChain = "dc; notch 50 harmonics 2".parse().unwrap();
+    let filter = filter.with_sample_rate(dataset.sample_rate());
+    let filtered = dataset.filtered(&filter);
+    assert_ne!(filtered.all_packets, dataset.all_packets);
+    assert_eq!(filtered.timestamps, dataset.timestamps);
+
+    // Inference sees the same samples as the training, no matter how often it runs
+    let mut calib = CalibController::new(PsyLinkDataset {
+        all_packets: vec![],
+        timestamps: vec![],
+        ..dataset.clone()
+    });
+    for (packet, timestamp) in dataset.all_packets.iter().zip(&dataset.timestamps) {
+        calib.add_packet(packet.clone(), *timestamp);
+        if calib.get_current_index() % 300 == 0 {
+            calib.get_latest_filtered(&filter);
+        }
+    }
+    let latest = calib.get_latest_filtered(&filter).unwrap();
+    assert_eq!(latest.features, filtered.get_latest().unwrap().features);
+}
+
+#[test]
+fn test_dataset_layout() {
+    let dataset = PsyLinkDataset::from_arrays(&[(0, 1)], &[[127; 10]; 3]);
+    assert_eq!(dataset.layout.emg_channels, 4);
+    assert_eq!(dataset.layout.total_channels(), 10);
+
+    let mut calib = CalibController::new(PsyLinkDataset::from_arrays(
+        &TEST_DATASET.0,
+        &TEST_DATASET.1,
+    ));
+    assert_eq!(calib.dataset.layout, protocol::ChannelLayout::default());
+    assert!(!calib.set_layout(protocol::ChannelLayout::default()));
+    assert!(calib.has_datapoints());
+    assert!(calib.set_layout(protocol::ChannelLayout::new(4)));
+    assert!(!calib.has_datapoints());
+    assert_eq!(calib.get_current_index(), 0);
+}
+
+#[test]
+fn test_session_round_trip() {
+    let mut dataset = PsyLinkDataset::from_arrays(&[(1, 0), (2, 1)], &[[127; 10]; 2500]);
+    dataset.timestamps[2499] = 123.456;
+    dataset.gaps.push(Gap {
+        packet_index: 1200,
+        inserted: 3,
+        duration: 0.012,
+    });
+    dataset.info.action_names = vec!["(no action)".into(), "Key \"w\"".into()];
+    dataset.info.devices.push(session::DeviceInfo {
+        description: "simulated PsyLink".into(),
+        ..Default::default()
+    });
+    dataset.info.set_metadata("subject", "test".into());
+
+    let mut bytes = vec![];
+    dataset.write_to(&mut bytes).unwrap();
+    assert!(bytes.len() < 2500 * (10 + 8) + 1000);
+    assert_eq!(
+        PsyLinkDataset::read_from(bytes.as_slice()).unwrap(),
+        dataset
+    );
+
+    // A session can be written while the dataset grows
+    let mut growing = PsyLinkDataset {
+        layout: dataset.layout,
+        info: dataset.info.clone(),
+        ..Default::default()
+    };
+    let mut stream = SessionStream::new(vec![], &growing).unwrap();
+    for index in 0..dataset.all_packets.len() {
+        growing.all_packets.push(dataset.all_packets[index].clone());
+        growing.timestamps.push(dataset.timestamps[index]);
+        let datapoints = dataset
+            .datapoints
+            .iter()
+            .filter(|d| d.packet_index == index);
+        growing.datapoints.extend(datapoints.cloned());
+        let gaps = dataset.gaps.iter().filter(|gap| gap.packet_index == index);
+        growing.gaps.extend(gaps.cloned());
+        if index % 100 == 99 {
+            stream.update(&growing).unwrap();
+        }
+    }
+    stream.update(&growing).unwrap();
+    let bytes = stream.into_inner();
+    assert_eq!(
+        PsyLinkDataset::read_from(bytes.as_slice()).unwrap(),
+        dataset
+    );
+
+    // Rows that don't fit the layout are rejected
+    let mut dataset = PsyLinkDataset::default();
+    let chunk = Chunk::Samples {
+        timestamps: vec![0.0],
+        rows: vec![vec![127; 10]],
+    };
+    assert!(dataset.append_chunk(chunk).is_err());
+
+    // And so are datapoints of packets that don't exist
+    let chunk = Chunk::Datapoints(vec![Datapoint {
+        packet_index: 0,
+        label: 1,
+    }]);
+    assert!(dataset.append_chunk(chunk).is_err());
+}
+
+#[test]
+fn test_append_datasets() {
+    let mut first = PsyLinkDataset::from_arrays(&[(1, 0), (2, 1)], &[[127; 10]; 3]);
+    first.info.action_names = vec!["(no action)".into(), "fist".into()];
+    let mut second = PsyLinkDataset::from_arrays(&[(0, 2), (2, 1)], &[[128; 10]; 3]);
+    second.info.action_names = vec!["(no action)".into(), "thumb".into(), "fist".into()];
+    assert_eq!(second.action_count(), 2);
+
+    let mut calib = CalibController::default();
+    calib.dataset.append(first).unwrap();
+    calib.dataset.append(second).unwrap();
+    let dataset = &calib.dataset;
+    assert_eq!(dataset.all_packets.len(), 6);
+    assert_eq!(dataset.timestamps.len(), 6);
+    assert!(dataset.timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(dataset.info.action_names, ["(no action)", "fist", "thumb"]);
+    let datapoints: Vec<(usize, u8)> = dataset
+        .datapoints
+        .iter()
+        .map(|d| (d.packet_index, d.label))
+        .collect();
+    assert_eq!(datapoints, [(1, 0), (2, 1), (3, 1), (5, 2)]);
+    assert!(dataset.window_has_gap(3));
+    assert_eq!(dataset.action_count(), 2);
+
+    let other = PsyLinkDataset::from_arrays(&[], &[[127; 14]; 3]);
+    assert!(calib.dataset.append(other).is_err());
+
+    // There are only 256 labels
+    let mut many = PsyLinkDataset::from_arrays(&[], &[[127; 10]; 3]);
+    many.info.action_names = (0..300).map(|action| format!("action {action}")).collect();
+    assert!(matches!(
+        calib.dataset.append(many),
+        Err(error::Error::TooManyActions { found: 257, .. })
+    ));
+}
+
+#[test]
+fn test_from_rust_dump() {
+    let dataset = PsyLinkDataset::from_rust_dump(include_str!("data/test_dataset.rs")).unwrap();
+    assert_eq!(
+        dataset,
+        PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1)
+    );
+
+    let dataset = PsyLinkDataset::from_rust_dump("([\n],\n[\n[1,2,3,4,5,6,7,8,],\n])\n").unwrap();
+    assert_eq!(dataset.layout.emg_channels, 2);
+    assert_eq!(dataset.all_packets, vec![vec![1, 2, 3, 4, 5, 6, 7, 8]]);
+    assert!(dataset.datapoints.is_empty());
+    assert!(PsyLinkDataset::from_rust_dump("([(1,2),],[[1,2,3,4,5,6,7,],[1,],])").is_err());
+    assert!(PsyLinkDataset::from_rust_dump("[1,2,3]").is_err());
+}
@@ -0,0 +1,876 @@
+// Classifiers that turn a window of packets into the predicted action.  Besides the neural
+// network of calibration::Model, there are classical classifiers that train on the
+// features of the windows within seconds and don't need a GPU:
+//
+//   lda     linear discriminant analysis with a shrunk covariance matrix
+//   svm     linear support vector machines, one per action against the rest
+//   knn     the majority of the K_NEIGHBORS nearest training samples
+//   forest  a random forest of decision trees, each grown on a bootstrap sample
+//
+// The classical classifiers use features::window_features of the whole window, scaled
+// to zero mean and unit variance, after the same filters as the neural network.  They
+// are saved as CLASSIFIER_FILE in an artifact directory, like the neural network.
+
+use crate::calibration::PsyLinkDataset;
+use crate::error::{Error, Result};
+use crate::features::{self, Feature};
+use crate::prelude::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+const SEED: u64 = 42;
+const LDA_SHRINKAGE: f64 = 0.1; // How much of the covariance matrix is replaced by the identity
+const SVM_LAMBDA: f64 = 1e-3; // Regularization
+const SVM_EPOCHS: usize = 30;
+const SVM_OFFSET: f64 = 1000.0; // Steps added to the step count of the learning rate
+const K_NEIGHBORS: usize = 5;
+const TREES: usize = 25;
+const TREE_DEPTH: usize = 10;
+const MIN_SPLIT: usize = 4; // Nodes with fewer samples become leaves
+pub const CLASSIFIER_FILE: &str = "classifier.json";
+
+/// Predicts actions from windows of SAMPLE_TIMESPAN packets
+pub trait Classifier: Send {
+    /// A description for the activity log
+    fn describe(&self) -> String;
+
+    /// The number of channels (EMG + IMU) that the classifier was trained on
+    fn channel_count(&self) -> usize;
+
+    /// The number of actions besides "no action" that the classifier tells apart
+    fn action_count(&self) -> usize;
+
+    /// The filters that the packets have to pass through before predict()
+    fn filter_chain(&self) -> Result<dsp::FilterChain>;
+
+    /// Predicts the label of the action in a window of packets, oldest first
+    fn predict(&self, window: &[Vec<u8>], layout: protocol::ChannelLayout) -> Result<i32>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClassifierKind {
+    #[default]
+    NeuralNetwork,
+    Lda,
+    Svm,
+    Knn,
+    Forest,
+}
+
+/// A trained classical classifier
+#[derive(Clone, Debug)]
+pub struct Classical {
+    kind: ClassifierKind,
+    method: Method,
+    scaler: Scaler,
+    features: Vec<Feature>,
+    filter: dsp::FilterChain,
+    layout: protocol::ChannelLayout,
+    action_count: usize,
+    pub accuracy: Option<f64>, // On the validation set, if there was one
+}
+
+#[derive(Clone, Debug)]
+enum Method {
+    Linear(Linear),
+    Knn(Vec<(Vec<f64>, u8)>),
+    Forest(Vec<Tree>),
+}
+
+// One score per class, the highest wins
+#[derive(Clone, Debug)]
+struct Linear {
+    classes: Vec<u8>,
+    weights: Vec<Vec<f64>>,
+    biases: Vec<f64>,
+}
+
+#[derive(Clone, Debug)]
+enum Tree {
+    Leaf(u8),
+    Split {
+        feature: usize,
+        threshold: f64,
+        below: Box<Tree>,
+        above: Box<Tree>,
+    },
+}
+
+// Scales every feature to zero mean and unit variance
+#[derive(Clone, Debug, Default)]
+struct Scaler {
+    means: Vec<f64>,
+    scales: Vec<f64>,
+}
+
+impl ClassifierKind {
+    pub const ALL: [ClassifierKind; 5] = [
+        ClassifierKind::NeuralNetwork,
+        ClassifierKind::Lda,
+        ClassifierKind::Svm,
+        ClassifierKind::Knn,
+        ClassifierKind::Forest,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClassifierKind::NeuralNetwork => "cnn",
+            ClassifierKind::Lda => "lda",
+            ClassifierKind::Svm => "svm",
+            ClassifierKind::Knn => "knn",
+            ClassifierKind::Forest => "forest",
+        }
+    }
+}
+
+impl FromStr for ClassifierKind {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        ClassifierKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == text.trim())
+            .ok_or_else(|| {
+                Error::Parse(format!(
+                    "Unknown classifier \"{text}\", expected cnn, lda, svm, knn or forest"
+                ))
+            })
+    }
+}
+
+impl fmt::Display for ClassifierKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Trains a classical classifier on the features of the datapoints of a dataset, after
+/// passing the packets through the given filters
+pub fn train(
+    dataset: &PsyLinkDataset,
+    kind: ClassifierKind,
+    filter: &dsp::FilterChain,
+    features: &[Feature],
+    max_datapoints: usize,
+    exclude_gaps: bool,
+) -> Result<Classical> {
+    if kind == ClassifierKind::NeuralNetwork {
+        return Err(Error::Calibration(
+            "The neural network is trained by CalibController::train".into(),
+        ));
+    }
+    if features.is_empty() {
+        return Err(Error::Calibration("Need at least one feature".into()));
+    }
+    let filter = filter.with_sample_rate(dataset.sample_rate());
+    let dataset = match filter.is_empty() {
+        true => dataset.clone(),
+        false => dataset.filtered(&filter),
+    };
+    let layout = dataset.layout;
+    let (train_set, validation_set) = dataset.split_train_validate(max_datapoints, exclude_gaps);
+    let samples = |set: &PsyLinkDataset| -> Vec<(Vec<f64>, u8)> {
+        set.samples()
+            .map(|sample| {
+                let vector = features::window_features(features, &sample.features, layout);
+                (vector, sample.label)
+            })
+            .collect()
+    };
+    let mut training = samples(&train_set);
+    if training.is_empty() {
+        return Err(Error::Calibration(
+            "No datapoints to train on, please record some first".into(),
+        ));
+    }
+
+    let scaler = Scaler::fit(&training);
+    for (vector, _) in &mut training {
+        scaler.scale(vector);
+    }
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let method = match kind {
+        ClassifierKind::Lda => Method::Linear(Linear::lda(&training)),
+        ClassifierKind::Svm => Method::Linear(Linear::svm(&training, &mut rng)),
+        ClassifierKind::Knn => Method::Knn(training),
+        ClassifierKind::NeuralNetwork => unreachable!("The neural network is rejected above"),
+        ClassifierKind::Forest => Method::Forest(
+            (0..TREES)
+                .map(|_| {
+                    let bootstrap: Vec<&(Vec<f64>, u8)> = (0..training.len())
+                        .map(|_| &training[rng.gen_range(0..training.len())])
+                        .collect();
+                    Tree::grow(bootstrap, 0, &mut rng)
+                })
+                .collect(),
+        ),
+    };
+
+    let mut classifier = Classical {
+        kind,
+        method,
+        scaler,
+        features: features.to_vec(),
+        filter,
+        layout,
+        action_count: dataset.action_count(),
+        accuracy: None,
+    };
+    let validation = samples(&validation_set);
+    if !validation.is_empty() {
+        let correct = validation
+            .iter()
+            .filter(|(vector, label)| classifier.classify(vector.clone()) == *label)
+            .count();
+        classifier.accuracy = Some(correct as f64 / validation.len() as f64);
+    }
+    Ok(classifier)
+}
+
+impl Classical {
+    fn classify(&self, mut vector: Vec<f64>) -> u8 {
+        self.scaler.scale(&mut vector);
+        match &self.method {
+            Method::Linear(linear) => linear.classify(&vector),
+            Method::Knn(points) => {
+                let mut nearest: Vec<(f64, u8)> = points
+                    .iter()
+                    .map(|(point, label)| (distance(point, &vector), *label))
+                    .collect();
+                let k = K_NEIGHBORS.min(nearest.len());
+                if k < nearest.len() {
+                    nearest.select_nth_unstable_by(k, |a, b| a.0.total_cmp(&b.0));
+                }
+                nearest.truncate(k);
+                nearest.sort_by(|a, b| a.0.total_cmp(&b.0));
+                // On a tie, the label of the nearest neighbor wins
+                majority(nearest.iter().map(|(_, label)| *label))
+            }
+            Method::Forest(trees) => majority(trees.iter().map(|tree| tree.classify(&vector))),
+        }
+    }
+
+    /// Saves the classifier as CLASSIFIER_FILE in the given directory
+    pub fn save<P: AsRef<Path>>(&self, artifact_dir: P) -> Result<()> {
+        let artifact_dir = artifact_dir.as_ref();
+        std::fs::create_dir_all(artifact_dir)?;
+        let method = match &self.method {
+            Method::Linear(linear) => json!({
+                "classes": linear.classes,
+                "weights": linear.weights,
+                "biases": linear.biases,
+            }),
+            Method::Knn(points) => json!(points),
+            Method::Forest(trees) => Value::Array(trees.iter().map(Tree::to_json).collect()),
+        };
+        let features = features::Input::Features(self.features.clone());
+        let text = json!({
+            "kind": self.kind.name(),
+            "filter": self.filter.to_string(),
+            "input": features.to_string(),
+            "emg_channels": self.layout.emg_channels,
+            "devices": self.layout.devices,
+            "action_count": self.action_count,
+            "accuracy": self.accuracy,
+            "means": self.scaler.means,
+            "scales": self.scaler.scales,
+            "method": method,
+        });
+        std::fs::write(artifact_dir.join(CLASSIFIER_FILE), text.to_string())?;
+        Ok(())
+    }
+
+    /// Loads a classifier that save() wrote to the given directory
+    pub fn load<P: AsRef<Path>>(artifact_dir: P) -> Result<Self> {
+        let text = std::fs::read_to_string(artifact_dir.as_ref().join(CLASSIFIER_FILE))?;
+        let value: Value = serde_json::from_str(&text)
+            .map_err(|err| Error::Parse(format!("Invalid classifier file: {err}")))?;
+        let kind: ClassifierKind = text_field(&value, "kind")?.parse()?;
+        let features = match text_field(&value, "input")?.parse()? {
+            features::Input::Features(features) => features,
+            features::Input::Raw => return Err(invalid("the input has no features")),
+        };
+        let method = field(&value, "method")?;
+        let method = match kind {
+            ClassifierKind::Lda | ClassifierKind::Svm => Method::Linear(Linear {
+                classes: array(field(method, "classes")?, label)?,
+                weights: array(field(method, "weights")?, numbers)?,
+                biases: numbers(field(method, "biases")?)?,
+            }),
+            ClassifierKind::Knn => Method::Knn(array(method, |point| {
+                Ok((numbers(index(point, 0)?)?, label(index(point, 1)?)?))
+            })?),
+            ClassifierKind::Forest => Method::Forest(array(method, Tree::from_json)?),
+            ClassifierKind::NeuralNetwork => {
+                return Err(invalid("the neural network isn't a classical classifier"))
+            }
+        };
+        let accuracy = match field(&value, "accuracy")? {
+            Value::Null => None,
+            accuracy => Some(number(accuracy)?),
+        };
+        let classifier = Self {
+            kind,
+            method,
+            scaler: Scaler {
+                means: numbers(field(&value, "means")?)?,
+                scales: numbers(field(&value, "scales")?)?,
+            },
+            features,
+            filter: text_field(&value, "filter")?.parse()?,
+            layout: protocol::ChannelLayout {
+                emg_channels: count(field(&value, "emg_channels")?)?,
+                devices: count(field(&value, "devices")?)?,
+            },
+            action_count: count(field(&value, "action_count")?)?,
+            accuracy,
+        };
+        classifier.check()?;
+        Ok(classifier)
+    }
+
+    // Checks that the parameters fit the feature vectors, so that classify() can't fail
+    fn check(&self) -> Result<()> {
+        let dims: usize = (0..self.layout.total_channels())
+            .map(|channel| match self.layout.is_emg(channel) {
+                true => self.features.len(),
+                false => 1,
+            })
+            .sum();
+        let fits = match &self.method {
+            Method::Linear(linear) => {
+                linear.weights.iter().all(|weight| weight.len() == dims)
+                    && linear.classes.len() == linear.weights.len()
+                    && linear.biases.len() == linear.weights.len()
+            }
+            Method::Knn(points) => points.iter().all(|(point, _)| point.len() == dims),
+            Method::Forest(trees) => trees.iter().all(|tree| tree.fits(dims)),
+        };
+        match fits && self.scaler.means.len() == dims && self.scaler.scales.len() == dims {
+            true => Ok(()),
+            false => Err(invalid(
+                "the parameters don't fit the features and channels",
+            )),
+        }
+    }
+}
+
+impl Classifier for Classical {
+    fn describe(&self) -> String {
+        let names: Vec<&str> = self.features.iter().map(Feature::name).collect();
+        let mut description = format!("{} on {}", self.kind, names.join(" "));
+        if let Some(accuracy) = self.accuracy {
+            description.push_str(&format!(", validation accuracy {:.1}%", accuracy * 100.0));
+        }
+        description
+    }
+
+    fn channel_count(&self) -> usize {
+        self.layout.total_channels()
+    }
+
+    fn action_count(&self) -> usize {
+        self.action_count
+    }
+
+    fn filter_chain(&self) -> Result<dsp::FilterChain> {
+        Ok(self.filter.clone())
+    }
+
+    fn predict(&self, window: &[Vec<u8>], layout: protocol::ChannelLayout) -> Result<i32> {
+        let vector = features::window_features(&self.features, window, layout);
+        Ok(self.classify(vector) as i32)
+    }
+}
+
+/// Whether the given directory holds a classifier that Classical::save wrote
+pub fn is_saved<P: AsRef<Path>>(artifact_dir: P) -> bool {
+    artifact_dir.as_ref().join(CLASSIFIER_FILE).is_file()
+}
+
+impl Scaler {
+    fn fit(samples: &[(Vec<f64>, u8)]) -> Self {
+        let dims = samples[0].0.len();
+        let count = samples.len() as f64;
+        let means: Vec<f64> = (0..dims)
+            .map(|i| samples.iter().map(|(x, _)| x[i]).sum::<f64>() / count)
+            .collect();
+        let scales = (0..dims)
+            .map(|i| {
+                let variance = samples
+                    .iter()
+                    .map(|(x, _)| (x[i] - means[i]).powi(2))
+                    .sum::<f64>()
+                    / count;
+                match variance > 0.0 {
+                    true => 1.0 / variance.sqrt(),
+                    false => 1.0,
+                }
+            })
+            .collect();
+        Self { means, scales }
+    }
+
+    fn scale(&self, vector: &mut [f64]) {
+        for ((x, mean), scale) in vector.iter_mut().zip(&self.means).zip(&self.scales) {
+            *x = (*x - mean) * scale;
+        }
+    }
+}
+
+impl Linear {
+    fn classes(samples: &[(Vec<f64>, u8)]) -> Vec<u8> {
+        let mut classes: Vec<u8> = samples.iter().map(|(_, label)| *label).collect();
+        classes.sort();
+        classes.dedup();
+        classes
+    }
+
+    fn lda(samples: &[(Vec<f64>, u8)]) -> Self {
+        let classes = Self::classes(samples);
+        let dims = samples[0].0.len();
+        let means: Vec<Vec<f64>> = classes
+            .iter()
+            .map(|class| {
+                let members: Vec<&Vec<f64>> = samples
+                    .iter()
+                    .filter(|(_, label)| label == class)
+                    .map(|(x, _)| x)
+                    .collect();
+                (0..dims)
+                    .map(|i| members.iter().map(|x| x[i]).sum::<f64>() / members.len() as f64)
+                    .collect()
+            })
+            .collect();
+
+        // The covariance within the classes, pooled over all classes
+        let mut covariance = vec![vec![0.0; dims]; dims];
+        for (x, label) in samples {
+            let mean = &means[classes.binary_search(label).unwrap_or_default()];
+            for i in 0..dims {
+                for j in 0..dims {
+                    covariance[i][j] += (x[i] - mean[i]) * (x[j] - mean[j]);
+                }
+            }
+        }
+        let degrees = samples.len().saturating_sub(classes.len()).max(1) as f64;
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                let identity = if i == j { 1.0 } else { 0.0 };
+                *value = (1.0 - LDA_SHRINKAGE) * *value / degrees + LDA_SHRINKAGE * identity;
+            }
+        }
+
+        let weights: Vec<Vec<f64>> = means
+            .iter()
+            .map(|mean| solve(covariance.clone(), mean.clone()))
+            .collect();
+        let biases = classes
+            .iter()
+            .zip(&means)
+            .zip(&weights)
+            .map(|((class, mean), weight)| {
+                let count = samples.iter().filter(|(_, label)| label == class).count();
+                let prior = count as f64 / samples.len() as f64;
+                -0.5 * dot(mean, weight) + prior.ln()
+            })
+            .collect();
+        Self {
+            classes,
+            weights,
+            biases,
+        }
+    }
+
+    // Trained with the Pegasos algorithm, stochastic gradient descent on the hinge loss
+    fn svm(samples: &[(Vec<f64>, u8)], rng: &mut StdRng) -> Self {
+        let classes = Self::classes(samples);
+        let dims = samples[0].0.len();
+        let mut weights = vec![vec![0.0; dims]; classes.len()];
+        let mut biases = vec![0.0; classes.len()];
+        let mut order: Vec<usize> = (0..samples.len()).collect();
+        let mut step = 0;
+        for _ in 0..SVM_EPOCHS {
+            order.shuffle(rng);
+            for index in &order {
+                step += 1;
+                let rate = 1.0 / (SVM_LAMBDA * (step as f64 + SVM_OFFSET));
+                let (x, label) = &samples[*index];
+                for ((class, weight), bias) in classes.iter().zip(&mut weights).zip(&mut biases) {
+                    let y = if class == label { 1.0 } else { -1.0 };
+                    let margin = y * (dot(weight, x) + *bias);
+                    weight
+                        .iter_mut()
+                        .for_each(|w| *w *= 1.0 - rate * SVM_LAMBDA);
+                    if margin < 1.0 {
+                        weight
+                            .iter_mut()
+                            .zip(x)
+                            .for_each(|(w, x)| *w += rate * y * x);
+                        *bias += rate * y;
+                    }
+                }
+            }
+        }
+        Self {
+            classes,
+            weights,
+            biases,
+        }
+    }
+
+    fn classify(&self, x: &[f64]) -> u8 {
+        let scores = self
+            .weights
+            .iter()
+            .zip(&self.biases)
+            .map(|(weight, bias)| dot(weight, x) + bias);
+        let best = scores
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index);
+        best.map(|index| self.classes[index]).unwrap_or_default()
+    }
+}
+
+impl Tree {
+    fn grow(samples: Vec<&(Vec<f64>, u8)>, depth: usize, rng: &mut StdRng) -> Self {
+        let labels = samples.iter().map(|(_, label)| *label);
+        let leaf = Tree::Leaf(majority(labels.clone()));
+        let first = samples[0].1;
+        if depth >= TREE_DEPTH || samples.len() < MIN_SPLIT || labels.clone().all(|l| l == first) {
+            return leaf;
+        }
+
+        // Every split considers a random subset of the features
+        let dims = samples[0].0.len();
+        let tries = (dims as f64).sqrt().ceil() as usize;
+        let mut candidates: Vec<usize> = (0..dims).collect();
+        candidates.shuffle(rng);
+        let mut best: Option<(f64, usize, f64)> = None; // (impurity, feature, threshold)
+        for feature in candidates.into_iter().take(tries) {
+            if let Some((impurity, threshold)) = best_threshold(&samples, feature) {
+                if best.map_or(true, |(lowest, _, _)| impurity < lowest) {
+                    best = Some((impurity, feature, threshold));
+                }
+            }
+        }
+        let Some((_, feature, threshold)) = best else {
+            return leaf;
+        };
+        let (below, above): (Vec<_>, Vec<_>) = samples
+            .into_iter()
+            .partition(|(x, _)| x[feature] < threshold);
+        Tree::Split {
+            feature,
+            threshold,
+            below: Box::new(Tree::grow(below, depth + 1, rng)),
+            above: Box::new(Tree::grow(above, depth + 1, rng)),
+        }
+    }
+
+    fn classify(&self, x: &[f64]) -> u8 {
+        match self {
+            Tree::Leaf(label) => *label,
+            Tree::Split {
+                feature,
+                threshold,
+                below,
+                above,
+            } => match x[*feature] < *threshold {
+                true => below.classify(x),
+                false => above.classify(x),
+            },
+        }
+    }
+
+    // A leaf is its label, a split an object with both branches
+    fn to_json(&self) -> Value {
+        match self {
+            Tree::Leaf(label) => json!(label),
+            Tree::Split {
+                feature,
+                threshold,
+                below,
+                above,
+            } => json!({
+                "feature": feature,
+                "threshold": threshold,
+                "below": below.to_json(),
+                "above": above.to_json(),
+            }),
+        }
+    }
+
+    // Whether every split uses one of the given number of features
+    fn fits(&self, dims: usize) -> bool {
+        match self {
+            Tree::Leaf(_) => true,
+            Tree::Split {
+                feature,
+                below,
+                above,
+                ..
+            } => *feature < dims && below.fits(dims) && above.fits(dims),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        if !value.is_object() {
+            return Ok(Tree::Leaf(label(value)?));
+        }
+        Ok(Tree::Split {
+            feature: count(field(value, "feature")?)?,
+            threshold: number(field(value, "threshold")?)?,
+            below: Box::new(Tree::from_json(field(value, "below")?)?),
+            above: Box::new(Tree::from_json(field(value, "above")?)?),
+        })
+    }
+}
+
+// Helpers for reading the values of a classifier file
+fn invalid(problem: &str) -> Error {
+    Error::Parse(format!("Invalid classifier file: {problem}"))
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> Result<&'a Value> {
+    value
+        .get(key)
+        .ok_or_else(|| invalid(&format!("\"{key}\" is missing")))
+}
+
+fn index(value: &Value, index: usize) -> Result<&Value> {
+    value
+        .get(index)
+        .ok_or_else(|| invalid(&format!("item {index} is missing")))
+}
+
+fn text_field<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    field(value, key)?
+        .as_str()
+        .ok_or_else(|| invalid(&format!("\"{key}\" isn't a string")))
+}
+
+fn number(value: &Value) -> Result<f64> {
+    value.as_f64().ok_or_else(|| invalid("expected a number"))
+}
+
+fn count(value: &Value) -> Result<usize> {
+    value
+        .as_u64()
+        .map(|count| count as usize)
+        .ok_or_else(|| invalid("expected a count"))
+}
+
+fn label(value: &Value) -> Result<u8> {
+    value
+        .as_u64()
+        .and_then(|label| u8::try_from(label).ok())
+        .ok_or_else(|| invalid("expected a label"))
+}
+
+fn array<T>(value: &Value, item: impl Fn(&Value) -> Result<T>) -> Result<Vec<T>> {
+    value
+        .as_array()
+        .ok_or_else(|| invalid("expected an array"))?
+        .iter()
+        .map(item)
+        .collect()
+}
+
+fn numbers(value: &Value) -> Result<Vec<f64>> {
+    array(value, number)
+}
+
+// The threshold of a feature that splits the samples with the lowest weighted Gini
+// impurity, and that impurity
+fn best_threshold(samples: &[&(Vec<f64>, u8)], feature: usize) -> Option<(f64, f64)> {
+    let mut sorted: Vec<(f64, u8)> = samples.iter().map(|(x, l)| (x[feature], *l)).collect();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut below = [0usize; 256];
+    let mut above = [0usize; 256];
+    sorted
+        .iter()
+        .for_each(|(_, label)| above[*label as usize] += 1);
+    let gini = |counts: &[usize; 256], total: usize| {
+        let sum: f64 = counts
+            .iter()
+            .map(|c| (*c as f64 / total as f64).powi(2))
+            .sum();
+        total as f64 * (1.0 - sum)
+    };
+    let mut best = None;
+    for i in 1..sorted.len() {
+        let label = sorted[i - 1].1 as usize;
+        below[label] += 1;
+        above[label] -= 1;
+        if sorted[i].0 == sorted[i - 1].0 {
+            continue;
+        }
+        let impurity = gini(&below, i) + gini(&above, sorted.len() - i);
+        if best.map_or(true, |(lowest, _)| impurity < lowest) {
+            best = Some((impurity, (sorted[i].0 + sorted[i - 1].0) / 2.0));
+        }
+    }
+    best
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum()
+}
+
+// The most frequent label, the first one on a tie
+fn majority(labels: impl Iterator<Item = u8>) -> u8 {
+    let mut counts = [0usize; 256];
+    let mut order = vec![];
+    for label in labels {
+        if counts[label as usize] == 0 {
+            order.push(label);
+        }
+        counts[label as usize] += 1;
+    }
+    let most = order.iter().map(|l| counts[*l as usize]).max().unwrap_or(0);
+    order
+        .into_iter()
+        .find(|label| counts[*label as usize] == most)
+        .unwrap_or_default()
+}
+
+// Solves the linear equations a * x = b by Gaussian elimination with partial pivoting
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Vec<f64> {
+    let n = b.len();
+    for column in 0..n {
+        let pivot = (column..n)
+            .max_by(|i, j| a[*i][column].abs().total_cmp(&a[*j][column].abs()))
+            .unwrap_or(column);
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        if a[column][column].abs() < f64::EPSILON {
+            continue;
+        }
+        let (upper, lower) = a.split_at_mut(column + 1);
+        let pivot_row = &upper[column];
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            row.iter_mut()
+                .zip(pivot_row)
+                .skip(column)
+                .for_each(|(value, pivot)| *value -= factor * pivot);
+            b[column + 1 + offset] -= factor * b[column];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        if a[row][row].abs() >= f64::EPSILON {
+            x[row] = (b[row] - sum) / a[row][row];
+        }
+    }
+    x
+}
+
+#[test]
+fn test_classifiers() {
+    // Action 1 makes channel 0 noisy, action 2 channel 3, the null action neither
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut dataset = PsyLinkDataset::default();
+    for block in 0..60 {
+        let label = (block % 3) as u8;
+        for i in 0..300 {
+            let packet = (0..14)
+                .map(|channel| {
+                    let active = (label == 1 && channel == 0) || (label == 2 && channel == 3);
+                    let amplitude = if active { 60 } else { 5 };
+                    127u8.wrapping_add_signed(rng.gen_range(-amplitude..=amplitude))
+                })
+                .collect();
+            let index = dataset.all_packets.len();
+            dataset.all_packets.push(packet);
+            dataset
+                .timestamps
+                .push(index as f64 / firmware::SAMPLE_RATE);
+            if i >= 250 && i % 10 == 0 {
+                dataset.datapoints.push(calibration::Datapoint {
+                    packet_index: index,
+                    label,
+                });
+            }
+        }
+    }
+    let filter: dsp::FilterChain = "highpass 10".parse().unwrap();
+    let features = [Feature::Rms, Feature::WaveformLength];
+    let recent = &dataset.all_packets[..300 * 5]; // Ends with action 1
+    let artifact_dir =
+        std::env::temp_dir().join(format!("psylink_classifier_test_{}", std::process::id()));
+
+    for kind in ClassifierKind::ALL {
+        assert_eq!(kind.to_string().parse::<ClassifierKind>().unwrap(), kind);
+        let trained = train(&dataset, kind, &filter, &features, 4000, true);
+        if kind == ClassifierKind::NeuralNetwork {
+            assert!(trained.is_err());
+            continue;
+        }
+        let classifier = trained.unwrap();
+        assert!(
+            classifier.accuracy.unwrap() > 0.9,
+            "{}",
+            classifier.describe()
+        );
+        assert_eq!(classifier.channel_count(), 14);
+        let sample_rate = classifier.filter_chain().unwrap().sample_rate.unwrap();
+        assert!((sample_rate - firmware::SAMPLE_RATE).abs() < 1e-6);
+        // Like CalibController::infer_latest, filter the stream before predicting
+        let layout = dataset.layout;
+        let chain = classifier.filter_chain().unwrap();
+        let mut stream = dsp::StreamFilter::new(&chain, layout, dataset.sample_rate());
+        let filtered: Vec<Vec<u8>> = recent.iter().map(|row| stream.filter_row(row)).collect();
+        let window = &filtered[filtered.len() - 250..];
+        assert_eq!(classifier.predict(window, layout).unwrap(), 1, "{kind}");
+
+        // Save it like train does, and load it again
+        classifier.save(&artifact_dir).unwrap();
+        assert!(is_saved(&artifact_dir));
+        let loaded =
+            calibration::load_model(&artifact_dir, calibration::BackendKind::NdArray).unwrap();
+        assert_eq!(loaded.describe(), classifier.describe());
+        assert_eq!(loaded.channel_count(), 14);
+        assert_eq!(loaded.action_count(), 2);
+        assert_eq!(loaded.filter_chain().unwrap(), chain);
+        for end in (250..recent.len()).step_by(100) {
+            let window = &filtered[end - 250..end];
+            assert_eq!(
+                loaded.predict(window, layout).unwrap(),
+                classifier.predict(window, layout).unwrap(),
+                "{kind}"
+            );
+        }
+    }
+
+    // Parameters that don't fit the features are rejected
+    let path = artifact_dir.join(CLASSIFIER_FILE);
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, text.replace("\"devices\":1", "\"devices\":2")).unwrap();
+    assert!(Classical::load(&artifact_dir).is_err());
+    std::fs::write(&path, "{}").unwrap();
+    assert!(Classical::load(&artifact_dir).is_err());
+    std::fs::remove_dir_all(&artifact_dir).unwrap();
+    assert!("svn".parse::<ClassifierKind>().is_err());
+    assert!(train(
+        &PsyLinkDataset::default(),
+        ClassifierKind::Lda,
+        &filter,
+        &features,
+        10,
+        true
+    )
+    .is_err());
+}
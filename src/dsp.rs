@@ -0,0 +1,414 @@
+// Causal filters that condition the EMG signals while they stream in, sample by sample,
+// so they can run on live data as well as on recordings with the same result.
+//
+// A filter chain is described by statements separated by semicolons or newlines:
+//
+//     dc; notch 50 harmonics 3; bandpass 20 200
+//     highpass 20; biquad 0.2 0.4 0.2 -0.5 0.3; rate 500
+//
+//   dc                     removes the DC offset with a one-pole high-pass at DC_CUTOFF
+//   highpass F, lowpass F  2nd order Butterworth filters with the cutoff F in Hz
+//   bandpass LOW HIGH      a high-pass at LOW followed by a low-pass at HIGH
+//   notch F [harmonics N]  removes mains hum at F Hz and the next N-1 multiples of F
+//   biquad B0 B1 B2 A1 A2  any 2nd order IIR filter, normalized so that A0 is 1
+//   rate R                 designs the filters for R samples per second, instead of the
+//                          rate of the signal they are applied to
+//
+// The filters work on the deviation of the samples from the middle of the ADC range and
+// only touch the EMG channels.  Every filter is a cascade of biquads in transposed
+// direct form II, with a separate state for each channel.
+
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+const DC_CUTOFF: f64 = 0.5; // Hz
+const NOTCH_Q: f64 = 30.0; // The notch is 50/30 = 1.7 Hz wide at 50 Hz
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+const MAX_CUTOFF: f64 = 0.49; // Cutoff frequencies are capped at this fraction of the sample rate
+const RATE_TOLERANCE: f64 = 0.01; // Relative change of the sample rate that triggers a redesign
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    DcRemoval,
+    HighPass(f64),
+    LowPass(f64),
+    BandPass(f64, f64),
+    Notch { frequency: f64, harmonics: usize },
+    Biquad(Biquad),
+}
+
+/// The filters that are applied one after the other
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilterChain {
+    pub filters: Vec<Filter>,
+    pub sample_rate: Option<f64>, // Design for this rate rather than the rate of the signal
+}
+
+/// The coefficients of a 2nd order IIR filter with a0 = 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Biquad {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+/// Runs a filter chain over the channels of a signal
+#[derive(Clone, Debug)]
+pub struct StreamFilter {
+    chain: FilterChain,
+    layout: protocol::ChannelLayout,
+    sample_rate: f64,
+    stages: Vec<Biquad>,
+    states: Vec<Vec<[f64; 2]>>, // states[channel][stage]
+}
+
+impl Biquad {
+    /// Lets the signal through unchanged
+    pub const IDENTITY: Biquad = Biquad {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    // The designs follow the Audio EQ Cookbook by Robert Bristow-Johnson
+    fn design(frequency: f64, rate: f64, q: f64, b: impl Fn(f64) -> [f64; 3]) -> Self {
+        let w0 = 2.0 * PI * (frequency / rate).min(MAX_CUTOFF);
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let [b0, b1, b2] = b(w0.cos());
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    pub fn lowpass(cutoff: f64, rate: f64, q: f64) -> Self {
+        Self::design(cutoff, rate, q, |cos| {
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0]
+        })
+    }
+
+    pub fn highpass(cutoff: f64, rate: f64, q: f64) -> Self {
+        Self::design(cutoff, rate, q, |cos| {
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0]
+        })
+    }
+
+    pub fn notch(frequency: f64, rate: f64, q: f64) -> Self {
+        Self::design(frequency, rate, q, |cos| [1.0, -2.0 * cos, 1.0])
+    }
+
+    /// A one-pole high-pass, y[n] = x[n] - x[n-1] + r * y[n-1]
+    pub fn dc_blocker(cutoff: f64, rate: f64) -> Self {
+        let r = 1.0 - 2.0 * PI * cutoff / rate;
+        Biquad {
+            b0: 1.0,
+            b1: -1.0,
+            b2: 0.0,
+            a1: -r.clamp(0.0, 1.0),
+            a2: 0.0,
+        }
+    }
+
+    /// Filters one sample, updating the state of the channel
+    pub fn process(&self, state: &mut [f64; 2], x: f64) -> f64 {
+        let y = self.b0 * x + state[0];
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+impl Filter {
+    /// The biquads that make up this filter at the given sample rate
+    pub fn stages(&self, rate: f64) -> Vec<Biquad> {
+        match *self {
+            Filter::DcRemoval => vec![Biquad::dc_blocker(DC_CUTOFF, rate)],
+            Filter::HighPass(cutoff) => vec![Biquad::highpass(cutoff, rate, BUTTERWORTH_Q)],
+            Filter::LowPass(cutoff) => vec![Biquad::lowpass(cutoff, rate, BUTTERWORTH_Q)],
+            Filter::BandPass(low, high) => vec![
+                Biquad::highpass(low, rate, BUTTERWORTH_Q),
+                Biquad::lowpass(high, rate, BUTTERWORTH_Q),
+            ],
+            // Harmonics above the Nyquist frequency don't exist in the signal
+            Filter::Notch {
+                frequency,
+                harmonics,
+            } => (1..=harmonics)
+                .map(|n| n as f64 * frequency)
+                .take_while(|harmonic| *harmonic < rate * MAX_CUTOFF)
+                .map(|harmonic| Biquad::notch(harmonic, rate, NOTCH_Q))
+                .collect(),
+            Filter::Biquad(biquad) => vec![biquad],
+        }
+    }
+}
+
+impl FilterChain {
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Fixes the sample rate that the filters are designed for, unless it's fixed already.
+    /// A model that was trained on filtered data needs the very same filters later on.
+    pub fn with_sample_rate(&self, rate: f64) -> Self {
+        Self {
+            filters: self.filters.clone(),
+            sample_rate: self.sample_rate.or(Some(rate)),
+        }
+    }
+}
+
+impl FromStr for FilterChain {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut chain = FilterChain::default();
+        for statement in text.split([';', '\n']) {
+            let words: Vec<&str> = statement.split_whitespace().collect();
+            let invalid = || Error::Parse(format!("Invalid filter: {statement}"));
+            let numbers = |words: &[&str]| -> Result<Vec<f64>> {
+                words
+                    .iter()
+                    .map(|word| word.parse::<f64>().ok().filter(|x| x.is_finite()))
+                    .collect::<Option<Vec<f64>>>()
+                    .ok_or_else(invalid)
+            };
+            let frequency = |word: &str| -> Result<f64> {
+                let frequency = numbers(&[word])?[0];
+                match frequency > 0.0 {
+                    true => Ok(frequency),
+                    false => Err(invalid()),
+                }
+            };
+            let filter = match words.as_slice() {
+                [] | ["none"] => continue,
+                ["dc"] => Filter::DcRemoval,
+                ["highpass", cutoff] => Filter::HighPass(frequency(cutoff)?),
+                ["lowpass", cutoff] => Filter::LowPass(frequency(cutoff)?),
+                ["bandpass", low, high] => {
+                    let (low, high) = (frequency(low)?, frequency(high)?);
+                    if low >= high {
+                        return Err(invalid());
+                    }
+                    Filter::BandPass(low, high)
+                }
+                ["notch", notch] | ["notch", notch, "harmonics", _] => Filter::Notch {
+                    frequency: frequency(notch)?,
+                    harmonics: match words.get(3) {
+                        Some(count) => count.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                        None => 1,
+                    },
+                },
+                ["biquad", coefficients @ ..] if coefficients.len() == 5 => {
+                    let [b0, b1, b2, a1, a2] = numbers(coefficients)?[..] else {
+                        return Err(invalid());
+                    };
+                    Filter::Biquad(Biquad { b0, b1, b2, a1, a2 })
+                }
+                ["rate", rate] => {
+                    chain.sample_rate = Some(frequency(rate)?);
+                    continue;
+                }
+                _ => return Err(invalid()),
+            };
+            chain.filters.push(filter);
+        }
+        Ok(chain)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::DcRemoval => write!(f, "dc"),
+            Filter::HighPass(cutoff) => write!(f, "highpass {cutoff}"),
+            Filter::LowPass(cutoff) => write!(f, "lowpass {cutoff}"),
+            Filter::BandPass(low, high) => write!(f, "bandpass {low} {high}"),
+            Filter::Notch {
+                frequency,
+                harmonics: 1,
+            } => write!(f, "notch {frequency}"),
+            Filter::Notch {
+                frequency,
+                harmonics,
+            } => write!(f, "notch {frequency} harmonics {harmonics}"),
+            Filter::Biquad(Biquad { b0, b1, b2, a1, a2 }) => {
+                write!(f, "biquad {b0} {b1} {b2} {a1} {a2}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut statements: Vec<String> = self.filters.iter().map(Filter::to_string).collect();
+        if let Some(rate) = self.sample_rate {
+            statements.push(format!("rate {rate}"));
+        }
+        write!(f, "{}", statements.join("; "))
+    }
+}
+
+impl StreamFilter {
+    /// Prepares the filters for a signal with the given layout and sample rate.  If the
+    /// chain fixes the sample rate, that one is used instead.
+    pub fn new(chain: &FilterChain, layout: protocol::ChannelLayout, sample_rate: f64) -> Self {
+        let mut filter = Self {
+            chain: chain.clone(),
+            layout,
+            sample_rate: chain.sample_rate.unwrap_or(sample_rate),
+            stages: vec![],
+            states: vec![],
+        };
+        filter.design();
+        filter
+    }
+
+    fn design(&mut self) {
+        self.stages = self
+            .chain
+            .filters
+            .iter()
+            .flat_map(|filter| filter.stages(self.sample_rate))
+            .collect();
+        let channels = self.layout.total_channels();
+        self.states = vec![vec![[0.0; 2]; self.stages.len()]; channels];
+    }
+
+    pub fn chain(&self) -> &FilterChain {
+        &self.chain
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Adapts the filters to a new estimate of the sample rate, unless the chain fixes it.
+    /// The state of the filters is kept, so the signal continues smoothly.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        let change = (sample_rate - self.sample_rate).abs() / self.sample_rate;
+        if self.chain.sample_rate.is_none() && sample_rate > 0.0 && change > RATE_TOLERANCE {
+            self.sample_rate = sample_rate;
+            let states = std::mem::take(&mut self.states);
+            self.design();
+            if states.len() == self.states.len() {
+                self.states = states;
+            }
+        }
+    }
+
+    /// Filters the next sample of a channel, given as deviation from the middle of the
+    /// ADC range.  Channels other than EMG channels pass unchanged.
+    pub fn filter(&mut self, channel: usize, value: f64) -> f64 {
+        if !self.layout.is_emg(channel) {
+            return value;
+        }
+        let Some(states) = self.states.get_mut(channel) else {
+            return value;
+        };
+        self.stages
+            .iter()
+            .zip(states.iter_mut())
+            .fold(value, |x, (stage, state)| stage.process(state, x))
+    }
+
+    /// Filters the next row of samples, one byte per channel like in
+    /// calibration::PsyLinkDataset, and returns the filtered bytes
+    pub fn filter_row(&mut self, row: &[u8]) -> Vec<u8> {
+        row.iter()
+            .enumerate()
+            .map(|(channel, byte)| {
+                let value = self.filter(channel, *byte as f64 - protocol::SAMPLE_CENTER)
+                    + protocol::SAMPLE_CENTER;
+                value.round().clamp(u8::MIN as f64, u8::MAX as f64) as u8
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_filter_chain() {
+    let chain: FilterChain = "dc; notch 50 harmonics 3\nbandpass 20 200; rate 500"
+        .parse()
+        .unwrap();
+    assert_eq!(chain.filters.len(), 3);
+    assert_eq!(chain.sample_rate, Some(500.0));
+    assert_eq!(
+        chain.to_string(),
+        "dc; notch 50 harmonics 3; bandpass 20 200; rate 500"
+    );
+    assert_eq!(chain.to_string().parse::<FilterChain>().unwrap(), chain);
+    assert!("".parse::<FilterChain>().unwrap().is_empty());
+    assert!("none".parse::<FilterChain>().unwrap().is_empty());
+    assert_eq!(
+        "biquad 1 0 0 0 0".parse::<FilterChain>().unwrap().filters,
+        [Filter::Biquad(Biquad::IDENTITY)]
+    );
+    for invalid in [
+        "bandpass 200 20",
+        "notch -50",
+        "notch 50 harmonics 0",
+        "lowpass",
+        "fft",
+    ] {
+        assert!(invalid.parse::<FilterChain>().is_err(), "{invalid}");
+    }
+    // Harmonics above the Nyquist frequency are left out
+    let notch = Filter::Notch {
+        frequency: 60.0,
+        harmonics: 10,
+    };
+    assert_eq!(notch.stages(500.0).len(), 4);
+}
+
+#[test]
+fn test_stream_filter() {
+    let layout = protocol::ChannelLayout::new(2);
+    let rate = 500.0;
+    let chain: FilterChain = "highpass 10; notch 50".parse().unwrap();
+    let mut filter = StreamFilter::new(&chain, layout, rate);
+
+    // Channel 0 carries 50 Hz hum, channel 1 a 120 Hz signal, both with a DC offset
+    let sine = |frequency: f64, i: usize| (2.0 * PI * frequency * i as f64 / rate).sin();
+    let mut peaks = [0.0f64; 2];
+    let mut gyro = vec![];
+    for i in 0..2000 {
+        let row: Vec<u8> = (0..layout.total_channels())
+            .map(|channel| match channel {
+                0 => (160.0 + 50.0 * sine(50.0, i)) as u8,
+                1 => (160.0 + 50.0 * sine(120.0, i)) as u8,
+                _ => 100,
+            })
+            .collect();
+        let filtered = filter.filter_row(&row);
+        gyro.push(filtered[2]);
+        // Skip the settling time of the filters
+        if i >= 1000 {
+            for (peak, value) in peaks.iter_mut().zip(&filtered) {
+                *peak = peak.max((*value as f64 - protocol::SAMPLE_CENTER).abs());
+            }
+        }
+    }
+    assert!(peaks[0] < 5.0, "{peaks:?}");
+    assert!(peaks[1] > 40.0, "{peaks:?}");
+    assert!(gyro.iter().all(|value| *value == 100));
+
+    let mut identity = StreamFilter::new(&FilterChain::default(), layout, rate);
+    assert!(identity.is_empty());
+    let row: Vec<u8> = (0..14).map(|i| i * 17).collect();
+    assert_eq!(
+        identity.filter_row(&row[..layout.total_channels()]),
+        row[..8]
+    );
+}
@@ -0,0 +1,676 @@
+// Exports datasets to the European Data Format, which most tools for biosignals can read,
+// and imports them again.  EDF+ stores 16-bit samples, BDF+ stores 24-bit samples.
+//
+// The files are continuous (EDF+C): sample i of every channel was taken i / rate seconds
+// after the start, where the rate is estimated from the decoded sampling delays.  Lost
+// signal doesn't interrupt the samples, it becomes an annotation "Signal lost (0.520s)".
+// The digital value of a sample is its byte plus protocol::SAMPLE_VALUE_OFFSET, and the
+// physical units follow from the way the firmware encodes measurements into bytes, see
+// firmware::EMG_VOLTS_PER_STEP and below.  EMG channels are the amplified voltages at the
+// analog pins in mV, relative to the middle of the ADC range.
+//
+// Calibration labels become annotations with the name of the action as text, one for
+// every run of consecutive datapoints with the same label.  Actions without a name are
+// written as "Label N".
+
+use crate::calibration::{Datapoint, Gap, PsyLinkDataset};
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER_LEN: usize = 256; // Also the length of the header of each signal
+const ANNOTATION_SEPARATOR: u8 = 0x14;
+const DURATION_SEPARATOR: u8 = 0x15;
+const GAP_TEXT: &str = "Signal lost";
+const MAX_SIGNALS: usize = 1024; // More than any number of PsyLinks that we could record at once
+const MAX_RECORD_LEN: usize = 1 << 24; // The spec recommends less than 61440 bytes
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EdfFormat {
+    #[default]
+    Edf,
+    Bdf,
+}
+
+impl EdfFormat {
+    /// Picks the format from the extension of a file name, EDF unless it ends with .bdf
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("bdf") => EdfFormat::Bdf,
+            _ => EdfFormat::Edf,
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        match self {
+            EdfFormat::Edf => 2,
+            EdfFormat::Bdf => 3,
+        }
+    }
+
+    fn annotations_label(&self) -> &'static str {
+        match self {
+            EdfFormat::Edf => "EDF Annotations",
+            EdfFormat::Bdf => "BDF Annotations",
+        }
+    }
+}
+
+/// The header of one signal, i.e. one channel
+#[derive(Clone, Debug, PartialEq)]
+struct Signal {
+    label: String,
+    transducer: String,
+    dimension: String,
+    physical_min: f64,
+    physical_max: f64,
+    digital_min: i32,
+    digital_max: i32,
+    samples_per_record: usize,
+}
+
+impl Signal {
+    fn for_channel(layout: &protocol::ChannelLayout, channel: usize, name: String) -> Self {
+        // The IMU is an LSM9DS1 or a BMI270, depending on the board the firmware was built for
+        let imu_zero = -protocol::SAMPLE_VALUE_OFFSET as f64;
+        let (transducer, dimension, scale, zero) = if layout.is_emg(channel) {
+            (
+                "PsyLink electrode",
+                "mV",
+                firmware::EMG_VOLTS_PER_STEP * 1000.0,
+                firmware::EMG_ZERO_BYTE,
+            )
+        } else if layout.is_gyroscope(channel) {
+            (
+                "IMU gyroscope",
+                "deg/s",
+                firmware::GYRO_DEGREES_PER_STEP,
+                imu_zero,
+            )
+        } else {
+            (
+                "IMU accelerometer",
+                "g",
+                firmware::ACCEL_G_PER_STEP,
+                imu_zero,
+            )
+        };
+        let digital_min = u8::MIN as i32 + protocol::SAMPLE_VALUE_OFFSET;
+        let digital_max = u8::MAX as i32 + protocol::SAMPLE_VALUE_OFFSET;
+        Self {
+            label: name,
+            transducer: transducer.into(),
+            dimension: dimension.into(),
+            physical_min: (u8::MIN as f64 - zero) * scale,
+            physical_max: (u8::MAX as f64 - zero) * scale,
+            digital_min,
+            digital_max,
+            samples_per_record: 0,
+        }
+    }
+
+    fn annotations(format: EdfFormat, bytes: usize) -> Self {
+        Self {
+            label: format.annotations_label().into(),
+            transducer: String::new(),
+            dimension: String::new(),
+            physical_min: -1.0,
+            physical_max: 1.0,
+            digital_min: -(1 << (8 * format.bytes_per_sample() - 1)),
+            digital_max: (1 << (8 * format.bytes_per_sample() - 1)) - 1,
+            samples_per_record: bytes.div_ceil(format.bytes_per_sample()),
+        }
+    }
+
+    fn is_annotations(&self) -> bool {
+        self.label == EdfFormat::Edf.annotations_label()
+            || self.label == EdfFormat::Bdf.annotations_label()
+    }
+
+    /// Maps a digital value of this signal to a sample byte
+    fn to_byte(&self, digital: i32) -> u8 {
+        let range = (self.digital_max - self.digital_min).max(1) as f64;
+        let fraction = (digital - self.digital_min) as f64 / range;
+        (fraction * u8::MAX as f64).round().clamp(0.0, 255.0) as u8
+    }
+}
+
+/// Writes the dataset as EDF+ or BDF+
+pub fn write_edf<W: Write>(dataset: &PsyLinkDataset, mut out: W, format: EdfFormat) -> Result<()> {
+    let rate = protocol::estimate_sample_rate(&dataset.timestamps);
+    let samples_per_record = (rate.round() as usize).max(1);
+    let record_duration = samples_per_record as f64 / rate;
+    let records = dataset
+        .all_packets
+        .len()
+        .div_ceil(samples_per_record)
+        .max(1);
+
+    // Every record starts with an annotation that tells its time
+    let mut annotations: Vec<Vec<u8>> = (0..records)
+        .map(|record| tal(record as f64 * record_duration, None, ""))
+        .collect();
+    for (index, duration, text) in events(dataset, rate) {
+        let record = (index / samples_per_record).min(records - 1);
+        annotations[record].extend(tal(index as f64 / rate, duration, &text));
+    }
+    let annotation_bytes = annotations.iter().map(Vec::len).max().unwrap_or(0);
+
+    let layout = dataset.layout;
+    let mut signals: Vec<Signal> = layout
+        .channel_names()
+        .into_iter()
+        .enumerate()
+        .map(|(channel, name)| Signal {
+            samples_per_record,
+            ..Signal::for_channel(&layout, channel, name)
+        })
+        .collect();
+    signals.push(Signal::annotations(format, annotation_bytes));
+
+    write_header(
+        &mut out,
+        dataset,
+        format,
+        &signals,
+        records,
+        record_duration,
+    )?;
+
+    let bytes_per_sample = format.bytes_per_sample();
+    for (record, annotation) in annotations.into_iter().enumerate() {
+        let start = record * samples_per_record;
+        let mut data = vec![];
+        for channel in 0..layout.total_channels() {
+            for i in start..start + samples_per_record {
+                // The last record is filled up with the last sample
+                let byte = dataset
+                    .all_packets
+                    .get(i)
+                    .or(dataset.all_packets.last())
+                    .and_then(|packet| packet.get(channel))
+                    .copied()
+                    .unwrap_or(protocol::GAP_SENTINEL);
+                let digital = byte as i32 + protocol::SAMPLE_VALUE_OFFSET;
+                data.extend(&digital.to_le_bytes()[..bytes_per_sample]);
+            }
+        }
+        let annotation_signal = signals.last().unwrap();
+        data.extend(&annotation);
+        data.resize(
+            data.len() + annotation_signal.samples_per_record * bytes_per_sample - annotation.len(),
+            0,
+        );
+        out.write_all(&data)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn save_edf<P: AsRef<Path>>(dataset: &PsyLinkDataset, path: P) -> Result<()> {
+    let format = EdfFormat::from_path(path.as_ref());
+    write_edf(dataset, BufWriter::new(File::create(path)?), format)
+}
+
+/// The annotations of a dataset: the packet index where they start, their duration in
+/// seconds, and their text
+fn events(dataset: &PsyLinkDataset, rate: f64) -> Vec<(usize, Option<f64>, String)> {
+    let mut events = vec![];
+    let mut datapoints: Vec<&Datapoint> = dataset.datapoints.iter().collect();
+    datapoints.sort_by_key(|datapoint| datapoint.packet_index);
+    let mut run: Option<(usize, usize, u8)> = None; // first index, last index, label
+    for datapoint in datapoints.into_iter().map(Some).chain([None]) {
+        if let (Some((first, last, label)), Some(datapoint)) = (run, datapoint) {
+            if datapoint.label == label && datapoint.packet_index == last + 1 {
+                run = Some((first, datapoint.packet_index, label));
+                continue;
+            }
+        }
+        if let Some((first, last, label)) = run {
+            let text = match dataset.info.action_names.get(label as usize) {
+                Some(name) => name.clone(),
+                None => format!("Label {label}"),
+            };
+            events.push((first, Some((last + 1 - first) as f64 / rate), text));
+        }
+        run = datapoint.map(|d| (d.packet_index, d.packet_index, d.label));
+    }
+    for gap in &dataset.gaps {
+        let duration = (gap.inserted > 0).then(|| gap.inserted as f64 / rate);
+        let text = format!("{GAP_TEXT} ({:.3}s)", gap.duration);
+        events.push((gap.packet_index, duration, text));
+    }
+    events.sort_by_key(|event| event.0);
+    events
+}
+
+/// Encodes a time-stamped annotation list
+fn tal(onset: f64, duration: Option<f64>, text: &str) -> Vec<u8> {
+    let mut bytes = format!("+{}", seconds(onset)).into_bytes();
+    if let Some(duration) = duration {
+        bytes.push(DURATION_SEPARATOR);
+        bytes.extend(seconds(duration).as_bytes());
+    }
+    bytes.push(ANNOTATION_SEPARATOR);
+    // The separators can't be part of the text
+    bytes.extend(
+        text.chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .as_bytes(),
+    );
+    bytes.push(ANNOTATION_SEPARATOR);
+    bytes.push(0);
+    bytes
+}
+
+fn seconds(value: f64) -> String {
+    let text = format!("{value:.6}");
+    match text.trim_end_matches('0').trim_end_matches('.') {
+        "-0" => "0".into(),
+        text => text.into(),
+    }
+}
+
+fn write_header<W: Write>(
+    out: &mut W,
+    dataset: &PsyLinkDataset,
+    format: EdfFormat,
+    signals: &[Signal],
+    records: usize,
+    record_duration: f64,
+) -> Result<()> {
+    let started = dataset
+        .info
+        .get_metadata("started")
+        .and_then(|started| started.parse::<f64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |duration| duration.as_secs_f64())
+        });
+    let (year, month, day, hour, minute, second) = civil_time(started as i64);
+    const MONTHS: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let equipment = match dataset.info.devices.first() {
+        Some(device) if !device.address.is_empty() => device.address.replace(' ', "_"),
+        _ => "PsyLink".into(),
+    };
+
+    let mut header = String::new();
+    let mut field = |text: &str, width: usize| {
+        let text: String = text.chars().filter(char::is_ascii).take(width).collect();
+        header.push_str(&format!("{text:width$}"));
+    };
+    // The first byte of a BDF file isn't ASCII, it's written separately below
+    match format {
+        EdfFormat::Edf => field("0", 8),
+        EdfFormat::Bdf => field("BIOSEMI", 7),
+    }
+    field("X X X X", 80);
+    field(
+        &format!(
+            "Startdate {day:02}-{}-{year} X X {equipment}",
+            MONTHS[month as usize - 1]
+        ),
+        80,
+    );
+    field(&format!("{day:02}.{month:02}.{:02}", year % 100), 8);
+    field(&format!("{hour:02}.{minute:02}.{second:02}"), 8);
+    field(&(HEADER_LEN * (signals.len() + 1)).to_string(), 8);
+    field(
+        match format {
+            EdfFormat::Edf => "EDF+C",
+            EdfFormat::Bdf => "BDF+C",
+        },
+        44,
+    );
+    field(&records.to_string(), 8);
+    field(&number(record_duration), 8);
+    field(&signals.len().to_string(), 4);
+
+    // The signal headers are stored field by field, not signal by signal
+    type Column = fn(&Signal) -> (String, usize);
+    let columns: [Column; 10] = [
+        |s| (s.label.clone(), 16),
+        |s| (s.transducer.clone(), 80),
+        |s| (s.dimension.clone(), 8),
+        |s| (number(s.physical_min), 8),
+        |s| (number(s.physical_max), 8),
+        |s| (s.digital_min.to_string(), 8),
+        |s| (s.digital_max.to_string(), 8),
+        |_| (String::new(), 80),
+        |s| (s.samples_per_record.to_string(), 8),
+        |_| (String::new(), 32),
+    ];
+    for column in columns {
+        for signal in signals {
+            let (text, width) = column(signal);
+            field(&text, width);
+        }
+    }
+
+    if format == EdfFormat::Bdf {
+        out.write_all(&[0xff])?;
+    }
+    out.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// Formats a number so that it fits into the 8 characters of a header field
+fn number(value: f64) -> String {
+    let text = (0..=6)
+        .rev()
+        .map(|decimals| format!("{value:.decimals$}"))
+        .find(|text| text.len() <= 8)
+        .unwrap_or_else(|| format!("{value:.0}"));
+    match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.').into(),
+        false => text,
+    }
+}
+
+/// Converts seconds since 1970 into year, month, day, hour, minute and second (UTC)
+fn civil_time(unix: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = unix.div_euclid(86400);
+    let time = unix.rem_euclid(86400) as u32;
+    // From Howard Hinnant's "civil_from_days"
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Reads an EDF(+) or BDF(+) file whose signals all have the same sample rate, like
+/// the ones written by write_edf
+pub fn read_edf<R: Read>(mut input: R) -> Result<PsyLinkDataset> {
+    let invalid = |what: &str| Error::Parse(format!("Invalid EDF file: {what}"));
+    let mut header = [0; HEADER_LEN];
+    input.read_exact(&mut header)?;
+    let format = match &header[..8] {
+        b"0       " => EdfFormat::Edf,
+        b"\xffBIOSEMI" => EdfFormat::Bdf,
+        _ => return Err(invalid("unknown version")),
+    };
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim().to_string();
+    let parse = |bytes: &[u8], what: &str| -> Result<f64> {
+        text(bytes).parse::<f64>().map_err(|_| invalid(what))
+    };
+    let records = parse(&header[236..244], "record count")?;
+    let record_duration = parse(&header[244..252], "record duration")?;
+    let signal_count = parse(&header[252..256], "signal count")? as usize;
+    if records < 0.0 {
+        return Err(invalid("unknown record count"));
+    }
+    let header_len = parse(&header[184..192], "header length")? as usize;
+    if !(1..=MAX_SIGNALS).contains(&signal_count) || header_len != HEADER_LEN * (signal_count + 1) {
+        return Err(invalid("wrong signal count"));
+    }
+
+    let mut signal_header = vec![0; HEADER_LEN * signal_count];
+    input.read_exact(&mut signal_header)?;
+    let mut fields = signal_header.as_slice();
+    let mut column = |width: usize| -> Vec<String> {
+        let (bytes, rest) = fields.split_at(width * signal_count);
+        fields = rest;
+        bytes.chunks(width).map(text).collect()
+    };
+    let labels = column(16);
+    let transducers = column(80);
+    let dimensions = column(8);
+    let physical_min = column(8);
+    let physical_max = column(8);
+    let digital_min = column(8);
+    let digital_max = column(8);
+    let _prefiltering = column(80);
+    let samples = column(8);
+    let mut signals = vec![];
+    for i in 0..signal_count {
+        let value = |text: &str, what| text.parse::<f64>().map_err(|_| invalid(what));
+        signals.push(Signal {
+            label: labels[i].clone(),
+            transducer: transducers[i].clone(),
+            dimension: dimensions[i].clone(),
+            physical_min: value(&physical_min[i], "physical minimum")?,
+            physical_max: value(&physical_max[i], "physical maximum")?,
+            digital_min: value(&digital_min[i], "digital minimum")? as i32,
+            digital_max: value(&digital_max[i], "digital maximum")? as i32,
+            samples_per_record: value(&samples[i], "samples per record")? as usize,
+        });
+    }
+
+    let data_signals: Vec<&Signal> = signals.iter().filter(|s| !s.is_annotations()).collect();
+    let samples_per_record = data_signals.first().map_or(0, |s| s.samples_per_record);
+    if data_signals
+        .iter()
+        .any(|s| s.samples_per_record != samples_per_record)
+    {
+        return Err(invalid(
+            "signals with different sample rates are not supported",
+        ));
+    }
+    let rate = samples_per_record as f64 / record_duration;
+    let devices = labels
+        .iter()
+        .filter(|l| l.ends_with("Gyro1"))
+        .count()
+        .max(1);
+    let device_channels = data_signals.len() / devices;
+    let imu_channels = firmware::IMU_CHANNELS as usize;
+    if device_channels <= imu_channels || device_channels * devices != data_signals.len() {
+        return Err(Error::InvalidChannelCount(data_signals.len() as i32));
+    }
+
+    let mut dataset = PsyLinkDataset {
+        layout: protocol::ChannelLayout {
+            emg_channels: device_channels - imu_channels,
+            devices,
+        },
+        ..Default::default()
+    };
+    let bytes_per_sample = format.bytes_per_sample();
+    let record_len: usize =
+        signals.iter().map(|s| s.samples_per_record).sum::<usize>() * bytes_per_sample;
+    if record_len > MAX_RECORD_LEN {
+        return Err(invalid("data records too long"));
+    }
+    let mut record = vec![0; record_len];
+    let mut events: Vec<(f64, Option<f64>, String)> = vec![];
+    for _ in 0..records as usize {
+        input.read_exact(&mut record)?;
+        let mut rows = vec![vec![0; data_signals.len()]; samples_per_record];
+        let mut data = record.as_slice();
+        let mut channel = 0;
+        for signal in &signals {
+            let (bytes, rest) = data.split_at(signal.samples_per_record * bytes_per_sample);
+            data = rest;
+            if signal.is_annotations() {
+                events.extend(parse_annotations(bytes));
+                continue;
+            }
+            for (row, sample) in rows.iter_mut().zip(bytes.chunks(bytes_per_sample)) {
+                // Sign-extend the little-endian 16 or 24 bit integer
+                let mut value = [0; 4];
+                value[..bytes_per_sample].copy_from_slice(sample);
+                let shift = 32 - 8 * bytes_per_sample;
+                row[channel] = signal.to_byte(i32::from_le_bytes(value) << shift >> shift);
+            }
+            channel += 1;
+        }
+        dataset.all_packets.extend(rows);
+    }
+
+    events.retain(|event| !event.2.is_empty());
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let len = dataset.all_packets.len();
+    for (onset, duration, text) in events {
+        let index = (onset * rate).round() as usize;
+        let count = duration.map_or(0, |duration| (duration * rate).round() as usize);
+        // Annotations after the end of the signals don't belong to any packet
+        if index >= len {
+            continue;
+        }
+        if let Some(lost) = text
+            .strip_prefix(GAP_TEXT)
+            .and_then(|rest| rest.trim().strip_prefix('('))
+            .and_then(|rest| rest.strip_suffix("s)"))
+            .and_then(|lost| lost.parse::<f64>().ok())
+        {
+            dataset.gaps.push(Gap {
+                packet_index: index,
+                inserted: count,
+                duration: lost,
+            });
+            continue;
+        }
+        let label = match text.strip_prefix("Label ").map(str::parse::<u8>) {
+            Some(Ok(label)) => label,
+            _ => {
+                if dataset.info.action_names.is_empty() {
+                    dataset.info.action_names.push(Action::None.to_string());
+                }
+                dataset.info.label_for(&text)?
+            }
+        };
+        dataset.datapoints.extend(
+            (index..(index + count.max(1)).min(len)).map(|packet_index| Datapoint {
+                packet_index,
+                label,
+            }),
+        );
+    }
+
+    // Signal that was lost without a replacement still took time
+    let mut skipped = 0.0;
+    let mut gaps = dataset
+        .gaps
+        .iter()
+        .filter(|gap| gap.inserted == 0)
+        .peekable();
+    dataset.timestamps = (0..dataset.all_packets.len())
+        .map(|i| {
+            while let Some(gap) = gaps.next_if(|gap| gap.packet_index <= i) {
+                skipped += gap.duration;
+            }
+            i as f64 / rate + skipped
+        })
+        .collect();
+    Ok(dataset)
+}
+
+pub fn load_edf<P: AsRef<Path>>(path: P) -> Result<PsyLinkDataset> {
+    read_edf(BufReader::new(File::open(path)?))
+}
+
+/// Whether a file starts like an EDF or BDF file
+pub fn is_edf(start: &[u8]) -> bool {
+    start.starts_with(b"0       ") || start.starts_with(b"\xffBIOSEMI")
+}
+
+/// Decodes the time-stamped annotation lists in the annotation signal of one record
+fn parse_annotations(bytes: &[u8]) -> Vec<(f64, Option<f64>, String)> {
+    bytes
+        .split(|byte| *byte == 0)
+        .filter(|tal| !tal.is_empty())
+        .filter_map(|tal| {
+            let mut parts = tal.split(|byte| *byte == ANNOTATION_SEPARATOR);
+            let mut time = parts.next()?.split(|byte| *byte == DURATION_SEPARATOR);
+            let onset = String::from_utf8_lossy(time.next()?).parse::<f64>().ok()?;
+            let duration = time
+                .next()
+                .and_then(|duration| String::from_utf8_lossy(duration).parse::<f64>().ok());
+            let text = parts
+                .map(|text| String::from_utf8_lossy(text).to_string())
+                .find(|text| !text.is_empty())
+                .unwrap_or_default();
+            Some((onset, duration, text))
+        })
+        .collect()
+}
+
+#[test]
+fn test_edf_round_trip() {
+    let mut dataset = PsyLinkDataset::from_arrays(
+        &[(100, 0), (101, 0), (102, 1), (103, 1), (600, 2)],
+        &[[127; 10]; 1000],
+    );
+    for (i, packet) in dataset.all_packets.iter_mut().enumerate() {
+        packet[i % 10] = (i % 256) as u8;
+    }
+    dataset.info.action_names = vec!["(no action)".into(), "fist".into()];
+    dataset.gaps.push(Gap {
+        packet_index: 500,
+        inserted: 0,
+        duration: 0.25,
+    });
+
+    for format in [EdfFormat::Edf, EdfFormat::Bdf] {
+        let mut bytes = vec![];
+        write_edf(&dataset, &mut bytes, format).unwrap();
+        assert!(is_edf(&bytes));
+        // 2 records of 500 samples, which take 1 second each
+        assert_eq!(&bytes[236..252], b"2       1       ");
+
+        let imported = read_edf(bytes.as_slice()).unwrap();
+        assert_eq!(imported.layout, dataset.layout);
+        assert_eq!(imported.all_packets, dataset.all_packets);
+        assert_eq!(imported.datapoints, dataset.datapoints);
+        assert_eq!(imported.gaps, dataset.gaps);
+        assert_eq!(imported.info.action_names, ["(no action)", "fist"]);
+        approx_eq::assert_approx_eq!(imported.timestamps[499], 0.998, 1e-9);
+        approx_eq::assert_approx_eq!(imported.timestamps[500], 1.25, 1e-9);
+
+        // A header that claims more signals than it has
+        let mut broken = bytes.clone();
+        broken[252..256].copy_from_slice(b"9999");
+        assert!(read_edf(broken.as_slice()).is_err());
+    }
+}
+
+#[test]
+fn test_edf_header() {
+    let dataset = PsyLinkDataset::from_arrays(&[], &[[127; 14]; 10]);
+    let mut bytes = vec![];
+    write_edf(&dataset, &mut bytes, EdfFormat::Edf).unwrap();
+    let header = String::from_utf8_lossy(&bytes[..HEADER_LEN * 16]);
+    assert!(header.starts_with("0       X X X X"));
+    assert_eq!(&header[184..192], "4096    ");
+    assert_eq!(&header[192..197], "EDF+C");
+    // Labels, then transducers and so on for all 15 signals
+    assert_eq!(&header[256..272], "EMG1            ");
+    assert_eq!(&header[256 + 14 * 16..256 + 15 * 16], "EDF Annotations ");
+    let dimensions = 256 + 15 * (16 + 80);
+    assert_eq!(&header[dimensions..dimensions + 8], "mV      ");
+    assert_eq!(&header[dimensions + 8 * 8..dimensions + 9 * 8], "deg/s   ");
+    assert_eq!(
+        &header[dimensions + 11 * 8..dimensions + 12 * 8],
+        "g       "
+    );
+    let physical_min = dimensions + 15 * 8;
+    // The firmware maps half the ADC range to 128
+    assert_eq!(&header[physical_min..physical_min + 8], "-1662.99");
+    let physical_max = physical_min + 15 * 8;
+    assert_eq!(&header[physical_max..physical_max + 8], "1650    ");
+    let transducers = 256 + 15 * 16;
+    assert_eq!(
+        &header[transducers + 8 * 80..transducers + 8 * 80 + 13],
+        "IMU gyroscope"
+    );
+
+    assert_eq!(civil_time(0), (1970, 1, 1, 0, 0, 0));
+    assert_eq!(civil_time(1_760_000_000), (2025, 10, 9, 8, 53, 20));
+    assert_eq!(number(0.998312345), "0.998312");
+    assert_eq!(EdfFormat::from_path(Path::new("a.BDF")), EdfFormat::Bdf);
+    assert_eq!(tal(1.5, None, ""), b"+1.5\x14\x14\0");
+    assert_eq!(tal(2.0, Some(0.25), "fist"), b"+2\x150.25\x14fist\x14\0");
+}
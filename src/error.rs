@@ -0,0 +1,188 @@
+// The crate-wide error hierarchy. Every fallible public function in this crate
+// returns error::Result, so callers can match on the failure kind instead of
+// parsing strings.
+
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(DecodeError),
+    Encode(EncodeError),
+    Bluetooth(btleplug::Error),
+    Io(std::io::Error),
+    /// The PsyLink doesn't offer a characteristic that we need
+    MissingCharacteristic(&'static str),
+    /// An operation that requires an established connection was attempted without one
+    NotConnected,
+    /// The connection to the PsyLink was lost
+    Disconnected,
+    /// The operation was aborted because the user asked the application to quit
+    Quit,
+    /// Training, saving or loading the AI calibration model failed
+    Calibration(String),
+    /// The PsyLink reported a channel count that can't be right
+    InvalidChannelCount(i32),
+    /// Data with a certain number of channels was given to something made for another number
+    ChannelMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// A file or a text line doesn't have the expected format
+    Parse(String),
+    /// The signal source has no more data, e.g. the end of a recording was reached
+    EndOfStream,
+    /// A session file was written by a newer version of PsyLink
+    UnsupportedVersion(u16),
+    /// A session has more actions than can be labeled or trained on
+    TooManyActions {
+        found: usize,
+        max: usize,
+    },
+}
+
+/// The reasons why a BLE payload can't be turned into a protocol::Packet
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The payload is too short to contain the tick and the sampling delay byte
+    TruncatedHeader { len: usize },
+    /// The payload has a header, but the gyroscope/accelerometer block is incomplete
+    MissingImu { len: usize },
+    /// The sample bytes after the header can't be split evenly into channels
+    PayloadLength {
+        sample_bytes: usize,
+        channel_count: i32,
+    },
+    /// The tick counter holds a value that the firmware never sends
+    ImpossibleTickJump { last_tick: Option<i32>, tick: i32 },
+}
+
+/// The reasons why protocol::Encoder refuses to build a payload
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncodeError {
+    /// The number of sample vectors differs from the encoder's channel count
+    ChannelCount { expected: i32, found: usize },
+    /// Not all channels have the same number of samples
+    UnequalSampleCounts,
+    /// The decoder would reject this tick, see DecodeError::ImpossibleTickJump
+    InvalidTick(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(err) => write!(f, "Failed to decode packet: {err}"),
+            Error::Encode(err) => write!(f, "Failed to encode packet: {err}"),
+            Error::Bluetooth(err) => write!(f, "Bluetooth error: {err}"),
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::MissingCharacteristic(uuid) => {
+                write!(f, "PsyLink device has no characteristic with UUID {uuid}")
+            }
+            Error::NotConnected => write!(f, "Must load characteristics before reading"),
+            Error::Disconnected => write!(f, "The PsyLink disconnected"),
+            Error::Quit => write!(f, "Received a quit command"),
+            Error::Calibration(msg) => write!(f, "Calibration error: {msg}"),
+            Error::InvalidChannelCount(count) => {
+                write!(
+                    f,
+                    "PsyLink device reported an invalid channel count: {count}"
+                )
+            }
+            Error::ChannelMismatch { expected, found } => {
+                write!(f, "Expected data with {expected} channels, got {found}")
+            }
+            Error::Parse(msg) => write!(f, "Parse error: {msg}"),
+            Error::EndOfStream => write!(f, "The signal source has no more data"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Session file format version {version} is not supported")
+            }
+            Error::TooManyActions { found, max } => {
+                write!(f, "Found {found} actions, but at most {max} are supported")
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedHeader { len } => {
+                write!(f, "payload of {len} bytes has no complete header")
+            }
+            DecodeError::MissingImu { len } => {
+                write!(
+                    f,
+                    "payload of {len} bytes has no complete gyroscope/accelerometer block"
+                )
+            }
+            DecodeError::PayloadLength {
+                sample_bytes,
+                channel_count,
+            } => write!(
+                f,
+                "{sample_bytes} sample bytes can't be split into {channel_count} channels"
+            ),
+            DecodeError::ImpossibleTickJump { last_tick, tick } => match last_tick {
+                Some(last_tick) => write!(f, "impossible tick jump from {last_tick} to {tick}"),
+                None => write!(f, "impossible tick {tick}"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::ChannelCount { expected, found } => {
+                write!(f, "expected {expected} channels, got {found}")
+            }
+            EncodeError::UnequalSampleCounts => {
+                write!(f, "all channels must have the same number of samples")
+            }
+            EncodeError::InvalidTick(tick) => {
+                write!(f, "tick {tick} is never sent by the firmware")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(err) => Some(err),
+            Error::Encode(err) => Some(err),
+            Error::Bluetooth(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl std::error::Error for EncodeError {}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<EncodeError> for Error {
+    fn from(err: EncodeError) -> Self {
+        Error::Encode(err)
+    }
+}
+
+impl From<btleplug::Error> for Error {
+    fn from(err: btleplug::Error) -> Self {
+        Error::Bluetooth(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -3,6 +3,7 @@ use enigo::{
     Direction::{Click, Press, Release},
     Enigo, Key, Keyboard, Settings,
 };
+use std::fmt;
 
 const DEBOUNCE_THRESHOLD: u32 = 2;
 
@@ -13,12 +14,12 @@ pub enum Action {
     None,
 }
 
-impl Action {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Action::Key(key) => format!("Key \"{key}\"").to_string(),
-            Action::Sound(_) => String::from("Sound"),
-            Action::None => String::from("(no action)"),
+            Action::Key(key) => write!(f, "Key \"{key}\""),
+            Action::Sound(_) => write!(f, "Sound"),
+            Action::None => write!(f, "(no action)"),
         }
     }
 }
@@ -37,17 +38,18 @@ pub struct InputState {
 
 impl InputState {
     pub fn new(verbose: bool) -> Self {
-        let mut obj = Self::default();
-        obj.verbose = verbose;
-        obj.actions = vec![
-            Action::None,
-            Action::Key('w'),
-            Action::Key('a'),
-            Action::Key('d'),
-            Action::Key('s'),
-        ];
-        obj.tap = vec![false, false, false, false, false];
-        obj
+        Self {
+            verbose,
+            actions: vec![
+                Action::None,
+                Action::Key('w'),
+                Action::Key('a'),
+                Action::Key('d'),
+                Action::Key('s'),
+            ],
+            tap: vec![false, false, false, false, false],
+            ..Self::default()
+        }
     }
 
     pub fn reset(&mut self) {
@@ -168,21 +170,17 @@ impl Default for AbstractionLayer {
 
 impl AbstractionLayer {
     pub fn press(&mut self, key: char, tap: bool) {
-        if self.enigo.is_some() {
+        if let Some(enigo) = self.enigo.as_mut() {
             let activity = if tap { Click } else { Press };
-            self.enigo
-                .as_mut()
-                .unwrap()
+            enigo
                 .key(Key::Unicode(key), activity)
                 .expect("Key press failed");
         }
     }
 
     pub fn release(&mut self, key: char) {
-        if self.enigo.is_some() {
-            self.enigo
-                .as_mut()
-                .unwrap()
+        if let Some(enigo) = self.enigo.as_mut() {
+            enigo
                 .key(Key::Unicode(key), Release)
                 .expect("Key press failed");
         }
@@ -0,0 +1,367 @@
+// The classic time-domain features of EMG signals, computed per channel over a window of
+// samples.  The graph can show them as an overlay, and a model can take them as input
+// instead of the raw samples.
+//
+// The classical classifiers of the classifier module take the features of a whole window.
+//
+// The features work on samples that are centered and scaled like in the graph, so
+// that the middle of the ADC range is 0.0 and the ends are about -1.0 and 1.0:
+//
+//   rms         root mean square
+//   mav         mean absolute value
+//   wl          waveform length, the summed absolute differences between samples,
+//               divided by the window length so it doesn't depend on it
+//   zc          zero crossings per sample, ignoring crossings smaller than NOISE_THRESHOLD
+//   ssc         slope sign changes per sample, with the same threshold
+//   activity    Hjorth activity, the variance
+//   mobility    Hjorth mobility, sqrt(var(x') / var(x))
+//   complexity  Hjorth complexity, mobility(x') / mobility(x)
+//   envelope    the rectified signal, smoothed over ENVELOPE_SPAN samples, at the end of
+//               the window
+//
+// As model input, the window of a training sample is split into INPUT_FRAMES frames of
+// equal length.  Each frame becomes one row with the features of every EMG channel,
+// followed by the mean of every IMU channel, so the model still sees how the features
+// change over time.  The input is written as "raw" for the samples themselves, or as
+// "features" followed by the features to use, e.g. "features rms wl zc ssc", where a
+// plain "features" stands for all of them.
+
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+const NOISE_THRESHOLD: f64 = 0.01; // About one step of the ADC
+const ENVELOPE_SPAN: f64 = 25.0; // Samples, the time constant of the envelope
+pub const INPUT_FRAMES: usize = 10; // Rows of a feature matrix per training sample
+/// The features that the classical classifiers use unless told otherwise
+pub const DEFAULT_FEATURES: [Feature; 5] = [
+    Feature::Rms,
+    Feature::MeanAbsoluteValue,
+    Feature::WaveformLength,
+    Feature::ZeroCrossings,
+    Feature::SlopeSignChanges,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Rms,
+    MeanAbsoluteValue,
+    WaveformLength,
+    ZeroCrossings,
+    SlopeSignChanges,
+    HjorthActivity,
+    HjorthMobility,
+    HjorthComplexity,
+    Envelope,
+}
+
+/// What a model takes as input
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Input {
+    #[default]
+    Raw,
+    Features(Vec<Feature>),
+}
+
+impl Feature {
+    pub const ALL: [Feature; 9] = [
+        Feature::Rms,
+        Feature::MeanAbsoluteValue,
+        Feature::WaveformLength,
+        Feature::ZeroCrossings,
+        Feature::SlopeSignChanges,
+        Feature::HjorthActivity,
+        Feature::HjorthMobility,
+        Feature::HjorthComplexity,
+        Feature::Envelope,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Rms => "rms",
+            Feature::MeanAbsoluteValue => "mav",
+            Feature::WaveformLength => "wl",
+            Feature::ZeroCrossings => "zc",
+            Feature::SlopeSignChanges => "ssc",
+            Feature::HjorthActivity => "activity",
+            Feature::HjorthMobility => "mobility",
+            Feature::HjorthComplexity => "complexity",
+            Feature::Envelope => "envelope",
+        }
+    }
+
+    /// Computes the feature over a window of a centered and scaled signal
+    pub fn compute(&self, signal: &[f64]) -> f64 {
+        if signal.is_empty() {
+            return 0.0;
+        }
+        let len = signal.len() as f64;
+        match self {
+            Feature::Rms => (signal.iter().map(|x| x * x).sum::<f64>() / len).sqrt(),
+            Feature::MeanAbsoluteValue => signal.iter().map(|x| x.abs()).sum::<f64>() / len,
+            Feature::WaveformLength => {
+                let length: f64 = signal
+                    .windows(2)
+                    .map(|pair| (pair[1] - pair[0]).abs())
+                    .sum();
+                length / len
+            }
+            Feature::ZeroCrossings => {
+                let crossings = signal
+                    .windows(2)
+                    .filter(|pair| {
+                        pair[0] * pair[1] < 0.0 && (pair[0] - pair[1]).abs() >= NOISE_THRESHOLD
+                    })
+                    .count();
+                crossings as f64 / len
+            }
+            Feature::SlopeSignChanges => {
+                let changes = signal
+                    .windows(3)
+                    .filter(|triple| {
+                        let (before, after) = (triple[1] - triple[0], triple[1] - triple[2]);
+                        before * after > 0.0
+                            && (before.abs() >= NOISE_THRESHOLD || after.abs() >= NOISE_THRESHOLD)
+                    })
+                    .count();
+                changes as f64 / len
+            }
+            Feature::HjorthActivity => variance(signal),
+            Feature::HjorthMobility => mobility(signal),
+            Feature::HjorthComplexity => {
+                let mobility_x = mobility(signal);
+                match mobility_x > 0.0 {
+                    true => mobility(&derivative(signal)) / mobility_x,
+                    false => 0.0,
+                }
+            }
+            Feature::Envelope => envelope(signal).last().copied().unwrap_or(0.0),
+        }
+    }
+
+    /// The feature over the trailing window at every step-th sample of a signal, as pairs
+    /// of the index of the last sample in the window and the value, for plotting
+    pub fn trailing(&self, signal: &[f64], window: usize, step: usize) -> Vec<(usize, f64)> {
+        if *self == Feature::Envelope {
+            // The envelope is causal anyway, no need to start over for every window
+            return envelope(signal)
+                .into_iter()
+                .enumerate()
+                .step_by(step.max(1))
+                .collect();
+        }
+        (0..signal.len())
+            .step_by(step.max(1))
+            .map(|end| {
+                let start = (end + 1).saturating_sub(window);
+                (end, self.compute(&signal[start..=end]))
+            })
+            .collect()
+    }
+}
+
+fn mean(signal: &[f64]) -> f64 {
+    signal.iter().sum::<f64>() / signal.len().max(1) as f64
+}
+
+fn variance(signal: &[f64]) -> f64 {
+    let mean = mean(signal);
+    signal.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / signal.len().max(1) as f64
+}
+
+fn derivative(signal: &[f64]) -> Vec<f64> {
+    signal.windows(2).map(|pair| pair[1] - pair[0]).collect()
+}
+
+fn mobility(signal: &[f64]) -> f64 {
+    let variance_x = variance(signal);
+    match variance_x > 0.0 {
+        true => (variance(&derivative(signal)) / variance_x).sqrt(),
+        false => 0.0,
+    }
+}
+
+/// An exponential moving average of the rectified signal
+pub fn envelope(signal: &[f64]) -> Vec<f64> {
+    let mut level = 0.0;
+    signal
+        .iter()
+        .map(|x| {
+            level += (x.abs() - level) / ENVELOPE_SPAN;
+            level
+        })
+        .collect()
+}
+
+/// Centers and scales a sample byte like the graph does
+pub fn normalize(sample: u8) -> f64 {
+    (sample as f64 - protocol::SAMPLE_CENTER) / protocol::SAMPLE_CENTER
+}
+
+impl Input {
+    /// The shape [rows, columns] of the model input for a window of the given length
+    pub fn dims(&self, layout: protocol::ChannelLayout, window: usize) -> [usize; 2] {
+        match self {
+            Input::Raw => [window, layout.total_channels()],
+            Input::Features(features) => {
+                let emg_channels = layout.emg_channels * layout.devices;
+                let imu_channels = layout.total_channels() - emg_channels;
+                [INPUT_FRAMES, emg_channels * features.len() + imu_channels]
+            }
+        }
+    }
+
+    /// Turns a window of packets into the model input, as rows of the shape from dims()
+    pub fn matrix(&self, window: &[Vec<u8>], layout: protocol::ChannelLayout) -> Vec<f32> {
+        let features = match self {
+            // The raw samples as they are, like the first models were trained on them
+            Input::Raw => return window.concat().into_iter().map(f32::from).collect(),
+            Input::Features(features) => features,
+        };
+        // Windows shorter than INPUT_FRAMES packets share packets between the frames, so
+        // that there are always INPUT_FRAMES rows
+        let len = window.len();
+        (0..INPUT_FRAMES)
+            .flat_map(|frame| {
+                let start = frame * len / INPUT_FRAMES;
+                let end = ((frame + 1) * len / INPUT_FRAMES).max(start + 1).min(len);
+                window_features(features, &window[start..end], layout)
+            })
+            .map(|value| value as f32)
+            .collect()
+    }
+}
+
+/// The given features of every EMG channel over a window of packets, followed by the
+/// mean of every IMU channel, in the order of the channels
+pub fn window_features(
+    features: &[Feature],
+    window: &[Vec<u8>],
+    layout: protocol::ChannelLayout,
+) -> Vec<f64> {
+    let mut vector = vec![];
+    for channel in 0..layout.total_channels() {
+        let signal: Vec<f64> = window.iter().map(|row| normalize(row[channel])).collect();
+        if layout.is_emg(channel) {
+            vector.extend(features.iter().map(|feature| feature.compute(&signal)));
+        } else {
+            vector.push(mean(&signal));
+        }
+    }
+    vector
+}
+
+impl FromStr for Feature {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Feature::ALL
+            .into_iter()
+            .find(|feature| feature.name() == text.trim())
+            .ok_or_else(|| {
+                let names: Vec<&str> = Feature::ALL.iter().map(Feature::name).collect();
+                Error::Parse(format!(
+                    "Unknown feature \"{text}\", expected one of {}",
+                    names.join(", ")
+                ))
+            })
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Input {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let words: Vec<&str> = text.split([' ', ',']).filter(|w| !w.is_empty()).collect();
+        match words.as_slice() {
+            [] | ["raw"] => Ok(Input::Raw),
+            ["features"] => Ok(Input::Features(Feature::ALL.to_vec())),
+            ["features", names @ ..] => {
+                let features = names
+                    .iter()
+                    .map(|name| name.parse())
+                    .collect::<Result<Vec<Feature>>>()?;
+                Ok(Input::Features(features))
+            }
+            _ => Err(Error::Parse(format!(
+                "Invalid classifier input \"{text}\", expected raw or features [NAMES]"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Raw => write!(f, "raw"),
+            Input::Features(features) => {
+                let names: Vec<&str> = features.iter().map(Feature::name).collect();
+                write!(f, "features {}", names.join(" "))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_features() {
+    use std::f64::consts::PI;
+    use Feature::*;
+    let sine: Vec<f64> = (0..1000)
+        .map(|i| 0.5 * (2.0 * PI * 50.0 * i as f64 / 1000.0 + 0.1).sin())
+        .collect();
+    let approx = |feature: Feature, expected: f64| {
+        let value = feature.compute(&sine);
+        assert!((value - expected).abs() < 5e-3, "{feature}: {value}");
+    };
+    approx(Rms, 0.5 / 2f64.sqrt());
+    approx(MeanAbsoluteValue, 1.0 / PI);
+    approx(WaveformLength, 50.0 * 4.0 * 0.5 / 1000.0);
+    approx(ZeroCrossings, 99.0 / 1000.0);
+    approx(SlopeSignChanges, 100.0 / 1000.0);
+    approx(HjorthActivity, 0.125);
+    // The mobility of a sampled sine is 2 sin(w/2) for the angular frequency w per sample
+    approx(HjorthMobility, 2.0 * (PI * 50.0 / 1000.0).sin());
+    approx(HjorthComplexity, 1.0);
+    assert!((Envelope.compute(&sine) - 1.0 / PI).abs() < 0.05);
+    for feature in Feature::ALL {
+        assert_eq!(feature.compute(&[0.0; 100]), 0.0, "{feature}");
+        assert_eq!(feature.to_string().parse::<Feature>().unwrap(), feature);
+    }
+    let overlay = Rms.trailing(&sine, 100, 10);
+    assert_eq!(overlay.len(), 100);
+    assert_eq!(overlay[99].0, 990);
+    assert!((overlay[99].1 - 0.5 / 2f64.sqrt()).abs() < 1e-3);
+    assert_eq!(Envelope.trailing(&sine, 100, 0).len(), sine.len());
+
+    // As model input
+    let layout = protocol::ChannelLayout::new(8);
+    let input: Input = "features rms, wl zc".parse().unwrap();
+    assert_eq!(input.to_string(), "features rms wl zc");
+    assert_eq!("".parse::<Input>().unwrap(), Input::Raw);
+    assert!("features rms power".parse::<Input>().is_err());
+    assert!("cooked".parse::<Input>().is_err());
+    assert_eq!(input.dims(layout, 250), [INPUT_FRAMES, 8 * 3 + 6]);
+    let window: Vec<Vec<u8>> = (0..250)
+        .map(|i| {
+            (0..14)
+                .map(|c| if i % 2 == 0 { 127 + c } else { 127 })
+                .collect()
+        })
+        .collect();
+    let matrix = input.matrix(&window, layout);
+    assert_eq!(matrix.len(), INPUT_FRAMES * (8 * 3 + 6));
+    assert_eq!(matrix[0], 0.0); // Channel 0 stays at the center
+    assert!(matrix[3] > 0.0); // The RMS of channel 1
+    assert_eq!(Input::Raw.matrix(&window, layout).len(), 250 * 14);
+    for len in [0, 3, 19] {
+        let matrix = input.matrix(&window[..len], layout);
+        assert_eq!(matrix.len(), INPUT_FRAMES * (8 * 3 + 6), "{len}");
+    }
+}
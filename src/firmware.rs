@@ -3,9 +3,27 @@
 
 // Keep these in sync with arduino code.
 
+pub const SENSOR_SERVICE_UUID: &str = "0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e4";
 pub const SENSOR_CHARACTERISTICS_UUID: &str = "0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e5";
 pub const CHANNEL_COUNT_CHARACTERISTICS_UUID: &str = "0a3d3fd8-2f1c-46fd-bf46-eaef2fda91e6";
 
 pub const SAMPLE_DELAY_PARAM_A: f64 = -11.3384217;
 pub const SAMPLE_DELAY_PARAM_B: f64 = 1.93093431;
 pub const PROTOCOL_HEADER_LEN: i32 = 8;
+pub const IMU_CHANNELS: i32 = 6; // gyroscope (x,y,z) and accelerometer (x,y,z) in the header
+pub const DEFAULT_CHANNEL_COUNT: i32 = 8; // the CHANNELS of the reference design
+pub const SAMPLE_RATE: f64 = 500.0; // samples per second
+pub const BLE_NOTIFY_RATE: f64 = 20.0; // updates per second
+
+// How the firmware maps measurements to bytes.  EMG: the 12-bit readings of the analog
+// pins, 0 to ADC_VOLTAGE volts, become the bytes 1 to 255.  Gyroscope: degrees per second
+// plus 127.  Accelerometer: 128 times the acceleration in g plus 127.
+pub const ADC_VOLTAGE: f64 = 3.3;
+pub const ADC_READINGS: f64 = 4096.0; // map(reading, 0, ADC_READINGS, 1, 255)
+pub const EMG_VOLTS_PER_STEP: f64 = ADC_VOLTAGE / 254.0;
+pub const EMG_ZERO_BYTE: f64 = 1.0 + 254.0 * (ADC_READINGS / 2.0) / ADC_READINGS; // Half the ADC range
+pub const GYRO_DEGREES_PER_STEP: f64 = 1.0;
+pub const ACCEL_G_PER_STEP: f64 = 1.0 / 128.0;
+
+// This delay byte is sent instead of the compressed delays when SEND_METRICS is false.
+pub const NO_METRICS_DELAY_BYTE: u8 = 0xff;
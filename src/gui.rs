@@ -4,15 +4,18 @@ use plotters::prelude::*;
 use slint::SharedPixelBuffer;
 use std::collections::{HashSet, VecDeque};
 use std::io::Write;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 slint::include_modules!();
 
 const MAX_POINTS: usize = 2000;
-const EMG_CHANNELS: i32 = 8;
-const TOTAL_CHANNELS: usize = 14;
+const OVERLAY_WINDOW: usize = 100; // How many samples a feature of the graph overlay spans
+const OVERLAY_STEP: usize = 10; // Draw the overlay at every n-th sample
 const DEFAULT_ACTION_TIME: f64 = 5.0;
 const DEFAULT_REPETITIONS: usize = 2;
+const MAX_ACTIONS: usize = 4; // As many as the action selection offers
+const PREDICTION_LOG_PATH: &str = "/tmp/psylink_predictions.csv";
 
 const BG_COLOR: RGBColor = RGBColor(0x1c, 0x1c, 0x1c);
 const GRAPH_EMG1_5: RGBColor = RGBColor(0xdc, 0x32, 0x2f);
@@ -22,6 +25,8 @@ const GRAPH_EMG4_8: RGBColor = RGBColor(0xfc, 0xe3, 0x88);
 const GRAPH_GYRO1: RGBColor = RGBColor(0xff, 0xff, 0xff);
 const GRAPH_GYRO2: RGBColor = RGBColor(0xc6, 0x88, 0xfc);
 const GRAPH_GYRO3: RGBColor = RGBColor(0x88, 0x88, 0x88);
+const GRAPH_OVERLAY: RGBColor = RGBColor(0x26, 0x8b, 0xd2);
+const SPECTRUM_RANGE: f64 = 80.0; // Decibels between the strongest and the weakest power shown
 
 pub async fn start(app: App) {
     let state = GUIState::new();
@@ -33,6 +38,8 @@ pub async fn start(app: App) {
     ui.set_train_epochs(slint::SharedString::from(state.train_epochs.to_string()));
     ui.set_calib_repetitions(slint::SharedString::from(DEFAULT_REPETITIONS.to_string()));
     ui.set_calib_action_time(slint::SharedString::from(DEFAULT_ACTION_TIME.to_string()));
+    ui.set_source(slint::SharedString::from(app.source.to_string()));
+    ui.set_backend(slint::SharedString::from(app.backend.to_string()));
 
     // Naming convention:
     // orig_mutex_ABC = original Arc<Mutex<...>> struct
@@ -40,11 +47,14 @@ pub async fn start(app: App) {
     // ABC = cloned_ABC.lock().unwrap() inside thread, when ABC needs to be read/written
     let orig_mutex_calib = Arc::new(Mutex::new(calibration::CalibController::default()));
     let orig_mutex_flow = Arc::new(Mutex::new(CalibrationFlow::default()));
-    let orig_mutex_settings = Arc::new(Mutex::new(GUISettings::new()));
-    let orig_mutex_model = Arc::new(Mutex::new(None::<calibration::DefaultModel>));
+    let orig_mutex_settings = Arc::new(Mutex::new(GUISettings::new(
+        app.source.clone(),
+        app.backend,
+    )));
+    let orig_mutex_model = Arc::new(Mutex::new(None::<Box<dyn classifier::Classifier>>));
     let orig_mutex_commands = Arc::new(Mutex::new(GUICommands::default()));
     let orig_mutex_state = Arc::new(Mutex::new(state));
-    let orig_mutex_plotter = Arc::new(Mutex::new(Plotter::new(TOTAL_CHANNELS)));
+    let orig_mutex_plotter = Arc::new(Mutex::new(Plotter::new(protocol::ChannelLayout::default())));
     let orig_mutex_quit = Arc::new(Mutex::new(false));
     let orig_mutex_fakeinput = Arc::new(Mutex::new(fakeinput::InputState::new(app.verbose > 0)));
 
@@ -91,7 +101,7 @@ pub async fn start(app: App) {
         mutex_calib.lock().unwrap().reset();
         let _ = ui_weak.upgrade_in_event_loop(move |ui| {
             ui.set_calibrating(true);
-            ui.set_text_calibration_instruction(format!("Attempting to calibrate...").into());
+            ui.set_text_calibration_instruction("Attempting to calibrate...".into());
         });
     });
 
@@ -101,7 +111,7 @@ pub async fn start(app: App) {
         mutex_flow.lock().unwrap().stop();
         let _ = ui_weak.upgrade_in_event_loop(move |ui| {
             ui.set_calibrating(false);
-            ui.set_text_calibration_instruction(format!("No calibration in progress.").into());
+            ui.set_text_calibration_instruction("No calibration in progress.".into());
         });
     });
 
@@ -175,6 +185,120 @@ pub async fn start(app: App) {
                 .log(format!("repetitions = {parsed}."));
         });
 
+    let mutex_settings = orig_mutex_settings.clone();
+    ui.global::<Logic>()
+        .on_set_option_gap_policy(move |value: slint::SharedString| {
+            let gap_policy = match value.as_str() {
+                "Leave gap" => protocol::GapPolicy::Leave,
+                "Sentinel" => protocol::GapPolicy::Sentinel,
+                "Repeat last" => protocol::GapPolicy::RepeatLast,
+                _ => protocol::GapPolicy::Interpolate,
+            };
+            mutex_settings.lock().unwrap().gap_policy = gap_policy;
+        });
+
+    let mutex_state = orig_mutex_state.clone();
+    ui.global::<Logic>()
+        .on_set_option_exclude_gaps(move |checked: bool| {
+            mutex_state.lock().unwrap().train_exclude_gaps = checked;
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_state = orig_mutex_state.clone();
+    ui.global::<Logic>()
+        .on_connect_handler(move |value: slint::SharedString| {
+            match value.parse::<transport::SourceList>() {
+                Ok(source) => {
+                    let mut settings = mutex_settings.lock().unwrap();
+                    settings.source = source;
+                    settings.connect_requested = true;
+                }
+                Err(err) => mutex_state.lock().unwrap().log(err),
+            }
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_state = orig_mutex_state.clone();
+    let mutex_plotter = orig_mutex_plotter.clone();
+    ui.global::<Logic>()
+        .on_set_option_display_filter(move |value: slint::SharedString| {
+            match value.parse::<dsp::FilterChain>() {
+                Ok(filter) => {
+                    mutex_plotter.lock().unwrap().set_filter(&filter);
+                    mutex_settings.lock().unwrap().display_filter = filter;
+                }
+                Err(err) => mutex_state.lock().unwrap().log(err.to_string()),
+            }
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_state = orig_mutex_state.clone();
+    ui.global::<Logic>()
+        .on_set_option_classifier_filter(move |value: slint::SharedString| match value
+            .parse::<dsp::FilterChain>(
+        ) {
+            Ok(filter) => mutex_settings.lock().unwrap().classifier_filter = filter,
+            Err(err) => mutex_state.lock().unwrap().log(err.to_string()),
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_plotter = orig_mutex_plotter.clone();
+    ui.global::<Logic>()
+        .on_set_option_graph_overlay(move |value: slint::SharedString| {
+            let overlay = value.parse::<features::Feature>().ok();
+            mutex_plotter.lock().unwrap().overlay = overlay;
+            mutex_settings.lock().unwrap().graph_overlay = overlay;
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    ui.global::<Logic>()
+        .on_set_option_classifier(move |value: slint::SharedString| {
+            let kind = match value.as_str() {
+                "LDA" => classifier::ClassifierKind::Lda,
+                "Linear SVM" => classifier::ClassifierKind::Svm,
+                "k-NN" => classifier::ClassifierKind::Knn,
+                "Random forest" => classifier::ClassifierKind::Forest,
+                _ => classifier::ClassifierKind::NeuralNetwork,
+            };
+            mutex_settings.lock().unwrap().classifier = kind;
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_state = orig_mutex_state.clone();
+    ui.global::<Logic>()
+        .on_set_option_backend(move |value: slint::SharedString| {
+            match value.parse::<calibration::BackendKind>() {
+                Ok(backend) if !backend.is_available() => mutex_state.lock().unwrap().log(format!(
+                    "This build of PsyLink doesn't include the {backend} backend"
+                )),
+                Ok(backend) => mutex_settings.lock().unwrap().backend = backend,
+                Err(err) => mutex_state.lock().unwrap().log(err.to_string()),
+            }
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_plotter = orig_mutex_plotter.clone();
+    ui.global::<Logic>()
+        .on_set_option_graph_view(move |value: slint::SharedString| {
+            let view = match value.as_str() {
+                "Spectrum" => GraphView::Spectrum,
+                "Spectrogram" => GraphView::Spectrogram,
+                _ => GraphView::Signal,
+            };
+            mutex_plotter.lock().unwrap().view = view;
+            mutex_settings.lock().unwrap().graph_view = view;
+        });
+
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_state = orig_mutex_state.clone();
+    ui.global::<Logic>()
+        .on_set_option_classifier_input(move |value: slint::SharedString| {
+            match value.parse::<features::Input>() {
+                Ok(input) => mutex_settings.lock().unwrap().classifier_input = input,
+                Err(err) => mutex_state.lock().unwrap().log(err.to_string()),
+            }
+        });
+
     let mutex_settings = orig_mutex_settings.clone();
     ui.global::<Logic>()
         .on_set_option_accelerometer(move |checked: bool| {
@@ -223,21 +347,75 @@ pub async fn start(app: App) {
         //ui.set_text_calibration_instruction("Training... (See console for status)".into());
         //});
 
-        let (epochs, max_datapoints) = if let Ok(state) = mutex_state.lock() {
-            (state.train_epochs, state.train_max_datapoints)
+        let (epochs, max_datapoints, exclude_gaps) = if let Ok(state) = mutex_state.lock() {
+            (
+                state.train_epochs,
+                state.train_max_datapoints,
+                state.train_exclude_gaps,
+            )
         } else {
             (
                 calibration::DEFAULT_EPOCHS,
                 calibration::DEFAULT_MAX_DATAPOINTS,
+                true,
             )
         };
-        let calib = mutex_calib.lock().unwrap();
-        let action_count = mutex_settings.lock().unwrap().action_count;
-        let result = calib.train(action_count, epochs, max_datapoints);
-        dbg!(&result);
+        let mut calib = mutex_calib.lock().unwrap();
+        let (action_count, filter, input, kind, backend) = {
+            let settings = mutex_settings.lock().unwrap();
+            (
+                settings.action_count,
+                settings.classifier_filter.clone(),
+                settings.classifier_input.clone(),
+                settings.classifier,
+                settings.backend,
+            )
+        };
+        calib.backend = backend;
+        let result: error::Result<Box<dyn classifier::Classifier>> = match kind {
+            classifier::ClassifierKind::NeuralNetwork => calib.train(
+                action_count,
+                epochs,
+                max_datapoints,
+                exclude_gaps,
+                &filter,
+                &input,
+            ),
+            _ => {
+                // The classical classifiers take the features of the classifier input
+                let features = match &input {
+                    features::Input::Features(features) => features.clone(),
+                    features::Input::Raw => features::DEFAULT_FEATURES.to_vec(),
+                };
+                classifier::train(
+                    &calib.dataset,
+                    kind,
+                    &filter,
+                    &features,
+                    max_datapoints,
+                    exclude_gaps,
+                )
+                .map(|trained| {
+                    // Like the neural network, keep it for "Load model"
+                    if let Err(err) = calibration::save_classifier(&trained) {
+                        mutex_state
+                            .lock()
+                            .unwrap()
+                            .log(format!("Failed to save the classifier: {err}"));
+                    }
+                    Box::new(trained) as Box<dyn classifier::Classifier>
+                })
+            }
+        };
+        if let Err(err) = &result {
+            mutex_state
+                .lock()
+                .unwrap()
+                .log(format!("Training failed: {err}"));
+        }
         if let Ok(trained_model) = result {
             let mut model = mutex_model.lock().unwrap();
-            let model_log = format!("{:?}", &trained_model);
+            let model_log = trained_model.describe();
             *model = Some(trained_model);
             let _ = ui_weak.upgrade_in_event_loop(move |ui| {
                 ui.set_training(false);
@@ -246,7 +424,7 @@ pub async fn start(app: App) {
             });
             if let Ok(mut state) = mutex_state.lock() {
                 state.log("Finished training AI calibration model.".into());
-                state.log(format!("Training result: {model_log}").into());
+                state.log(format!("Training result: {model_log}"));
                 state.trained = true;
                 state.update_statusbar = true;
             }
@@ -264,15 +442,35 @@ pub async fn start(app: App) {
 
     let mutex_calib = orig_mutex_calib.clone();
     let mutex_state = orig_mutex_state.clone();
+    let mutex_settings = orig_mutex_settings.clone();
+    let mutex_fakeinput = orig_mutex_fakeinput.clone();
     ui.global::<Logic>().on_save_dataset_handler(move || {
-        let path = "/tmp/psylink_dataset.rs";
-        let calib = mutex_calib.lock().unwrap();
-        let mut output = std::fs::File::create(path).unwrap();
-        let _ = write!(output, "{}", calib.dataset.to_string());
-        mutex_state
+        let path = "/tmp/psylink_dataset.psylink";
+        let action_count = mutex_settings.lock().unwrap().action_count;
+        let action_names: Vec<String> = mutex_fakeinput
             .lock()
             .unwrap()
-            .log(format!("Saved dataset to {path}."));
+            .actions
+            .iter()
+            .take(action_count + 1)
+            .map(|action| action.to_string())
+            .collect();
+        let saved = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64());
+        let mut calib = mutex_calib.lock().unwrap();
+        let info = &mut calib.dataset.info;
+        // Loaded sessions may have named their actions already
+        let named = info.action_names.len();
+        info.action_names
+            .extend(action_names.into_iter().skip(named));
+        info.set_metadata("software", format!("psylink {}", env!("CARGO_PKG_VERSION")));
+        info.set_metadata("saved", format!("{saved:.3}"));
+        let msg = match calib.dataset.save(path) {
+            Ok(()) => format!("Saved dataset to {path}."),
+            Err(err) => format!("Failed to save dataset to {path}: {err}"),
+        };
+        mutex_state.lock().unwrap().log(msg);
     });
 
     let mutex_state = orig_mutex_state.clone();
@@ -285,18 +483,97 @@ pub async fn start(app: App) {
         }
     });
 
+    let ui_weak = ui.as_weak();
     let mutex_calib = orig_mutex_calib.clone();
     let mutex_state = orig_mutex_state.clone();
     let mutex_settings = orig_mutex_settings.clone();
-    ui.global::<Logic>().on_load_dataset_handler(move || {
-        mutex_settings.lock().unwrap().action_count = 3;
-        if let Ok(mut state) = mutex_state.lock() {
+    ui.global::<Logic>()
+        .on_load_dataset_handler(move |path: slint::SharedString, append: bool| {
+            let (dataset, name) = match path.trim() {
+                "" => (
+                    Ok(PsyLinkDataset::from_arrays(
+                        &TEST_DATASET.0,
+                        &TEST_DATASET.1,
+                    )),
+                    "the test dataset".to_string(),
+                ),
+                path => (session::load_dataset(path), path.to_string()),
+            };
+            let mut calib = mutex_calib.lock().unwrap();
+            let combined = dataset.and_then(|dataset| {
+                let dataset = match append {
+                    true => {
+                        let mut combined = calib.dataset.clone();
+                        combined.append(dataset)?;
+                        combined
+                    }
+                    false => dataset,
+                };
+                // The labels must fit into the classes of the model that we train
+                match dataset.action_count() {
+                    found if found > MAX_ACTIONS => Err(error::Error::TooManyActions {
+                        found,
+                        max: MAX_ACTIONS,
+                    }),
+                    _ => Ok(dataset),
+                }
+            });
+            let mut state = mutex_state.lock().unwrap();
+            match combined {
+                Ok(dataset) => calib.set_dataset(dataset),
+                Err(err) => {
+                    state.log(format!("Failed to load {name}: {err}"));
+                    return;
+                }
+            }
+
+            let action_count = calib.dataset.action_count().max(1);
+            mutex_settings.lock().unwrap().action_count = action_count;
+            state.log(format!(
+                "Loaded {name}, now {} packets and {} datapoints.",
+                calib.dataset.all_packets.len(),
+                calib.dataset.datapoints.len()
+            ));
+            if !calib.dataset.info.action_names.is_empty() {
+                state.log(format!(
+                    "Actions: {}.",
+                    calib.dataset.info.action_names.join(", ")
+                ));
+            }
             state.update_statusbar = true;
             state.update_action_count = true;
-        }
-        mutex_calib.lock().unwrap().dataset =
-            PsyLinkDataset::from_arrays(&TEST_DATASET.0, &TEST_DATASET.1);
-    });
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                let text = match action_count {
+                    1 => "1 action".to_string(),
+                    count => format!("{count} actions"),
+                };
+                ui.set_combobox_action_count(text.into());
+            });
+        });
+
+    let ui_weak = ui.as_weak();
+    let mutex_state = orig_mutex_state.clone();
+    let mutex_settings = orig_mutex_settings.clone();
+    ui.global::<Logic>()
+        .on_replay_dataset_handler(move |path: slint::SharedString| {
+            let mut state = mutex_state.lock().unwrap();
+            if path.trim().is_empty() {
+                state.log("Enter the path of a recording to replay it.".into());
+                return;
+            }
+            // The connection thread notices the request and switches over to the recording
+            match replay::sources(&replay::ReplayOptions::new(path.trim())) {
+                Ok(source) => {
+                    state.log(format!("Replaying {}.", path.trim()));
+                    let text = source.to_string();
+                    let mut settings = mutex_settings.lock().unwrap();
+                    settings.source = source;
+                    settings.connect_requested = true;
+                    let _ = ui_weak.upgrade_in_event_loop(move |ui| ui.set_source(text.into()));
+                }
+                Err(err) => state.log(format!("Failed to replay {}: {err}", path.trim())),
+            }
+        });
 
     let ui_weak = ui.as_weak();
     let mutex_model = orig_mutex_model.clone();
@@ -304,16 +581,44 @@ pub async fn start(app: App) {
     let mutex_settings = orig_mutex_settings.clone();
     ui.global::<Logic>().on_load_model_handler(move || {
         let mut model = mutex_model.lock().unwrap();
+        let backend = mutex_settings.lock().unwrap().backend;
+        // The latest trained model, or the test model if nothing was trained yet
+        let artifact_dir = Path::new(calibration::ARTIFACT_DIR);
+        let saved =
+            classifier::is_saved(artifact_dir) || artifact_dir.join("config.json").is_file();
+        let loaded = match saved {
+            true => calibration::load_model(artifact_dir, backend),
+            false => calibration::load_test_model(backend),
+        };
+        let loaded_model = match loaded {
+            Ok(loaded_model) => loaded_model,
+            Err(err) => {
+                mutex_state
+                    .lock()
+                    .unwrap()
+                    .log(format!("Loading the model failed: {err}"));
+                return;
+            }
+        };
+        let action_count = loaded_model.action_count().max(1);
         if let Ok(mut state) = mutex_state.lock() {
+            match saved {
+                true => state.log(format!("Loaded the model from {}.", artifact_dir.display())),
+                false => state.log("Loaded the test model.".into()),
+            }
             state.update_statusbar = true;
             state.update_action_count = true;
             state.trained = true;
         }
-        mutex_settings.lock().unwrap().action_count = 3;
-        *model = Some(calibration::load_test_model());
+        mutex_settings.lock().unwrap().action_count = action_count;
+        *model = Some(loaded_model);
         let _ = ui_weak.upgrade_in_event_loop(move |ui| {
             ui.set_model_trained(true);
-            ui.set_combobox_action_count(slint::SharedString::from("3 actions"));
+            let text = match action_count {
+                1 => "1 action".to_string(),
+                count => format!("{count} actions"),
+            };
+            ui.set_combobox_action_count(text.into());
         });
     });
 
@@ -352,27 +657,75 @@ pub async fn start(app: App) {
     let mutex_calib = orig_mutex_calib.clone();
     let mutex_commands = orig_mutex_commands.clone();
     let mutex_fakeinput = orig_mutex_fakeinput.clone();
+    let mutex_state = orig_mutex_state.clone();
     let appclone = app.clone();
     tokio::spawn(async move {
+        let mut was_inferring = false;
+        let mut prediction_log = None;
         loop {
             let currently_inferring: bool = {
                 // Create a sub-scope to drop the MutexGuard afterwards
                 let calib_flow = mutex_flow.lock().unwrap();
                 calib_flow.currently_inferring
             };
+            if currently_inferring != was_inferring {
+                // Every inference session gets a fresh log
+                was_inferring = currently_inferring;
+                prediction_log = None;
+                if currently_inferring {
+                    let msg = match recording::PredictionLog::create(PREDICTION_LOG_PATH) {
+                        Ok(log) => {
+                            prediction_log = Some(log);
+                            format!("Logging predictions to {PREDICTION_LOG_PATH}.")
+                        }
+                        Err(err) => format!("Failed to log predictions: {err}"),
+                    };
+                    mutex_state.lock().unwrap().log(msg);
+                }
+            }
             if currently_inferring {
                 let model = mutex_model.lock().unwrap();
-                let calib = mutex_calib.lock().unwrap();
-                if (*model).is_some() {
-                    let inferred = calib.infer_latest((*model).clone().unwrap());
+                let mut calib = mutex_calib.lock().unwrap();
+                if let Some(classifier) = model.as_deref() {
+                    let inferred = match calib.infer_latest(classifier) {
+                        Ok(inferred) => inferred,
+                        Err(err) => {
+                            // The model doesn't fit the connected PsyLink, there's no point
+                            // in retrying until the user trains or loads a matching model.
+                            mutex_flow.lock().unwrap().currently_inferring = false;
+                            mutex_state.lock().unwrap().log(format!(
+                                "Can't use this AI model with the connected device: {err}"
+                            ));
+                            None
+                        }
+                    };
                     if let Some(key) = inferred {
                         {
                             let mut gui_commands = mutex_commands.lock().unwrap();
                             gui_commands.change_predicted_key = Some(key.to_string());
                         }
-                        {
+                        let action = {
                             let mut fakeinput = mutex_fakeinput.lock().unwrap();
                             fakeinput.set_predicted(key as u8);
+                            fakeinput.actions.get(key as usize).map(Action::to_string)
+                        };
+                        if let Some(log) = &mut prediction_log {
+                            let index = calib.get_current_index().saturating_sub(1);
+                            let timestamp = calib.dataset.timestamps.get(index);
+                            let action = action.unwrap_or_default();
+                            let written = log.write(
+                                timestamp.copied().unwrap_or_default(),
+                                index,
+                                key,
+                                &action,
+                            );
+                            if let Err(err) = written {
+                                mutex_state
+                                    .lock()
+                                    .unwrap()
+                                    .log(format!("Stopped logging predictions: {err}"));
+                                prediction_log = None;
+                            }
                         }
                     }
                 } else {
@@ -404,7 +757,7 @@ pub async fn start(app: App) {
         loop {
             if mutex_state.lock().unwrap().connected {
                 let plotter = mutex_plotter.lock().unwrap().clone();
-                let rendered = plotter.render();
+                let rendered = plotter.render_view();
                 let _ = ui_weak.upgrade_in_event_loop(move |ui| {
                     ui.set_graph0(slint::Image::from_rgb8(rendered));
                 });
@@ -434,180 +787,376 @@ pub async fn start(app: App) {
     let mutex_settings = orig_mutex_settings.clone();
     let mutex_state = orig_mutex_state.clone();
     let thread_network = tokio::spawn(async move {
-        let mut device = loop {
-            mutex_state.lock().unwrap().update_statusbar = true;
-            if let Ok(device) = bluetooth::find_peripheral(appclone, Some(mutex_quit.clone())).await
+        'connect: loop {
+            let sources = {
+                let mut settings = mutex_settings.lock().unwrap();
+                settings.connect_requested = false;
+                settings.source.clone()
+            };
+            let devices = sources.len();
             {
-                let address = device.address.clone();
-                let _ = ui_weak.upgrade_in_event_loop(move |ui| {
-                    ui.set_text_connection_title(
-                        format!("Found PsyLink with MAC address {address}.\n\nConnecting...")
-                            .into(),
-                    );
-                });
-                break device;
-            }
-            tokio::time::sleep(tokio::time::Duration::from_secs_f32(0.25)).await;
-            if *(mutex_quit.lock().unwrap()) {
-                if appclone.verbose > 0 {
-                    println!("Quitting networking thread!");
-                }
-                return;
+                let mut state = mutex_state.lock().unwrap();
+                state.devices = vec![DeviceStatus::Connecting; devices];
+                state.packet_rates.clear();
+                state.update_statusbar = true;
             }
-        };
-        device.find_characteristics().await;
-        {
-            // Create a sub-scope to drop the MutexGuard afterwards
-            let mut state = mutex_state.lock().unwrap();
-            state.connected = true;
-            state.log(format!(
-                "Connected to PsyLink with MAC address {}.",
-                device.address.clone()
-            ));
-            state.update_statusbar = true;
-        }
-
-        let _ = ui_weak.upgrade_in_event_loop(move |ui| {
-            ui.set_connected(true);
-            ui.set_text_connection_title(
-                format!("PsyLink connection established.\n\nPlease select another tab.").into(),
-            );
-            ui.set_text_graph_title(format!("Displaying PsyLink signals.").into());
-            ui.set_page(1);
-        });
-        let mut decoder = protocol::Decoder::new(EMG_CHANNELS);
+            let scanning = sources
+                .iter()
+                .all(|spec| matches!(spec, transport::SourceSpec::Bluetooth(_)));
+            let title = match scanning {
+                true => "Scanning for nearby PsyLink devices...".into(),
+                false => format!("Connecting to {sources}..."),
+            };
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                ui.set_text_connection_title(title.into());
+            });
 
-        let mut time = SystemTime::now();
+            let mut acquisition = acquisition::MultiAcquisition::new(&appclone, &sources);
+            let mut layout = protocol::ChannelLayout::default();
+            let mut ready = false;
+            // For each device, the index of the first packet that was recorded without it
+            let mut outage_start: Vec<Option<usize>> = vec![None; devices];
+            let mut row_gaps: Vec<acquisition::RowGap> = vec![]; // Within the next packet
+            let mut last_rate_update = std::time::Instant::now();
+
+            loop {
+                let (enable_accelerometer, enable_gyroscope) = {
+                    let settings = mutex_settings.lock().unwrap();
+                    acquisition.set_gap_policy(settings.gap_policy);
+                    (!settings.disable_accelerometer, !settings.disable_gyroscope)
+                };
+
+                // Scanning for a PsyLink or waiting to reconnect can take forever, so we must
+                // be able to abandon it when the user quits or asks to connect again.
+                let event = if connect_requested(&mutex_settings, &mutex_quit) {
+                    None
+                } else {
+                    tokio::select! {
+                        event = acquisition.next_event(enable_accelerometer, enable_gyroscope) => Some(event),
+                        _ = wait_for_connect_request(&mutex_settings, &mutex_quit) => None,
+                    }
+                };
+                let Some(event) = event else {
+                    println!("Disconnecting...");
+                    acquisition.disconnect().await;
+                    {
+                        let mut state = mutex_state.lock().unwrap();
+                        state.connected = false;
+                        state.devices.clear();
+                        state.update_statusbar = true;
+                    }
+                    if *(mutex_quit.lock().unwrap()) {
+                        if appclone.verbose > 0 {
+                            println!("Quitting networking thread!");
+                        }
+                        return;
+                    }
+                    let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                        ui.set_connected(false);
+                        ui.set_page(0);
+                    });
+                    continue 'connect;
+                };
+
+                // Tells which device a message is about, if there's more than one
+                let prefix = |device: usize| match devices {
+                    1 => String::new(),
+                    _ => format!("Device {}: ", device + 1),
+                };
+
+                let packet = match event {
+                    acquisition::MultiEvent::Device(
+                        device,
+                        acquisition::Event::Connected {
+                            description,
+                            layout: device_layout,
+                            device: device_info,
+                        },
+                    ) => {
+                        {
+                            // Remembered in saved sessions
+                            let mut calib = mutex_calib.lock().unwrap();
+                            let infos = &mut calib.dataset.info.devices;
+                            infos.resize(devices, Default::default());
+                            infos[device] = device_info;
+                        }
+                        let title = {
+                            let mut state = mutex_state.lock().unwrap();
+                            state.devices[device] = DeviceStatus::Connected;
+                            state.log(format!(
+                                "{}Connected to {description} with {} EMG channels.",
+                                prefix(device),
+                                device_layout.emg_channels
+                            ));
+                            state.update_statusbar = true;
+                            let count = state.connected_devices();
+                            format!("Connected to {count} of {devices} devices...")
+                        };
+                        if devices > 1 && !ready {
+                            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                                ui.set_text_connection_title(title.into());
+                            });
+                        }
+                        continue;
+                    }
+                    acquisition::MultiEvent::Ready(new_layout) => {
+                        layout = new_layout;
+                        ready = true;
+                        let mut plotter = Plotter::new(layout);
+                        {
+                            let settings = mutex_settings.lock().unwrap();
+                            plotter.set_filter(&settings.display_filter);
+                            plotter.overlay = settings.graph_overlay;
+                            plotter.view = settings.graph_view;
+                        }
+                        *mutex_plotter.lock().unwrap() = plotter;
+                        let discarded = mutex_calib.lock().unwrap().set_layout(layout);
+                        {
+                            // Create a sub-scope to drop the MutexGuard afterwards
+                            let mut state = mutex_state.lock().unwrap();
+                            state.connected = true;
+                            if discarded {
+                                state.log(
+                                    "Discarded recorded data with a different channel layout."
+                                        .into(),
+                                );
+                            }
+                            state.update_statusbar = true;
+                        }
 
-        loop {
-            // Receive PsyLink signal packet
-            let bytearray: Vec<u8> = device.read().await.unwrap(); // TODO: catch panic
-            if appclone.verbose > 1 {
-                let text = bytearray
-                    .iter()
-                    .map(|n| n.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                println!("Received BLE payload: {text};");
-            }
+                        let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                            let channel_names: Vec<slint::SharedString> = layout
+                                .channel_names()
+                                .into_iter()
+                                .map(slint::SharedString::from)
+                                .collect();
+                            ui.set_channel_names(
+                                std::rc::Rc::new(slint::VecModel::from(channel_names)).into(),
+                            );
+                            ui.set_connected(true);
+                            ui.set_text_connection_title(
+                                "PsyLink connection established.\n\nPlease select another tab."
+                                    .into(),
+                            );
+                            ui.set_text_graph_title("Displaying PsyLink signals.".into());
+                            ui.set_page(1);
+                        });
+                        continue;
+                    }
+                    acquisition::MultiEvent::Device(
+                        device,
+                        acquisition::Event::Reconnected {
+                            description,
+                            outage,
+                        },
+                    ) => {
+                        // Mark the outage, so that no training sample spans it.  With several
+                        // devices, the others kept recording, so the gap covers those packets.
+                        if let Some(start) = outage_start[device].take() {
+                            let calib_flow = mutex_flow.lock().unwrap();
+                            if calib_flow.currently_calibrating || calib_flow.currently_inferring {
+                                let mut calib = mutex_calib.lock().unwrap();
+                                let packet_index = calib.get_current_index();
+                                let start = start.min(packet_index);
+                                calib.add_gap(calibration::Gap {
+                                    packet_index: start,
+                                    inserted: packet_index - start,
+                                    duration: outage,
+                                });
+                            }
+                        }
+                        let connected = {
+                            let mut state = mutex_state.lock().unwrap();
+                            state.devices[device] = DeviceStatus::Connected;
+                            state.connected = ready;
+                            state.log(format!(
+                                "{}Reconnected to {description} after {outage:.1}s.",
+                                prefix(device)
+                            ));
+                            state.update_statusbar = true;
+                            state.connected
+                        };
+                        let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                            ui.set_connected(connected);
+                            ui.set_text_graph_title("Displaying PsyLink signals.".into());
+                        });
+                        continue;
+                    }
+                    acquisition::MultiEvent::Device(
+                        device,
+                        acquisition::Event::Disconnected {
+                            error,
+                            retry_in,
+                            attempt,
+                        },
+                    ) => {
+                        println!("Error: {error}");
+                        outage_start[device]
+                            .get_or_insert_with(|| mutex_calib.lock().unwrap().get_current_index());
+                        let msg = match retry_in {
+                            Some(delay) => format!(
+                                "{}Connection lost: {error}\nReconnecting in {:.1}s (attempt {attempt})...",
+                                prefix(device),
+                                delay.as_secs_f32()
+                            ),
+                            None => format!("{}Connection lost: {error}", prefix(device)),
+                        };
+                        let (connected, gave_up) = {
+                            let mut state = mutex_state.lock().unwrap();
+                            state.devices[device] = match retry_in {
+                                Some(_) => DeviceStatus::Reconnecting(attempt),
+                                None => DeviceStatus::Lost,
+                            };
+                            // The other devices keep streaming on their own
+                            state.connected = ready && state.connected_devices() > 0;
+                            state.log(msg.replace('\n', " "));
+                            state.update_statusbar = true;
+                            let gave_up = state
+                                .devices
+                                .iter()
+                                .all(|status| *status == DeviceStatus::Lost);
+                            (state.connected, gave_up)
+                        };
+                        let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                            ui.set_connected(connected);
+                            ui.set_text_graph_title(msg.clone().into());
+                            ui.set_text_connection_title(msg.into());
+                            if gave_up {
+                                ui.set_page(0);
+                            }
+                        });
+                        continue;
+                    }
+                    acquisition::MultiEvent::Device(
+                        device,
+                        acquisition::Event::DecodeFailed(err),
+                    ) => {
+                        println!("Error: {}Failed to decode packet: {err}", prefix(device));
+                        continue;
+                    }
+                    acquisition::MultiEvent::Device(_, acquisition::Event::Packet(_)) => continue,
+                    acquisition::MultiEvent::Gap(gap) => {
+                        row_gaps.push(gap);
+                        continue;
+                    }
+                    acquisition::MultiEvent::Packet(packet) => packet,
+                };
+                let gaps = std::mem::take(&mut row_gaps);
 
-            // Decode packet
-            let (enable_accelerometer, enable_gyroscope) = {
-                let settings = mutex_settings.lock().unwrap();
-                (!settings.disable_accelerometer, !settings.disable_gyroscope)
-            };
-            let packet = decoder.decode_packet(bytearray, enable_accelerometer, enable_gyroscope);
-            if packet.is_err() {
-                let message: String = packet.unwrap_err();
-                println!("Error: {message}");
-                continue;
-            }
-            let packet = packet.unwrap();
-            if packet.is_duplicate {
-                if appclone.verbose > 0 {
-                    println!("Dropping duplicate packet.");
+                if packet.is_duplicate {
+                    if appclone.verbose > 0 {
+                        println!("Dropping duplicate packet.");
+                    }
+                    continue;
+                }
+                if last_rate_update.elapsed().as_secs_f32() >= 1.0 {
+                    last_rate_update = std::time::Instant::now();
+                    let mut state = mutex_state.lock().unwrap();
+                    state.packet_rates = acquisition.packet_rates();
+                    state.update_statusbar = true;
+                }
+                if packet.lost_packets > 0 && appclone.verbose > 1 {
+                    println!(
+                        "Warning: lost {} packet{}",
+                        packet.lost_packets,
+                        if packet.lost_packets == 1 { "" } else { "s" }
+                    );
                 }
-                continue;
-            }
-            if packet.lost_packets > 0 && appclone.verbose > 1 {
-                println!(
-                    "Warning: lost {} packet{}",
-                    packet.lost_packets,
-                    if packet.lost_packets == 1 { "" } else { "s" }
-                );
-            }
 
-            let dt = if let Ok(duration) = time.elapsed() {
-                duration.as_secs_f64()
-            } else {
-                0.1
-            };
-            time = SystemTime::now();
+                // Use the decoder's time base rather than the wall clock, so that the calibration
+                // timer agrees with the timestamps of the recorded samples.
+                let dt = packet.duration();
 
-            // Add packet to plotter
-            {
-                let mut plotter = mutex_plotter.lock().unwrap();
-                plotter.insert(&packet.samples);
-            }
+                // Add packet to plotter
+                {
+                    let mut plotter = mutex_plotter.lock().unwrap();
+                    plotter.insert(&packet.samples, &packet.timestamps);
+                }
 
-            // Create a sub-scope because we must drop the MutexGuard before await
-            {
-                let mut calib_flow = mutex_flow.lock().unwrap();
-                let mut calib = mutex_calib.lock().unwrap();
-                if calib_flow.currently_calibrating || calib_flow.currently_inferring {
-                    if calib_flow.currently_calibrating {
-                        // Update calibration flow state
-                        let state_changed = calib_flow.tick(dt);
-                        {
-                            let mut gui_commands = mutex_commands.lock().unwrap();
-                            if state_changed {
-                                let actions = mutex_fakeinput.lock().unwrap().actions.clone();
-                                gui_commands.change_calib_message =
-                                    Some(calib_flow.generate_message(actions));
-                                match calib_flow.state {
-                                    CalibrationFlowState::Done => {
+                // Create a sub-scope because we must drop the MutexGuard before await
+                {
+                    let mut calib_flow = mutex_flow.lock().unwrap();
+                    let mut calib = mutex_calib.lock().unwrap();
+                    if calib_flow.currently_calibrating || calib_flow.currently_inferring {
+                        // A dataset loaded in the meantime may not match the connected device
+                        if calib.set_layout(layout) {
+                            mutex_state.lock().unwrap().log(
+                                "Discarded recorded data with a different channel layout.".into(),
+                            );
+                        }
+
+                        if calib_flow.currently_calibrating {
+                            // Update calibration flow state
+                            let state_changed = calib_flow.tick(dt);
+                            {
+                                let mut gui_commands = mutex_commands.lock().unwrap();
+                                if state_changed {
+                                    let actions = mutex_fakeinput.lock().unwrap().actions.clone();
+                                    gui_commands.change_calib_message =
+                                        Some(calib_flow.generate_message(actions));
+                                    if calib_flow.state == CalibrationFlowState::Done {
                                         let _ = ui_weak.upgrade_in_event_loop(move |ui| {
                                             ui.set_calibrating(false);
                                         });
                                     }
-                                    _ => {}
                                 }
-                            }
-                            if calib_flow.timer > 0.0 {
-                                gui_commands.change_calib_timer =
-                                    Some(format!("{:.1}s", calib_flow.timer));
-                            } else {
-                                gui_commands.change_calib_timer = Some(String::new());
+                                if calib_flow.timer > 0.0 {
+                                    gui_commands.change_calib_timer =
+                                        Some(format!("{:.1}s", calib_flow.timer));
+                                } else {
+                                    gui_commands.change_calib_timer = Some(String::new());
+                                }
                             }
                         }
-                    }
 
-                    // Add samples to dataset
-                    let label_maybe = calib_flow.get_label();
-                    for sample in transpose_vec(packet.samples) {
-                        // Always add the packet, so we have a history of packets
-                        // from which we can construct the training samples
-                        if appclone.verbose > 0 {
-                            println!("Adding packet {sample:?}");
+                        // Add samples to dataset
+                        let packet_index = calib.get_current_index();
+                        for gap in gaps {
+                            calib.add_gap(calibration::Gap {
+                                packet_index: packet_index + gap.offset,
+                                inserted: gap.inserted,
+                                duration: gap.duration,
+                            });
                         }
-                        calib.add_packet(sample);
 
-                        // Add datapoints only if UI asks the user to perform some action
-                        if let Some(label) = label_maybe {
-                            let datapoint = calibration::Datapoint {
-                                packet_index: calib.get_current_index(),
-                                label,
-                            };
+                        let label_maybe = calib_flow.get_label();
+                        let rows = transpose_vec(packet.samples);
+                        for (sample, timestamp) in rows.into_iter().zip(packet.timestamps) {
+                            // Always add the packet, so we have a history of packets
+                            // from which we can construct the training samples
                             if appclone.verbose > 0 {
-                                println!("Adding datapoint {datapoint:?}");
+                                println!("Adding packet {sample:?}");
+                            }
+                            calib.add_packet(sample, timestamp);
+
+                            // Add datapoints only if UI asks the user to perform some action
+                            if let Some(label) = label_maybe {
+                                let datapoint = calibration::Datapoint {
+                                    packet_index: calib.get_current_index(),
+                                    label,
+                                };
+                                if appclone.verbose > 0 {
+                                    println!("Adding datapoint {datapoint:?}");
+                                }
+                                calib.add_datapoint(datapoint);
                             }
-                            calib.add_datapoint(datapoint);
                         }
+                        mutex_state.lock().unwrap().update_statusbar = true;
                     }
-                    mutex_state.lock().unwrap().update_statusbar = true;
                 }
             }
-
-            tokio::time::sleep(tokio::time::Duration::from_secs_f32(0.001)).await;
-            if *(mutex_quit.lock().unwrap()) {
-                if appclone.verbose > 0 {
-                    println!("Quitting networking thread!");
-                }
-                println!("Disconnecting...");
-                device.disconnect().await.expect("Failed to disconnect");
-                break;
-            }
         }
     });
 
     // The thread for updating UI elements
     let ui_weak = ui.as_weak();
+    let appclone = app.clone();
     let mutex_commands = orig_mutex_commands.clone();
     let mutex_keystate = orig_mutex_keystate.clone();
     let mutex_quit = orig_mutex_quit.clone();
     tokio::spawn(async move {
         loop {
-            let mut gui_commands = mutex_commands.lock().unwrap().clone();
+            let gui_commands = mutex_commands.lock().unwrap().clone();
             let keystate = mutex_keystate.lock().unwrap().clone();
             let mutex_state = orig_mutex_state.clone();
             let mutex_model = orig_mutex_model.clone();
@@ -618,16 +1167,13 @@ pub async fn start(app: App) {
                 // Update displayed text
                 if let Some(msg) = gui_commands.change_calib_message {
                     ui.set_text_calibration_instruction(msg.into());
-                    gui_commands.change_calib_message = None;
                 }
                 if let Some(msg) = gui_commands.change_calib_timer {
                     ui.set_text_calibration_timer(msg.into());
-                    gui_commands.change_calib_timer = None;
                 }
 
                 if let Some(msg) = gui_commands.change_predicted_key {
                     ui.set_text_predicted(msg.into());
-                    gui_commands.change_predicted_key = None;
                 }
 
                 ui.set_sampled(mutex_calib.lock().unwrap().has_datapoints());
@@ -644,7 +1190,7 @@ pub async fn start(app: App) {
                     }
 
                     if state.update_statusbar {
-                        let con = if state.connected { "Yes" } else { "No" };
+                        let con = state.connection_status();
                         let cal = if mutex_model.lock().unwrap().is_some() {
                             "Yes"
                         } else {
@@ -654,9 +1200,20 @@ pub async fn start(app: App) {
                             let calib = mutex_calib.lock().unwrap();
                             (calib.get_current_index(), calib.count_datapoints())
                         };
-                        ui.set_text_statusbar(
-                            format!("Connected: {con}, Calibrated: {cal}, Samples: {sampl}, Training datapoints: {dpts}").into(),
-                        );
+                        let mut text = format!("Connected: {con}, Calibrated: {cal}, Samples: {sampl}, Training datapoints: {dpts}");
+                        if state.connected && !state.packet_rates.is_empty() {
+                            let rates: Vec<String> = state
+                                .packet_rates
+                                .iter()
+                                .map(|rate| format!("{rate:.1}"))
+                                .collect();
+                            text += &format!(
+                                ", Packet rate: {}/{} Hz",
+                                rates.join(", "),
+                                firmware::BLE_NOTIFY_RATE
+                            );
+                        }
+                        ui.set_text_statusbar(text.into());
                         state.update_statusbar = false;
                     }
                 }
@@ -698,30 +1255,128 @@ pub async fn start(app: App) {
     let _ = tokio::join!(thread_network);
 }
 
+/// Returns true if the user quit or asked to connect, to other sources or to the same ones
+fn connect_requested(
+    mutex_settings: &Arc<Mutex<GUISettings>>,
+    mutex_quit: &Arc<Mutex<bool>>,
+) -> bool {
+    *(mutex_quit.lock().unwrap()) || mutex_settings.lock().unwrap().connect_requested
+}
+
+/// Returns once the user quits or asks to connect
+async fn wait_for_connect_request(
+    mutex_settings: &Arc<Mutex<GUISettings>>,
+    mutex_quit: &Arc<Mutex<bool>>,
+) {
+    while !connect_requested(mutex_settings, mutex_quit) {
+        tokio::time::sleep(tokio::time::Duration::from_secs_f32(0.1)).await;
+    }
+}
+
 #[derive(Clone)]
 pub struct Plotter {
     pub data: Vec<VecDeque<f64>>,
+    pub timestamps: VecDeque<f64>,
+    pub layout: protocol::ChannelLayout,
+    pub overlay: Option<features::Feature>, // Drawn over each EMG channel
+    pub view: GraphView,
+    filter: dsp::StreamFilter, // Only affects the graph, not the recorded data
+    sample_rate: f64,          // As decoded from the sampling delay of the latest packet
+    spectrogram: spectrum::Spectrogram, // Of the EMG channels
+}
+
+/// What the graph page shows
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GraphView {
+    #[default]
+    Signal,
+    Spectrum,
+    Spectrogram,
 }
 
 impl Plotter {
-    pub fn new(channel_count: usize) -> Self {
-        let data = (0..channel_count)
+    pub fn new(layout: protocol::ChannelLayout) -> Self {
+        let data = (0..layout.total_channels())
             .map(|_| VecDeque::with_capacity(MAX_POINTS))
             .collect();
-        Self { data }
+        let timestamps = VecDeque::with_capacity(MAX_POINTS);
+        let filter = dsp::StreamFilter::new(&dsp::FilterChain::default(), layout, 0.0);
+        Self {
+            data,
+            timestamps,
+            layout,
+            overlay: None,
+            view: GraphView::default(),
+            filter,
+            sample_rate: firmware::SAMPLE_RATE,
+            spectrogram: spectrum::Spectrogram::default(),
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: &dsp::FilterChain) {
+        let sample_rate = protocol::estimate_sample_rate(self.timestamps.make_contiguous());
+        self.filter = dsp::StreamFilter::new(filter, self.layout, sample_rate);
     }
 
-    pub fn insert(&mut self, items: &Vec<Vec<u8>>) {
+    pub fn insert(&mut self, items: &[Vec<u8>], timestamps: &[f64]) {
+        if timestamps.len() > 1 {
+            self.sample_rate = protocol::estimate_sample_rate(timestamps);
+            self.filter.set_sample_rate(self.sample_rate);
+        }
+        for timestamp in timestamps {
+            if self.timestamps.len() >= MAX_POINTS {
+                self.timestamps.pop_front();
+            }
+            self.timestamps.push_back(*timestamp);
+        }
         for (channel_index, samples) in items.iter().enumerate() {
             let channel = &mut self.data[channel_index];
             for signal in samples {
                 if channel.len() >= MAX_POINTS {
                     channel.pop_front();
                 }
-                let normalized_signal = ((*signal as f64) - 127.0) / 127.0;
+                let filtered = self.filter.filter(channel_index, (*signal as f64) - 127.0);
+                let normalized_signal = filtered / 127.0;
                 channel.push_back(normalized_signal);
             }
         }
+        let emg_channels: Vec<usize> = (0..self.data.len())
+            .filter(|channel| self.layout.is_emg(*channel))
+            .collect();
+        self.spectrogram.update(
+            &self.data,
+            &emg_channels,
+            &self.timestamps,
+            timestamps.len(),
+            self.sample_rate,
+        );
+    }
+
+    fn channel_color(&self, channel: usize) -> RGBColor {
+        // The last channels of each device belong to the gyroscope and the accelerometer
+        if self.layout.is_emg(channel) {
+            match channel % self.layout.device_channels() % 4 {
+                0 => GRAPH_EMG1_5,
+                1 => GRAPH_EMG2_6,
+                2 => GRAPH_EMG3_7,
+                _ => GRAPH_EMG4_8,
+            }
+        } else {
+            match (channel % self.layout.device_channels() - self.layout.emg_channels) % 3 {
+                0 => GRAPH_GYRO1,
+                1 => GRAPH_GYRO2,
+                _ => GRAPH_GYRO3,
+            }
+        }
+    }
+
+    /// Renders the graph that the view asks for
+    pub fn render_view(&self) -> SharedPixelBuffer<slint::Rgb8Pixel> {
+        match self.view {
+            GraphView::Signal => self.render(),
+            GraphView::Spectrum => self.render_spectrum(),
+            GraphView::Spectrogram => self.render_spectrogram(),
+        }
     }
 
     pub fn render(&self) -> SharedPixelBuffer<slint::Rgb8Pixel> {
@@ -731,8 +1386,11 @@ impl Plotter {
         let root = backend.into_drawing_area();
         root.fill(&BG_COLOR).expect("error filling drawing area");
 
-        let x_axis = 0..MAX_POINTS;
-        let y_axis = -(TOTAL_CHANNELS as f64 + 1.0)..1.0;
+        // The x axis shows the seconds relative to the latest sample
+        let latest = self.timestamps.back().copied().unwrap_or(0.0);
+        let earliest = self.timestamps.front().copied().unwrap_or(0.0);
+        let x_axis = (earliest - latest).min(-1.0)..0.0;
+        let y_axis = -(self.data.len() as f64 + 1.0)..1.0;
         let mut chart = ChartBuilder::on(&root)
             .build_cartesian_2d(x_axis, y_axis)
             .expect("error building coordinate system");
@@ -744,18 +1402,78 @@ impl Plotter {
                 .draw_series(LineSeries::new(
                     samples
                         .iter()
-                        .enumerate()
-                        .map(|(i, x)| (i, *x as f64 - 1.0 * channel as f64)),
-                    &match channel {
-                        0 | 4 => GRAPH_EMG1_5,
-                        1 | 5 => GRAPH_EMG2_6,
-                        2 | 6 => GRAPH_EMG3_7,
-                        3 | 7 => GRAPH_EMG4_8,
-                        8 | 11 => GRAPH_GYRO1,
-                        9 | 12 => GRAPH_GYRO2,
-                        10 | 13 => GRAPH_GYRO3,
-                        _ => WHITE,
-                    },
+                        .zip(self.timestamps.iter())
+                        .map(|(x, t)| (t - latest, *x - 1.0 * channel as f64)),
+                    &self.channel_color(channel),
+                ))
+                .expect("error drawing series");
+
+            if let Some(feature) = self.overlay.filter(|_| self.layout.is_emg(channel)) {
+                let timestamps: Vec<f64> = self.timestamps.iter().copied().collect();
+                let signal: Vec<f64> = samples.iter().copied().collect();
+                chart
+                    .draw_series(LineSeries::new(
+                        feature
+                            .trailing(&signal, OVERLAY_WINDOW, OVERLAY_STEP)
+                            .into_iter()
+                            .filter_map(|(index, value)| {
+                                let t = timestamps.get(index)?;
+                                Some((t - latest, value - 1.0 * channel as f64))
+                            }),
+                        GRAPH_OVERLAY.stroke_width(2),
+                    ))
+                    .expect("error drawing overlay");
+            }
+        }
+
+        root.present().expect("error presenting");
+        drop(chart);
+        drop(root);
+
+        pixel_buffer
+    }
+
+    /// Renders the power spectral density of every EMG channel in decibels, up to the
+    /// Nyquist frequency
+    pub fn render_spectrum(&self) -> SharedPixelBuffer<slint::Rgb8Pixel> {
+        let mut pixel_buffer = SharedPixelBuffer::new(512, 386);
+        let size = (pixel_buffer.width(), pixel_buffer.height());
+        let backend = BitMapBackend::with_buffer(pixel_buffer.make_mut_bytes(), size);
+        let root = backend.into_drawing_area();
+        root.fill(&BG_COLOR).expect("error filling drawing area");
+
+        let spectra: Vec<(usize, spectrum::Psd)> = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(channel, _)| self.layout.is_emg(*channel))
+            .map(|(channel, samples)| {
+                let signal: Vec<f64> = samples.iter().copied().collect();
+                (channel, spectrum::welch(&signal, self.sample_rate))
+            })
+            .collect();
+        let decibels = |density: f64| 10.0 * density.max(1e-12).log10();
+        let highest = spectra
+            .iter()
+            .flat_map(|(_, psd)| psd.density.iter().copied().map(decibels))
+            .fold(SPECTRUM_RANGE - 100.0, f64::max)
+            .ceil();
+        let x_axis = 0.0..self.sample_rate / 2.0;
+        let y_axis = highest - SPECTRUM_RANGE..highest;
+        let mut chart = ChartBuilder::on(&root)
+            .build_cartesian_2d(x_axis, y_axis)
+            .expect("error building coordinate system");
+
+        chart.configure_mesh().draw().expect("error drawing");
+
+        for (channel, psd) in spectra {
+            chart
+                .draw_series(LineSeries::new(
+                    psd.frequencies
+                        .iter()
+                        .zip(&psd.density)
+                        .map(|(frequency, density)| (*frequency, decibels(*density))),
+                    &self.channel_color(channel),
                 ))
                 .expect("error drawing series");
         }
@@ -766,6 +1484,66 @@ impl Plotter {
 
         pixel_buffer
     }
+
+    /// Renders the spectrogram of the EMG channels, with time on the x axis, frequency
+    /// on the y axis and the power in decibels as color
+    pub fn render_spectrogram(&self) -> SharedPixelBuffer<slint::Rgb8Pixel> {
+        let mut pixel_buffer = SharedPixelBuffer::new(512, 386);
+        let size = (pixel_buffer.width(), pixel_buffer.height());
+        let backend = BitMapBackend::with_buffer(pixel_buffer.make_mut_bytes(), size);
+        let root = backend.into_drawing_area();
+        root.fill(&BG_COLOR).expect("error filling drawing area");
+
+        let spectrogram = &self.spectrogram;
+        let frequencies = spectrogram.frequencies();
+        let nyquist = spectrogram.sample_rate / 2.0;
+        let latest = spectrogram.times.back().copied().unwrap_or(0.0);
+        let earliest = spectrogram.times.front().copied().unwrap_or(0.0);
+        let hop = spectrum::HOP as f64 / spectrogram.sample_rate;
+        let x_axis = (earliest - latest - hop).min(-1.0)..0.0;
+        let mut chart = ChartBuilder::on(&root)
+            .build_cartesian_2d(x_axis, 0.0..nyquist.max(1.0))
+            .expect("error building coordinate system");
+
+        let decibels = |density: f64| 10.0 * density.max(1e-12).log10();
+        let highest = spectrogram
+            .columns
+            .iter()
+            .flatten()
+            .copied()
+            .map(decibels)
+            .fold(SPECTRUM_RANGE - 100.0, f64::max);
+        let bin_height = frequencies.get(1).copied().unwrap_or(1.0);
+        let cells =
+            spectrogram
+                .columns
+                .iter()
+                .zip(&spectrogram.times)
+                .flat_map(|(column, time)| {
+                    let right = time - latest;
+                    column
+                        .iter()
+                        .zip(&frequencies)
+                        .map(move |(density, frequency)| {
+                            let level = 1.0 - (highest - decibels(*density)) / SPECTRUM_RANGE;
+                            let color = ViridisRGB.get_color(level.clamp(0.0, 1.0));
+                            Rectangle::new(
+                                [
+                                    (right - hop, frequency - bin_height / 2.0),
+                                    (right, frequency + bin_height / 2.0),
+                                ],
+                                color.filled(),
+                            )
+                        })
+                });
+        chart.draw_series(cells).expect("error drawing series");
+
+        root.present().expect("error presenting");
+        drop(chart);
+        drop(root);
+
+        pixel_buffer
+    }
 }
 
 /// A thread messaging struct for deferring UI changes to a separate thread
@@ -781,13 +1559,27 @@ pub struct GUISettings {
     pub disable_gyroscope: bool,
     pub disable_accelerometer: bool,
     pub action_count: usize,
+    pub gap_policy: protocol::GapPolicy,
+    pub source: transport::SourceList,
+    pub connect_requested: bool, // Reconnect, even if the source didn't change
+    pub display_filter: dsp::FilterChain,
+    pub classifier_filter: dsp::FilterChain,
+    pub graph_overlay: Option<features::Feature>,
+    pub graph_view: GraphView,
+    pub classifier_input: features::Input,
+    pub classifier: classifier::ClassifierKind,
+    pub backend: calibration::BackendKind,
 }
 
 impl GUISettings {
-    pub fn new() -> Self {
-        let mut result = Self::default();
-        result.action_count = 1;
-        result
+    pub fn new(source: transport::SourceList, backend: calibration::BackendKind) -> Self {
+        Self {
+            action_count: 1,
+            gap_policy: protocol::GapPolicy::Interpolate,
+            source,
+            backend,
+            ..Self::default()
+        }
     }
 }
 
@@ -799,22 +1591,27 @@ pub struct GUIState {
     pub training: bool,
     pub log_entries: Vec<String>,
     pub update_statusbar: bool,
+    pub packet_rates: Vec<f64>, // Distinct packets received per second, for each device
+    pub devices: Vec<DeviceStatus>,
     pub update_log: bool,
     pub update_action_count: bool,
     pub train_max_datapoints: usize,
     pub train_epochs: usize,
+    pub train_exclude_gaps: bool,
     pub calib_repetitions: usize,
     pub calib_action_time: f64,
 }
 
 impl GUIState {
     pub fn new() -> Self {
-        let mut result = Self::default();
-        result.train_max_datapoints = calibration::DEFAULT_MAX_DATAPOINTS;
-        result.train_epochs = calibration::DEFAULT_EPOCHS;
-        result.calib_repetitions = DEFAULT_REPETITIONS;
-        result.calib_action_time = DEFAULT_ACTION_TIME;
-        result
+        Self {
+            train_max_datapoints: calibration::DEFAULT_MAX_DATAPOINTS,
+            train_epochs: calibration::DEFAULT_EPOCHS,
+            train_exclude_gaps: true,
+            calib_repetitions: DEFAULT_REPETITIONS,
+            calib_action_time: DEFAULT_ACTION_TIME,
+            ..Self::default()
+        }
     }
 
     pub fn log(&mut self, entry: String) {
@@ -825,6 +1622,46 @@ impl GUIState {
     pub fn log2string(&self) -> String {
         self.log_entries.join("\n")
     }
+
+    pub fn connected_devices(&self) -> usize {
+        self.devices
+            .iter()
+            .filter(|status| **status == DeviceStatus::Connected)
+            .count()
+    }
+
+    /// Describes the connection of each device for the status bar
+    pub fn connection_status(&self) -> String {
+        match self.devices.as_slice() {
+            [] => "No".into(),
+            [status] => status.to_string(),
+            devices => devices
+                .iter()
+                .enumerate()
+                .map(|(index, status)| format!("{}: {status}", index + 1))
+                .collect::<Vec<String>>()
+                .join(", "),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceStatus {
+    Connecting,
+    Connected,
+    Reconnecting(u32), // The number of the next attempt
+    Lost,
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceStatus::Connecting => write!(f, "No"),
+            DeviceStatus::Connected => write!(f, "Yes"),
+            DeviceStatus::Reconnecting(attempt) => write!(f, "Reconnecting (attempt {attempt})"),
+            DeviceStatus::Lost => write!(f, "Lost"),
+        }
+    }
 }
 
 #[derive(Clone, Default)]
@@ -890,7 +1727,7 @@ impl CalibrationFlow {
                 CalibrationFlowState::Welcome => CalibrationFlowState::NullActionWait,
                 CalibrationFlowState::NullActionWait => CalibrationFlowState::NullAction,
                 CalibrationFlowState::NullAction => {
-                    if self.remaining_repetitions.iter().all(|&x| x <= 0) {
+                    if self.remaining_repetitions.iter().all(|&x| x == 0) {
                         CalibrationFlowState::Done
                     } else {
                         CalibrationFlowState::GestureActionWait
@@ -918,9 +1755,9 @@ impl CalibrationFlow {
             let state_change_happened = self.state != new_state;
             self.state = new_state;
             self.timer = delay;
-            return state_change_happened;
+            state_change_happened
         } else {
-            return false;
+            false
         }
     }
 
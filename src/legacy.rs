@@ -0,0 +1,359 @@
+// Imports the training data of the Python version of PsyLink (archive/7_python), so that
+// recordings made with it can be used to train the models of this version.
+//
+// The Python tool saved each run into a directory save_RUN with three files:
+//
+//     signals.npz       numpy.savez_compressed of a uint8 array with the shape
+//                       (records, window, channels), stored as "arr_0"
+//     labels.json       the label of each record, the pressed keys joined by ','
+//     label_order.json  all labels in the order in which they were first recorded
+//
+// Every record is the window of samples that preceded it, newest sample first, with the
+// EMG channels followed by the gyroscope and accelerometer channels like in this version.
+// Samples are the raw bytes from the firmware.  The tool took a record at every sample, so
+// consecutive windows overlap, and the importer stitches them back together into a
+// continuous signal with a datapoint at the newest sample of each record.  Where a record
+// doesn't overlap the previous ones, e.g. after reconnecting, a gap is inserted.
+//
+// Labels become actions: a single key becomes the action that presses it, other key
+// combinations keep their legacy label as name, and no key at all is the null action.
+
+use crate::calibration::{Datapoint, Gap, PsyLinkDataset};
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+const SIGNALS_FILE: &str = "signals.npz";
+const LABELS_FILE: &str = "labels.json";
+const LABEL_ORDER_FILE: &str = "label_order.json";
+const SIGNALS_ARRAY: &str = "arr_0.npy";
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+const LABEL_SEPARATOR: char = ',';
+const SPACE_KEY: &str = "<space>";
+
+/// An array from a .npy file
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<u8>, // In C order
+}
+
+/// Returns true if the path is a run directory of the Python version or a file in it
+pub fn is_legacy_run(path: &Path) -> bool {
+    run_directory(path).join(SIGNALS_FILE).is_file()
+}
+
+fn run_directory(path: &Path) -> &Path {
+    match path.file_name() {
+        Some(name) if name == SIGNALS_FILE || name == LABELS_FILE || name == LABEL_ORDER_FILE => {
+            path.parent().unwrap_or(path)
+        }
+        _ => path,
+    }
+}
+
+/// Loads a run saved by the Python version, given its directory or a file in it
+pub fn load_run<P: AsRef<Path>>(path: P) -> Result<PsyLinkDataset> {
+    let dir = run_directory(path.as_ref());
+    let signals = BufReader::new(File::open(dir.join(SIGNALS_FILE))?);
+    let labels = std::fs::read_to_string(dir.join(LABELS_FILE))?;
+    // Older runs may lack the label order, it only decides the numbering of the actions
+    let label_order = match std::fs::read_to_string(dir.join(LABEL_ORDER_FILE)) {
+        Ok(text) => Some(text),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let mut dataset = read_run(signals, &labels, label_order.as_deref())?;
+    let source = format!("PsyLink for Python, {}", dir.display());
+    dataset.info.set_metadata("source", source);
+    Ok(dataset)
+}
+
+/// Builds a dataset from the contents of the files of a run
+pub fn read_run<R: Read + Seek>(
+    signals: R,
+    labels: &str,
+    label_order: Option<&str>,
+) -> Result<PsyLinkDataset> {
+    let signals = read_npz(signals, SIGNALS_ARRAY)?;
+    let labels = parse_labels(labels)?;
+    let (records, window, channels) = match signals.shape[..] {
+        [records, window, channels] => (records, window, channels),
+        _ => {
+            return Err(Error::Parse(format!(
+                "Expected signals of the shape (records, window, channels), found {:?}",
+                signals.shape
+            )))
+        }
+    };
+    if labels.len() != records {
+        return Err(Error::Parse(format!(
+            "Found {} labels for {records} records",
+            labels.len()
+        )));
+    }
+    let imu_channels = firmware::IMU_CHANNELS as usize;
+    if channels <= imu_channels || window == 0 {
+        return Err(Error::Parse(format!(
+            "Records of {window} samples with {channels} channels can't be PsyLink signals"
+        )));
+    }
+
+    let mut dataset = PsyLinkDataset {
+        datapoints: vec![],
+        all_packets: vec![],
+        timestamps: vec![],
+        gaps: vec![],
+        layout: protocol::ChannelLayout::new(channels - imu_channels),
+        info: Default::default(),
+    };
+    dataset.info.action_names = vec![Action::None.to_string()];
+    if let Some(label_order) = label_order {
+        for label in parse_labels(label_order)? {
+            action_label(&mut dataset, &label)?;
+        }
+    }
+
+    let mut newest = None;
+    for (record, label) in signals.data.chunks(window * channels).zip(&labels) {
+        // The Python version stored the newest sample first
+        let record: Vec<&[u8]> = record.chunks(channels).rev().collect();
+        let rows = &mut dataset.all_packets;
+        let position = match newest.and_then(|newest| find_record(rows, &record, newest)) {
+            Some(position) => position,
+            None => {
+                if !rows.is_empty() {
+                    dataset.gaps.push(Gap {
+                        packet_index: rows.len(),
+                        inserted: 0,
+                        duration: 0.0,
+                    });
+                }
+                rows.len() + window - 1
+            }
+        };
+        // Only the samples after the last row are new
+        let known = (rows.len() + window).saturating_sub(position + 1);
+        let new_rows = &record[known.min(window)..];
+        rows.extend(new_rows.iter().map(|row| row.to_vec()));
+        let label = action_label(&mut dataset, label)?;
+        dataset.datapoints.push(Datapoint {
+            packet_index: position,
+            label,
+        });
+        newest = Some(position);
+    }
+    dataset
+        .datapoints
+        .sort_by_key(|datapoint| datapoint.packet_index);
+
+    // The Python version didn't keep the timing, so assume the nominal sample rate
+    dataset.timestamps = (0..dataset.all_packets.len())
+        .map(|i| i as f64 / firmware::SAMPLE_RATE)
+        .collect();
+    if dataset.info.action_names.len() == 1 {
+        dataset.info.action_names.clear();
+    }
+    Ok(dataset)
+}
+
+/// Finds the index in `rows` at which the newest sample of a record lies, if the record
+/// overlaps the end of `rows` by at least half a window.  Positions close to the one
+/// after the previous record are tried first, since the Python version recorded the
+/// windows of one packet from the newest to the oldest.  The start of a record may lie
+/// before the first row, the Python version filled its buffer with zeros at first.
+fn find_record(rows: &[Vec<u8>], record: &[&[u8]], previous: usize) -> Option<usize> {
+    let window = record.len();
+    let min_overlap = window.div_ceil(2);
+    let first = (min_overlap - 1).max(previous.saturating_sub(window));
+    let last = (rows.len() + window).checked_sub(min_overlap + 1)?;
+    let mut candidates: Vec<usize> = (first..=last).collect();
+    candidates.sort_by_key(|position| position.abs_diff(previous.saturating_sub(1)));
+    candidates.into_iter().find(|position| {
+        let skipped = (window - 1).saturating_sub(*position);
+        let start = (position + 1 + skipped) - window;
+        rows[start.min(rows.len())..]
+            .iter()
+            .zip(&record[skipped..])
+            .all(|(row, sample)| row == sample)
+    })
+}
+
+/// The label of the action that a legacy label stands for
+fn action_label(dataset: &mut PsyLinkDataset, label: &str) -> Result<u8> {
+    let mut keys = label.split(LABEL_SEPARATOR).filter(|key| !key.is_empty());
+    let name = match (keys.next(), keys.next()) {
+        (None, _) => return Ok(0),
+        (Some(SPACE_KEY), None) => Action::Key(' ').to_string(),
+        (Some(key), None) if key.chars().count() == 1 => {
+            Action::Key(key.chars().next().unwrap_or_default()).to_string()
+        }
+        _ => label.to_string(),
+    };
+    dataset.info.label_for(&name)
+}
+
+fn parse_labels(text: &str) -> Result<Vec<String>> {
+    serde_json::from_str(text)
+        .map_err(|err| Error::Parse(format!("Expected a JSON list of labels: {err}")))
+}
+
+/// Reads an array from a .npz file as written by numpy.savez or numpy.savez_compressed
+pub fn read_npz<R: Read + Seek>(input: R, name: &str) -> Result<NpyArray> {
+    let invalid = |err: zip::result::ZipError| Error::Parse(format!("Invalid NPZ file: {err}"));
+    let mut archive = zip::ZipArchive::new(input).map_err(invalid)?;
+    let mut file = archive.by_name(name).map_err(invalid)?;
+    let mut bytes = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut bytes)?;
+    read_npy(&bytes)
+}
+
+/// Parses a .npy file with an array of unsigned bytes
+pub fn read_npy(bytes: &[u8]) -> Result<NpyArray> {
+    let invalid = |what: &str| Error::Parse(format!("Invalid NPY file: {what}"));
+    let rest = bytes
+        .strip_prefix(NPY_MAGIC)
+        .ok_or_else(|| invalid("missing magic string"))?;
+    let (header_len, rest) = match rest {
+        [1, _, a, b, rest @ ..] => (u16::from_le_bytes([*a, *b]) as usize, rest),
+        [2 | 3, _, a, b, c, d, rest @ ..] => (u32::from_le_bytes([*a, *b, *c, *d]) as usize, rest),
+        _ => return Err(invalid("unsupported version")),
+    };
+    if rest.len() < header_len {
+        return Err(invalid("truncated header"));
+    }
+    let (header, data) = rest.split_at(header_len);
+    let header = String::from_utf8_lossy(header);
+
+    // The header is a Python dict literal like
+    // {'descr': '|u1', 'fortran_order': False, 'shape': (10, 250, 14), }
+    let field = |key: &str| {
+        let start = header.find(&format!("'{key}':"))? + key.len() + 3;
+        Some(header[start..].trim_start())
+    };
+    let descr = field("descr").ok_or_else(|| invalid("missing descr"))?;
+    if !(descr.starts_with("'|u1'") || descr.starts_with("'<u1'") || descr.starts_with("'u1'")) {
+        return Err(invalid("only arrays of uint8 are supported"));
+    }
+    if field("fortran_order").is_some_and(|order| order.starts_with("True")) {
+        return Err(invalid("only arrays in C order are supported"));
+    }
+    let shape = field("shape")
+        .and_then(|shape| shape.strip_prefix('('))
+        .and_then(|shape| shape.split_once(')'))
+        .ok_or_else(|| invalid("missing shape"))?
+        .0;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|dimension| !dimension.is_empty())
+        .map(|dimension| dimension.parse::<usize>())
+        .collect::<std::result::Result<Vec<usize>, _>>()
+        .map_err(|_| invalid("bad shape"))?;
+
+    let len: usize = shape.iter().product();
+    if data.len() < len {
+        return Err(invalid("truncated data"));
+    }
+    Ok(NpyArray {
+        shape,
+        data: data[..len].to_vec(),
+    })
+}
+
+#[test]
+fn test_legacy_import() {
+    use std::io::Write;
+
+    // What the Python version saved for a continuous signal of 4 EMG channels,
+    // recorded in packets of 25 samples with windows of 60 samples
+    let (window, channels, packets): (usize, usize, i64) = (60, 10, 6);
+    let signal = |time: i64| -> Vec<u8> {
+        match time {
+            ..=-1 => vec![0; channels],
+            _ => (0..channels)
+                .map(|channel| ((time * 7 + channel as i64 * 13) % 251 + 1) as u8)
+                .collect(),
+        }
+    };
+    let mut data = vec![];
+    let mut labels = vec![];
+    let mut newest = vec![];
+    for packet in 0..packets {
+        let time = packet * 25 + 24;
+        for index in 0..25 {
+            for sample in 0..window {
+                data.extend(signal(time - index - sample as i64));
+            }
+            labels.push(match time - index {
+                ..=49 => "",
+                50..=99 => "a",
+                _ => "a,s",
+            });
+            newest.push(time - index);
+        }
+    }
+    // After reconnecting, the signal continues elsewhere
+    for sample in 0..window {
+        data.extend(signal(1000 - sample as i64));
+    }
+    labels.push("<space>");
+
+    let records = labels.len();
+    let header = format!(
+        "{{'descr': '|u1', 'fortran_order': False, 'shape': ({records}, {window}, {channels}), }}"
+    );
+    let mut npy = NPY_MAGIC.to_vec();
+    npy.extend([1, 0]);
+    npy.extend((header.len() as u16).to_le_bytes());
+    npy.extend(header.as_bytes());
+    npy.extend(&data);
+    let mut npz = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    npz.start_file(SIGNALS_ARRAY, options).unwrap();
+    npz.write_all(&npy).unwrap();
+    let npz = npz.finish().unwrap();
+
+    let labels_json = format!("[\"{}\"]", labels.join("\", \""));
+    let dataset = read_run(npz, &labels_json, Some("[\"\", \"a,s\", \"a\"]")).unwrap();
+
+    // The zeros before the start of the signal come first
+    let offset = window - 25;
+    let length = offset + packets as usize * 25;
+    assert_eq!(dataset.layout, protocol::ChannelLayout::new(4));
+    assert_eq!(dataset.all_packets.len(), length + window);
+    assert_eq!(dataset.all_packets[0], vec![0; channels]);
+    assert_eq!(dataset.all_packets[offset + 33], signal(33));
+    assert_eq!(dataset.all_packets[length - 1], signal(149));
+    assert_eq!(dataset.all_packets[length + window - 1], signal(1000));
+    assert_eq!(dataset.timestamps.len(), dataset.all_packets.len());
+    assert_eq!(
+        dataset.gaps,
+        [Gap {
+            packet_index: length,
+            inserted: 0,
+            duration: 0.0
+        }]
+    );
+
+    assert_eq!(
+        dataset.info.action_names,
+        ["(no action)", "a,s", "Key \"a\"", "Key \" \""]
+    );
+    assert_eq!(dataset.datapoints.len(), records);
+    let mut positions: Vec<usize> = newest.iter().map(|time| offset + *time as usize).collect();
+    positions.sort();
+    positions.push(length + window - 1);
+    let found: Vec<usize> = dataset.datapoints.iter().map(|d| d.packet_index).collect();
+    assert_eq!(found, positions);
+    assert_eq!(dataset.datapoints[offset + 10].label, 0);
+    assert_eq!(dataset.datapoints[60].label, 2);
+    assert_eq!(dataset.datapoints[120].label, 1);
+    assert_eq!(dataset.datapoints[records - 1].label, 3);
+
+    assert!(read_npy(b"\x93NUMPY\x01\x00\x10\x00{'descr': '<f8'}").is_err());
+    assert!(read_run(std::io::Cursor::new(vec![]), "[]", None).is_err());
+}
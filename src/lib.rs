@@ -1,26 +1,45 @@
 #![doc(html_favicon_url = "https://psylink.me/favicon.ico")]
 #![doc(html_logo_url = "https://psylink.me/favicon.ico")]
 
+pub mod acquisition;
 pub mod bluetooth;
 pub mod calibration;
+pub mod classifier;
+pub mod dsp;
+pub mod edf;
+pub mod error;
 pub mod fakeinput;
+pub mod features;
 pub mod firmware;
 #[cfg(feature = "gui")]
 pub mod gui;
+pub mod legacy;
 #[allow(dead_code)]
 pub mod protocol;
+pub mod recording;
+pub mod replay;
+pub mod session;
+pub mod simulator;
 pub mod sound;
+pub mod spectrum;
+pub mod transport;
 
 pub mod prelude {
     pub use crate::fakeinput::Action;
     #[cfg(feature = "gui")]
     pub use crate::gui;
-    pub use crate::{bluetooth, calibration, fakeinput, firmware, protocol, sound};
+    pub use crate::{
+        acquisition, bluetooth, calibration, classifier, dsp, edf, error, fakeinput, features,
+        firmware, legacy, protocol, recording, replay, session, simulator, sound, spectrum,
+        transport,
+    };
 
-    #[derive(Clone, Copy)]
+    #[derive(Clone)]
     pub struct App {
         pub verbose: u8,
         pub scantime: f32,
+        pub source: transport::SourceList,
+        pub backend: calibration::BackendKind, // For training and running the neural network
     }
 
     pub fn transpose_vec<T: Clone>(matrix: Vec<Vec<T>>) -> Vec<Vec<T>> {
@@ -51,6 +70,8 @@ fn android_main(app: slint::android::AndroidApp) {
     let conf = prelude::App {
         verbose: 0,
         scantime: 3.0,
+        source: prelude::transport::SourceList::default(),
+        backend: prelude::calibration::BackendKind::default(),
     };
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -14,29 +14,161 @@ struct Cli {
     #[arg(short, long, value_name = "SECONDS", default_value_t = 3.0)]
     scantime: f32,
 
+    /// Where to get the signals from: ble, ble:FILTER, sim, sim:SCRIPT, serial:PATH, file:PATH
+    /// or replay:PATH. Defaults to ble.
+    /// Repeat --source to record several sources at once.
+    #[arg(long = "source", value_name = "SOURCE")]
+    sources: Vec<transport::SourceSpec>,
+
+    /// Only connect to the PsyLink with this MAC address
+    #[arg(long, value_name = "MAC")]
+    address: Option<String>,
+
+    /// Only connect to PsyLinks whose name matches this pattern, which may contain * and ?
+    #[arg(long, value_name = "PATTERN")]
+    name: Option<String>,
+
+    /// Use the Bluetooth adapter with this index or with this text in its description
+    #[arg(long, value_name = "ADAPTER")]
+    adapter: Option<String>,
+
+    /// Connect to the PsyLink that was used last time
+    #[arg(long)]
+    last: bool,
+
+    /// Where to train and run the neural network: wgpu (GPU) or ndarray (CPU).
+    /// Defaults to the GPU if this build supports it.
+    #[arg(long, value_name = "BACKEND")]
+    backend: Option<calibration::BackendKind>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+impl Cli {
+    fn refine_filter(&self, filter: &mut bluetooth::DeviceFilter) {
+        if let Some(address) = &self.address {
+            filter.address = Some(address.clone());
+        }
+        if let Some(name) = &self.name {
+            filter.name = name.clone();
+        }
+        if let Some(adapter) = &self.adapter {
+            filter.adapter = Some(adapter.clone());
+        }
+        filter.last |= self.last;
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Scan for PsyLink devices
-    Scan {},
+    /// Scan for PsyLink devices and list them with their signal strength
+    Scan {
+        /// How to print the devices: text, json (one object per line) or csv
+        #[arg(long, value_name = "FORMAT", default_value = "text")]
+        format: bluetooth::ScanFormat,
+
+        /// Keep scanning and print the devices again every SCANTIME seconds
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// Write the data from the signal source to the console
+    Print {
+        /// How to print the data: raw, hex, decoded, csv or jsonl
+        #[arg(long, value_name = "FORMAT", default_value = "raw")]
+        format: transport::PrintFormat,
+
+        /// Also print the gyroscope and accelerometer channels
+        #[arg(long)]
+        imu: bool,
+
+        /// Stop after this many packets
+        #[arg(long, value_name = "N")]
+        packets: Option<usize>,
+
+        /// Stop after this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        seconds: Option<f64>,
+    },
+
+    /// Record the signal sources to a file, with labels read from stdin
+    Record {
+        /// The session file to write the recording to, which `psylink export` converts to CSV
+        #[arg(long, value_name = "FILE")]
+        out: std::path::PathBuf,
+
+        /// Stop after this much time, e.g. 60s, 10m or 2h
+        #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+        duration: Option<f64>,
+    },
+
+    /// Convert a dataset saved by older versions into a session file: a dump as Rust code,
+    /// or the save_RUN directory of a run of the Python version
+    Convert {
+        /// The dataset dump, e.g. /tmp/psylink_dataset.rs, or the run directory
+        input: std::path::PathBuf,
+
+        /// The session file to write
+        output: std::path::PathBuf,
+    },
 
-    /// Write the raw data from a PsyLink to the console
-    Print {},
+    /// Export a session file, EDF/BDF file or CSV recording to CSV, EDF+ or BDF+
+    Export {
+        /// The session file, EDF/BDF file or CSV recording
+        input: std::path::PathBuf,
+
+        /// The file to write, as CSV if it ends with .csv, as BDF+ if it ends with .bdf,
+        /// and as EDF+ otherwise
+        output: std::path::PathBuf,
+    },
+
+    /// Replay a session file, EDF/BDF file or CSV recording as if it came from a PsyLink
+    Replay {
+        /// The recording to replay
+        file: std::path::PathBuf,
+
+        /// How much faster than recorded to play it back
+        #[arg(long, value_name = "FACTOR", default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64,
+
+        /// Start over at the end of the recording
+        #[arg(long = "loop")]
+        looped: bool,
+    },
 
     /// Perform a calibration on the test dataset
-    Train {},
+    Train {
+        /// The classifier to train: cnn, lda, svm, knn or forest
+        #[arg(long, value_name = "KIND", default_value = "cnn")]
+        classifier: classifier::ClassifierKind,
+    },
 
     /// Perform a calibration inference based on the pre-trained test model
-    Infer {},
+    Infer {
+        /// Use the model that train saved to this directory instead, e.g. /tmp/psylink
+        #[arg(long, value_name = "DIR")]
+        model: Option<std::path::PathBuf>,
+    },
 
     #[cfg(feature = "gui")]
     /// Open the graphical user interface (default action)
     Gui {},
 }
 
+fn parse_duration(text: &str) -> Result<f64, String> {
+    simulator::parse_time(text).ok_or_else(|| format!("Invalid time \"{text}\""))
+}
+
+fn parse_speed(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!(
+            "Invalid speed \"{text}\", expected a positive number"
+        )),
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
@@ -44,23 +176,116 @@ async fn main() -> Result<(), Box<dyn Error>> {
         dbg!(&cli);
     }
 
+    // The device options refine the filters of the Bluetooth sources
+    let mut source = match cli.sources.is_empty() {
+        true => transport::SourceList::default(),
+        false => transport::SourceList(cli.sources.clone()),
+    };
+    for spec in source.iter_mut() {
+        if let transport::SourceSpec::Bluetooth(filter) = spec {
+            cli.refine_filter(filter);
+        }
+    }
+    let filter = match source.first() {
+        transport::SourceSpec::Bluetooth(filter) => filter.clone(),
+        _ => {
+            let mut filter = bluetooth::DeviceFilter::default();
+            cli.refine_filter(&mut filter);
+            filter
+        }
+    };
+
     let conf = App {
         verbose: cli.verbose,
         scantime: cli.scantime,
+        source,
+        backend: cli.backend.unwrap_or_default(),
     };
 
     match &cli.command {
-        Some(Commands::Scan {}) => {
-            bluetooth::scan(conf).await?;
+        Some(Commands::Scan { format, watch }) => {
+            bluetooth::scan(&conf, &filter, *format, *watch).await?;
         }
-        Some(Commands::Print {}) => {
-            bluetooth::stream(conf).await?;
+        Some(Commands::Print {
+            format,
+            imu,
+            packets,
+            seconds,
+        }) => {
+            let options = transport::PrintOptions {
+                format: *format,
+                imu: *imu,
+                max_packets: *packets,
+                duration: *seconds,
+            };
+            transport::stream(&conf, &options).await?;
+        }
+        Some(Commands::Record { out, duration }) => {
+            let options = recording::RecordOptions {
+                out: out.clone(),
+                duration: *duration,
+            };
+            recording::record(&conf, &options).await?;
+        }
+        Some(Commands::Convert { input, output }) => {
+            let dataset = if legacy::is_legacy_run(input) {
+                legacy::load_run(input)?
+            } else {
+                let text = std::fs::read_to_string(input)?;
+                calibration::PsyLinkDataset::from_rust_dump(&text)?
+            };
+            dataset.save(output)?;
+            println!(
+                "Converted {} packets and {} datapoints to {}",
+                dataset.all_packets.len(),
+                dataset.datapoints.len(),
+                output.display()
+            );
+        }
+        Some(Commands::Export { input, output }) => {
+            let dataset = session::load_dataset(input)?;
+            match output.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) if ext.eq_ignore_ascii_case("csv") => {
+                    recording::save_csv(&dataset, output)?
+                }
+                _ => edf::save_edf(&dataset, output)?,
+            }
+            println!(
+                "Exported {} packets at {:.1} Hz to {}",
+                dataset.all_packets.len(),
+                protocol::estimate_sample_rate(&dataset.timestamps),
+                output.display()
+            );
+        }
+        Some(Commands::Replay {
+            file,
+            speed,
+            looped,
+        }) => {
+            let mut options = replay::ReplayOptions::new(file);
+            options.speed = *speed;
+            options.looped = *looped;
+            let conf = App {
+                source: replay::sources(&options)?,
+                ..conf
+            };
+            // Without a GUI, the replay can at least be watched on the console
+            #[cfg(feature = "gui")]
+            gui::start(conf).await;
+            #[cfg(not(feature = "gui"))]
+            {
+                let options = transport::PrintOptions {
+                    format: transport::PrintFormat::Decoded,
+                    ..Default::default()
+                };
+                transport::stream(&conf, &options).await?;
+            }
         }
-        Some(Commands::Train {}) => {
-            calibration::train()?;
+        Some(Commands::Train { classifier }) => {
+            calibration::train(*classifier, conf.backend)?;
         }
-        Some(Commands::Infer {}) => {
-            calibration::infer()?;
+        Some(Commands::Infer { model }) => {
+            calibration::infer(conf.backend, model.as_deref())?;
         }
         #[cfg(feature = "gui")]
         Some(Commands::Gui {}) | None => {
@@ -1,10 +1,52 @@
+use crate::error::{DecodeError, EncodeError, Error};
 use crate::firmware;
 
 pub const SAMPLE_VALUE_OFFSET: i32 = -127;
+pub const SAMPLE_CENTER: f64 = -SAMPLE_VALUE_OFFSET as f64; // The byte at the middle of the ADC range
+
+// The firmware never sends 0 bytes since they would terminate the BLE string,
+// so this value can't be mistaken for a real sample.
+pub const GAP_SENTINEL: u8 = 0;
+
+/// Decides which samples the decoder inserts in place of the samples of lost packets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GapPolicy {
+    /// Insert nothing, the samples before and after the gap become adjacent
+    #[default]
+    Leave,
+    /// Insert rows of GAP_SENTINEL
+    Sentinel,
+    /// Insert copies of the last sample before the gap
+    RepeatLast,
+    /// Insert a straight line between the last sample before and the first sample after the gap
+    Interpolate,
+}
+
+/// Describes the columns of a decoded sample row: first the EMG channels of the device,
+/// then the gyroscope and accelerometer channels.  When several devices are recorded at
+/// once, their columns follow each other in this order, one block per device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelLayout {
+    pub emg_channels: usize, // per device
+    pub devices: usize,
+}
 
 pub struct Decoder {
     last_tick: Option<i32>,
     channel_count: i32,
+    gap_policy: GapPolicy,
+    // The last sample of each channel of the previous packet, for filling gaps
+    last_samples: Option<Vec<u8>>,
+    // The estimated time of the next sample, in seconds since the first decoded packet
+    clock: f64,
+    // The estimated time of the first sample of the last packet
+    last_packet_start: f64,
+}
+
+/// Builds BLE payloads in the same layout as the firmware, so that they can be fed into
+/// Decoder::decode_packet.  Useful for simulators, test fixtures and fuzzing.
+pub struct Encoder {
+    channel_count: i32,
 }
 
 #[derive(Debug)]
@@ -15,8 +57,13 @@ pub struct Packet {
     pub max_sampling_delay: f64,
     pub sample_count: i32,
     pub samples: Vec<Vec<u8>>, // samples[channel][timestep]
+    // The number of timesteps at the start of `samples` that were inserted for lost packets,
+    // according to the GapPolicy of the decoder. They are not counted in sample_count.
+    pub filled_samples: i32,
     pub is_duplicate: bool,
     pub lost_packets: i32,
+    pub sample_interval: f64, // estimated seconds between two samples
+    pub timestamps: Vec<f64>, // timestamps[timestep], seconds since the first decoded packet
 }
 
 impl Decoder {
@@ -24,6 +71,10 @@ impl Decoder {
         Self {
             last_tick: None,
             channel_count,
+            gap_policy: GapPolicy::default(),
+            last_samples: None,
+            clock: 0.0,
+            last_packet_start: 0.0,
         }
     }
 
@@ -32,18 +83,32 @@ impl Decoder {
         raw_packet_payload: Vec<u8>,
         enable_accelerometer: bool,
         enable_gyroscope: bool,
-    ) -> Result<Packet, String> {
-        let tick: i32 = *raw_packet_payload
-            .get(0)
-            .ok_or("Failed to decode packet, no Tick supplied")? as i32;
-
-        let delay_byte: u8 = *raw_packet_payload
-            .get(1)
-            .ok_or("Failed to decode packet, no sampling delay byte supplied")?;
+    ) -> Result<Packet, DecodeError> {
+        let len = raw_packet_payload.len();
+        let (tick, delay_byte) = match raw_packet_payload[..] {
+            [tick, delay_byte, ..] => (tick as i32, delay_byte),
+            _ => return Err(DecodeError::TruncatedHeader { len }),
+        };
 
         let gyroscope_accelerometer: &[u8] = raw_packet_payload
-            .get(2..8)
-            .ok_or("Failed to decode packet, no gyroscope/accelerometer data supplied")?;
+            .get(2..firmware::PROTOCOL_HEADER_LEN as usize)
+            .ok_or(DecodeError::MissingImu { len })?;
+
+        let sample_bytes = len - firmware::PROTOCOL_HEADER_LEN as usize;
+        if self.channel_count <= 0 || sample_bytes % self.channel_count as usize != 0 {
+            return Err(DecodeError::PayloadLength {
+                sample_bytes,
+                channel_count: self.channel_count,
+            });
+        }
+
+        // The firmware skips the tick 0 when its counter wraps around
+        if tick == 0 {
+            return Err(DecodeError::ImpossibleTickJump {
+                last_tick: self.last_tick,
+                tick,
+            });
+        }
 
         let (min_sampling_delay, max_sampling_delay) = decompress_delay(delay_byte);
 
@@ -53,12 +118,11 @@ impl Decoder {
             false
         };
 
-        let lost_packets: i32 = if let Some(last_tick) = self.last_tick {
-            if tick > last_tick { tick } else { tick + 255 }
+        let lost_packets: i32 = match self.last_tick {
+            Some(last_tick) if !is_duplicate => if tick > last_tick { tick } else { tick + 255 }
                 .saturating_sub(last_tick)
-                .saturating_sub(1)
-        } else {
-            0
+                .saturating_sub(1),
+            _ => 0,
         };
 
         let sample_count: i32 = (raw_packet_payload.len() as i32)
@@ -67,13 +131,25 @@ impl Decoder {
 
         self.last_tick = Some(tick);
 
+        // A duplicate packet carries the same samples as the previous one, so it gets
+        // the same timestamps and doesn't advance the clock.
+        let sample_interval = estimate_sample_interval(delay_byte);
+        let packet_start = if is_duplicate {
+            self.last_packet_start
+        } else {
+            let start = self.clock + (lost_packets * sample_count) as f64 * sample_interval;
+            self.clock = start + sample_count as f64 * sample_interval;
+            self.last_packet_start = start;
+            start
+        };
+
         let mut samples: Vec<Vec<u8>> = (0..self.channel_count)
             .map(|channel_index| {
                 raw_packet_payload
                     .iter()
                     .skip((firmware::PROTOCOL_HEADER_LEN + channel_index) as usize)
                     .step_by(self.channel_count as usize)
-                    .cloned()
+                    .copied()
                     .collect()
             })
             .collect();
@@ -85,29 +161,228 @@ impl Decoder {
                 _ => false,
             };
             if enable {
-                let mut repeated = Vec::<u8>::new();
-                for _ in 0..sample_count {
-                    repeated.push(*value);
-                }
-                samples.push(repeated);
+                samples.push(vec![*value; sample_count as usize]);
             } else {
-                // TODO: can this be written more efficiently?
-                samples.push(std::iter::repeat(0).take(sample_count as usize).collect());
+                samples.push(vec![0; sample_count as usize]);
             }
         }
 
+        let filled_samples = match self.gap_policy {
+            GapPolicy::Leave => 0,
+            _ => lost_packets * sample_count,
+        };
+        if filled_samples > 0 {
+            for (channel_index, channel) in samples.iter_mut().enumerate() {
+                let before = self
+                    .last_samples
+                    .as_ref()
+                    .and_then(|last| last.get(channel_index))
+                    .copied();
+                let after = channel.first().copied().unwrap_or(GAP_SENTINEL);
+                let fill = self.gap_policy.fill(before, after, filled_samples as usize);
+                channel.splice(0..0, fill);
+            }
+        }
+        if !is_duplicate && sample_count > 0 {
+            self.last_samples = Some(samples.iter().filter_map(|c| c.last()).copied().collect());
+        }
+
+        let first_timestamp = packet_start - filled_samples as f64 * sample_interval;
+        let timestamps: Vec<f64> = (0..filled_samples + sample_count)
+            .map(|i| first_timestamp + i as f64 * sample_interval)
+            .collect();
+
         let extra_channels_count = gyroscope_accelerometer.len();
 
-        return Ok(Packet {
+        Ok(Packet {
             channel_count: self.channel_count + extra_channels_count as i32,
             tick,
             min_sampling_delay,
             max_sampling_delay,
             sample_count,
             samples,
+            filled_samples,
             is_duplicate,
             lost_packets,
-        });
+            sample_interval,
+            timestamps,
+        })
+    }
+
+    /// Forget the last seen tick, e.g. after the tick counter jumped to a value that made no
+    /// sense, or after reconnecting to the device, which restarts the firmware's tick counter.
+    /// The clock keeps running, so timestamps stay monotonic.
+    pub fn reset(&mut self) {
+        self.last_tick = None;
+        self.last_samples = None;
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        ChannelLayout::new(self.channel_count as usize)
+    }
+
+    pub fn set_gap_policy(&mut self, gap_policy: GapPolicy) {
+        self.gap_policy = gap_policy;
+    }
+
+    /// Advance the clock by the given number of seconds, e.g. to account for the time
+    /// during which no packets could be received at all.
+    pub fn skip(&mut self, seconds: f64) {
+        self.clock += seconds.max(0.0);
+    }
+
+    /// The estimated time of the next sample, in seconds since the first decoded packet
+    pub fn clock(&self) -> f64 {
+        self.clock
+    }
+}
+
+impl ChannelLayout {
+    pub fn new(emg_channels: usize) -> Self {
+        Self {
+            emg_channels,
+            devices: 1,
+        }
+    }
+
+    /// The layout of the rows of several devices side by side.  This only works if all
+    /// devices have the same number of EMG channels.
+    pub fn merge(layouts: &[ChannelLayout]) -> Result<Self, Error> {
+        let first = layouts.first().copied().unwrap_or_default();
+        let mut devices = 0;
+        for layout in layouts {
+            if layout.emg_channels != first.emg_channels {
+                return Err(Error::ChannelMismatch {
+                    expected: first.emg_channels,
+                    found: layout.emg_channels,
+                });
+            }
+            devices += layout.devices;
+        }
+        Ok(Self {
+            emg_channels: first.emg_channels,
+            devices,
+        })
+    }
+
+    /// The number of columns that belong to each device
+    pub fn device_channels(&self) -> usize {
+        self.emg_channels + firmware::IMU_CHANNELS as usize
+    }
+
+    pub fn total_channels(&self) -> usize {
+        self.device_channels() * self.devices
+    }
+
+    /// Whether the given column holds an EMG signal rather than an IMU signal
+    pub fn is_emg(&self, channel: usize) -> bool {
+        channel % self.device_channels() < self.emg_channels
+    }
+
+    /// Whether the given column holds a gyroscope signal, see also is_emg
+    pub fn is_gyroscope(&self, channel: usize) -> bool {
+        let column = channel % self.device_channels();
+        column >= self.emg_channels && column < self.emg_channels + 3
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        let emg = (1..=self.emg_channels).map(|i| format!("EMG{i}"));
+        let gyro = (1..=3).map(|i| format!("Gyro{i}"));
+        let accel = (1..=3).map(|i| format!("Accel{i}"));
+        let names: Vec<String> = emg.chain(gyro).chain(accel).collect();
+        if self.devices == 1 {
+            return names;
+        }
+        (1..=self.devices)
+            .flat_map(|device| names.iter().map(move |name| format!("{device}:{name}")))
+            .collect()
+    }
+}
+
+impl Default for ChannelLayout {
+    fn default() -> Self {
+        Self::new(firmware::DEFAULT_CHANNEL_COUNT as usize)
+    }
+}
+
+impl GapPolicy {
+    /// Generates `count` samples of one channel to bridge the gap between the values
+    /// `before` and `after`.  `before` is None if there was no previous sample.
+    pub fn fill(&self, before: Option<u8>, after: u8, count: usize) -> Vec<u8> {
+        match (self, before) {
+            (GapPolicy::Leave, _) => vec![],
+            (GapPolicy::Sentinel, _) => vec![GAP_SENTINEL; count],
+            (GapPolicy::RepeatLast, before) => vec![before.unwrap_or(after); count],
+            (GapPolicy::Interpolate, None) => vec![after; count],
+            (GapPolicy::Interpolate, Some(before)) => (1..=count)
+                .map(|i| {
+                    let fraction = i as f64 / (count + 1) as f64;
+                    (before as f64 + (after as f64 - before as f64) * fraction).round() as u8
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Packet {
+    /// The time that passed between the end of the previous packet and the end of this one,
+    /// including any lost packets.
+    pub fn duration(&self) -> f64 {
+        if self.is_duplicate {
+            0.0
+        } else {
+            (self.lost_packets + 1) as f64 * self.sample_count as f64 * self.sample_interval
+        }
+    }
+
+    /// How many seconds of signal the lost packets before this one held
+    pub fn lost_duration(&self) -> f64 {
+        (self.lost_packets * self.sample_count) as f64 * self.sample_interval
+    }
+}
+
+impl Encoder {
+    pub fn new(channel_count: i32) -> Encoder {
+        Self { channel_count }
+    }
+
+    /// Encodes one payload.  The sampling delays are given in microseconds, like the values
+    /// in Packet, and `samples` has the layout samples[channel][timestep] without the
+    /// gyroscope/accelerometer channels, which are passed in `imu` instead.
+    pub fn encode_packet(
+        &self,
+        tick: u8,
+        min_sampling_delay: f64,
+        max_sampling_delay: f64,
+        imu: [u8; 6],
+        samples: &[Vec<u8>],
+    ) -> Result<Vec<u8>, EncodeError> {
+        if samples.len() != self.channel_count as usize {
+            return Err(EncodeError::ChannelCount {
+                expected: self.channel_count,
+                found: samples.len(),
+            });
+        }
+        if tick == 0 {
+            return Err(EncodeError::InvalidTick(tick));
+        }
+        let sample_count = samples.first().map_or(0, |channel| channel.len());
+        if samples.iter().any(|channel| channel.len() != sample_count) {
+            return Err(EncodeError::UnequalSampleCounts);
+        }
+
+        let mut payload = Vec::with_capacity(
+            firmware::PROTOCOL_HEADER_LEN as usize + sample_count * samples.len(),
+        );
+        payload.push(tick);
+        payload.push(compress_delay(min_sampling_delay, max_sampling_delay));
+        payload.extend_from_slice(&imu);
+        for timestep in 0..sample_count {
+            for channel in samples {
+                payload.push(channel[timestep]);
+            }
+        }
+        Ok(payload)
     }
 }
 
@@ -117,10 +392,10 @@ impl Decoder {
 fn decompress_delay(delay_byte: u8) -> (f64, f64) {
     let min_delay = (delay_byte & 0xf0) >> 4;
     let max_delay = delay_byte & 0x0f;
-    return (
+    (
         decompress_delay_4bit(min_delay),
         decompress_delay_4bit(max_delay),
-    );
+    )
 }
 
 #[inline]
@@ -128,6 +403,56 @@ fn decompress_delay_4bit(delay_4bit: u8) -> f64 {
     ((delay_4bit as f64 - firmware::SAMPLE_DELAY_PARAM_A) / firmware::SAMPLE_DELAY_PARAM_B).exp()
 }
 
+/// Estimates the time between two samples in seconds.  Since the delays are compressed
+/// logarithmically, the geometric mean of the minimum and maximum delay is a fair guess.
+/// Without metrics from the firmware, we assume the nominal sample rate.
+fn estimate_sample_interval(delay_byte: u8) -> f64 {
+    if delay_byte == firmware::NO_METRICS_DELAY_BYTE || delay_byte & 0x0f == 0 {
+        return 1.0 / firmware::SAMPLE_RATE;
+    }
+    let (min_delay, max_delay) = decompress_delay(delay_byte);
+    (min_delay * max_delay).sqrt() / 1_000_000.0
+}
+
+/// Estimates the sample rate in Hz from the timestamps of decoded samples.  The median
+/// interval is used, so gaps and reconnections don't distort the estimate.
+pub fn estimate_sample_rate(timestamps: &[f64]) -> f64 {
+    let mut intervals: Vec<f64> = timestamps
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|interval| *interval > 0.0)
+        .collect();
+    if intervals.is_empty() {
+        return firmware::SAMPLE_RATE;
+    }
+    let middle = intervals.len() / 2;
+    let (_, median, _) = intervals.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+    1.0 / *median
+}
+
+/// Finds the delay byte from which the decoder estimates a sample interval closest to the
+/// given one, in seconds.  For re-encoding decoded samples without distorting their timing.
+pub fn delay_byte_for_interval(interval: f64) -> u8 {
+    (0..=u8::MAX)
+        .min_by(|a, b| {
+            let error = |byte: u8| (estimate_sample_interval(byte) - interval).abs();
+            error(*a).total_cmp(&error(*b))
+        })
+        .unwrap_or(firmware::NO_METRICS_DELAY_BYTE)
+}
+
+/// The inverse of decompress_delay, behaving like the COMPRESS_DELAY macro of the firmware.
+pub fn compress_delay(min_delay: f64, max_delay: f64) -> u8 {
+    (compress_delay_4bit(min_delay) << 4) | compress_delay_4bit(max_delay)
+}
+
+#[inline]
+fn compress_delay_4bit(delay: f64) -> u8 {
+    (firmware::SAMPLE_DELAY_PARAM_A + firmware::SAMPLE_DELAY_PARAM_B * delay.ln())
+        .round()
+        .clamp(1.0, 15.0) as u8
+}
+
 #[test]
 fn test_decoding() {
     let channel_count = 8;
@@ -160,14 +485,14 @@ fn test_decoding() {
         124, 205, 153, 106, 125, 136, 103, 127,
     ];
 
-    let packet = decoder.decode_packet(packet_data_1);
+    let packet = decoder.decode_packet(packet_data_1, true, true);
     assert!(packet.is_ok());
     let packet = packet.unwrap();
 
-    assert_eq!(packet.channel_count, channel_count);
+    assert_eq!(packet.channel_count, channel_count + 6); // + gyroscope/accelerometer
     assert_eq!(packet.tick, 45);
     assert_eq!(packet.sample_count, 200 / channel_count);
-    assert_eq!(packet.is_duplicate, false);
+    assert!(!packet.is_duplicate);
     approx_eq::assert_approx_eq!(packet.min_sampling_delay, 595.779, 1e-3);
     approx_eq::assert_approx_eq!(packet.max_sampling_delay, 4728.708, 1e-3);
     assert_eq!(packet.lost_packets, 0);
@@ -185,9 +510,188 @@ fn test_decoding() {
             156, 132, 145, 133, 133, 143, 147, 133
         ]
     );
-    let packet = decoder.decode_packet(packet_data_2);
+    let packet = decoder.decode_packet(packet_data_2, true, true);
     assert!(packet.is_ok());
     let packet = packet.unwrap();
     assert_eq!(packet.tick, 47);
     assert_eq!(packet.lost_packets, 1); // packet 46 was missing
 }
+
+#[test]
+fn test_timestamps() {
+    let mut decoder = Decoder::new(1);
+    let encoder = Encoder::new(1);
+    let imu = [128; 6];
+    let samples = vec![vec![127; 25]];
+    let mut decode = |tick: u8| {
+        let payload = encoder
+            .encode_packet(tick, 2000.0, 2000.0, imu, &samples)
+            .unwrap();
+        decoder.decode_packet(payload, true, true).unwrap()
+    };
+
+    let first = decode(1);
+    let interval = first.sample_interval;
+    let rate = estimate_sample_rate(&first.timestamps);
+    approx_eq::assert_approx_eq!(rate, 1.0 / interval, 1e-6);
+    // Minimum and maximum delay are equal, so their geometric mean is exact
+    approx_eq::assert_approx_eq!(interval, first.min_sampling_delay / 1_000_000.0);
+    let byte = delay_byte_for_interval(interval);
+    approx_eq::assert_approx_eq!(estimate_sample_interval(byte), interval);
+    let byte = delay_byte_for_interval(1.0 / firmware::SAMPLE_RATE);
+    assert_eq!(estimate_sample_interval(byte), 1.0 / firmware::SAMPLE_RATE);
+    assert_eq!(first.timestamps.len(), 25);
+    assert_eq!(first.timestamps[0], 0.0);
+    approx_eq::assert_approx_eq!(first.timestamps[24], 24.0 * interval);
+
+    let duplicate = decode(1);
+    assert!(duplicate.is_duplicate);
+    assert_eq!(duplicate.lost_packets, 0);
+    assert_eq!(duplicate.timestamps, first.timestamps);
+
+    // Packets 2 and 3 are missing, so the clock should skip 50 samples
+    let next = decode(4);
+    assert_eq!(next.lost_packets, 2);
+    approx_eq::assert_approx_eq!(next.timestamps[0], 75.0 * interval);
+    approx_eq::assert_approx_eq!(next.duration(), 75.0 * interval);
+
+    // The tick counter wraps around from 255 to 1
+    decode(255);
+    let wrapped = decode(1);
+    assert_eq!(wrapped.lost_packets, 0);
+}
+
+#[test]
+fn test_gap_policy() {
+    let encoder = Encoder::new(1);
+    let imu = [128; 6];
+    let decode_pair = |gap_policy: GapPolicy| {
+        let mut decoder = Decoder::new(1);
+        decoder.set_gap_policy(gap_policy);
+        let mut decode = |tick: u8, value: u8| {
+            let samples = vec![vec![value; 4]];
+            let payload = encoder
+                .encode_packet(tick, 2000.0, 2000.0, imu, &samples)
+                .unwrap();
+            decoder.decode_packet(payload, true, true).unwrap()
+        };
+        let first = decode(1, 10);
+        let second = decode(3, 20); // packet 2 is lost
+        (first, second)
+    };
+
+    let (first, left) = decode_pair(GapPolicy::Leave);
+    assert_eq!(left.filled_samples, 0);
+    assert_eq!(left.samples[0], vec![20; 4]);
+    assert_eq!(left.timestamps.len(), 4);
+
+    let (_, sentinel) = decode_pair(GapPolicy::Sentinel);
+    assert_eq!(sentinel.filled_samples, 4);
+    assert_eq!(sentinel.sample_count, 4);
+    assert_eq!(sentinel.samples[0], vec![0, 0, 0, 0, 20, 20, 20, 20]);
+    assert_eq!(sentinel.samples[6].len(), 8);
+
+    let (_, repeated) = decode_pair(GapPolicy::RepeatLast);
+    assert_eq!(repeated.samples[0], vec![10, 10, 10, 10, 20, 20, 20, 20]);
+
+    let (_, interpolated) = decode_pair(GapPolicy::Interpolate);
+    assert_eq!(
+        interpolated.samples[0],
+        vec![12, 14, 16, 18, 20, 20, 20, 20]
+    );
+
+    // The filled timestamps continue seamlessly from the previous packet
+    let interval = interpolated.sample_interval;
+    approx_eq::assert_approx_eq!(interpolated.timestamps[0], first.timestamps[3] + interval);
+    assert_eq!(interpolated.timestamps[4], left.timestamps[0]);
+}
+
+#[test]
+fn test_decoding_errors() {
+    let mut decoder = Decoder::new(8);
+    assert_eq!(
+        decoder.decode_packet(vec![48], true, true).unwrap_err(),
+        DecodeError::TruncatedHeader { len: 1 }
+    );
+    assert_eq!(
+        decoder
+            .decode_packet(vec![1, 21, 127, 124], true, true)
+            .unwrap_err(),
+        DecodeError::MissingImu { len: 4 }
+    );
+    assert_eq!(
+        decoder
+            .decode_packet(vec![1; 8 + 12], true, true)
+            .unwrap_err(),
+        DecodeError::PayloadLength {
+            sample_bytes: 12,
+            channel_count: 8
+        }
+    );
+    assert!(decoder.decode_packet(vec![1; 8 + 16], true, true).is_ok());
+    assert_eq!(
+        decoder
+            .decode_packet(vec![0; 8 + 16], true, true)
+            .unwrap_err(),
+        DecodeError::ImpossibleTickJump {
+            last_tick: Some(1),
+            tick: 0
+        }
+    );
+}
+
+#[test]
+fn test_encoding_roundtrip() {
+    let channel_count = 8;
+    let mut decoder = Decoder::new(channel_count);
+    let encoder = Encoder::new(channel_count);
+    let imu = [127, 124, 126, 175, 122, 239];
+    let samples: Vec<Vec<u8>> = (0..channel_count as u8)
+        .map(|channel| (0..25).map(|i| 1 + channel * 25 + i).collect())
+        .collect();
+
+    let payload = encoder
+        .encode_packet(45, 595.779, 4728.708, imu, &samples)
+        .unwrap();
+    assert_eq!(payload[..8], [45, 21, 127, 124, 126, 175, 122, 239]);
+
+    let packet = decoder.decode_packet(payload.clone(), true, true).unwrap();
+    assert_eq!(packet.tick, 45);
+    assert_eq!(packet.sample_count, 25);
+    assert_eq!(packet.samples[..8], samples[..]);
+    assert_eq!(packet.samples[8], vec![127; 25]);
+    assert_eq!(packet.samples[13], vec![239; 25]);
+    assert_eq!(
+        encoder
+            .encode_packet(
+                packet.tick as u8,
+                packet.min_sampling_delay,
+                packet.max_sampling_delay,
+                imu,
+                &packet.samples[..8],
+            )
+            .unwrap(),
+        payload
+    );
+
+    // Every delay that the firmware can send survives decompression and compression
+    for delay_byte in 0x11..=0xff {
+        if delay_byte & 0x0f == 0 {
+            continue;
+        }
+        let (min_delay, max_delay) = decompress_delay(delay_byte);
+        assert_eq!(compress_delay(min_delay, max_delay), delay_byte);
+    }
+
+    assert_eq!(
+        encoder.encode_packet(1, 2000.0, 2000.0, imu, &samples[..7]),
+        Err(EncodeError::ChannelCount {
+            expected: 8,
+            found: 7
+        })
+    );
+    assert_eq!(
+        encoder.encode_packet(0, 2000.0, 2000.0, imu, &samples),
+        Err(EncodeError::InvalidTick(0))
+    );
+}
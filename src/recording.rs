@@ -0,0 +1,710 @@
+// Records sessions without the GUI, e.g. for long unattended data collection.
+//
+// A recording is a session file, see the session module, that is written incrementally
+// and flushed after every packet, so a crash loses at most the packet that was being
+// written.  Labels are read from stdin, one per line; an empty line or "-" clears the
+// label.  Every sample that is recorded while a label is active becomes a datapoint.
+//
+// Datasets can also be exported to CSV files and loaded from them.  Lines starting
+// with '#' describe the session or mark an outage of the signal:
+//
+//     # PsyLink recording
+//     # source: ble:address=AA:BB:CC:DD:EE:FF
+//     # started: 1760000000.000
+//     # layout: 1 device with 8 EMG channels
+//     index,timestamp,EMG1,...,Accel3,label
+//     0,0.0000,127,...,130,
+//     # gap: 0.520s
+//     1,0.5220,128,...,131,fist
+//
+// The label column holds the label of the datapoint at that sample, if any.  When a CSV
+// file is loaded as a calibration::PsyLinkDataset, every labeled sample becomes a
+// datapoint.  Numeric labels are used as they are, with 0 meaning "no action".  Other
+// labels become actions in the order in which they first appear, unless comments like
+// "# action 1: fist" assign them a number, as written by write_csv.  The channel columns
+// are found by their names, so they may be reordered, e.g. by a spreadsheet.
+//
+// While inferring, the predictions of the model can be logged to another CSV file, see
+// PredictionLog.
+
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+/// The settings of `psylink record`
+#[derive(Clone, Debug, Default)]
+pub struct RecordOptions {
+    pub out: PathBuf,
+    pub duration: Option<f64>, // Stop after this many seconds
+}
+
+/// Writes samples to a recording as they arrive
+pub struct Recorder<W: Write> {
+    dataset: calibration::PsyLinkDataset, // Everything that was recorded so far
+    stream: calibration::SessionStream<W>,
+    pub label: Option<String>,
+}
+
+impl Recorder<BufWriter<File>> {
+    pub fn create(
+        path: &PathBuf,
+        source: &str,
+        layout: protocol::ChannelLayout,
+        devices: Vec<session::DeviceInfo>,
+    ) -> Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), source, layout, devices)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Starts a recording by writing the layout, the devices and where the signals come from
+    pub fn new(
+        out: W,
+        source: &str,
+        layout: protocol::ChannelLayout,
+        devices: Vec<session::DeviceInfo>,
+    ) -> Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64());
+        let mut dataset = calibration::PsyLinkDataset {
+            layout,
+            ..Default::default()
+        };
+        dataset.info.devices = devices;
+        dataset.info.set_metadata("source", source.into());
+        dataset
+            .info
+            .set_metadata("started", format!("{started:.3}"));
+        dataset.info.action_names = vec![Action::None.to_string()];
+        let stream = calibration::SessionStream::new(out, &dataset)?;
+        Ok(Self {
+            dataset,
+            stream,
+            label: None,
+        })
+    }
+
+    pub fn layout(&self) -> protocol::ChannelLayout {
+        self.dataset.layout
+    }
+
+    /// The number of samples recorded so far
+    pub fn samples(&self) -> usize {
+        self.dataset.all_packets.len()
+    }
+
+    pub fn write_packet(&mut self, packet: &protocol::Packet) -> Result<()> {
+        let expected = self.layout().total_channels();
+        if packet.samples.len() != expected {
+            return Err(Error::ChannelMismatch {
+                expected,
+                found: packet.samples.len(),
+            });
+        }
+        let label = match &self.label {
+            Some(name) => Some(self.dataset.info.label_for(name)?),
+            None => None,
+        };
+        let rows = transpose_vec(packet.samples.clone());
+        for (values, &timestamp) in rows.into_iter().zip(&packet.timestamps) {
+            if let Some(label) = label {
+                self.dataset.datapoints.push(calibration::Datapoint {
+                    packet_index: self.samples(),
+                    label,
+                });
+            }
+            self.dataset.all_packets.push(values);
+            self.dataset.timestamps.push(timestamp);
+        }
+        self.stream.update(&self.dataset)
+    }
+
+    /// Marks that the given number of seconds of signal are missing before the next sample
+    pub fn write_gap(&mut self, duration: f64) -> Result<()> {
+        self.dataset.gaps.push(calibration::Gap {
+            packet_index: self.samples(),
+            inserted: 0,
+            duration,
+        });
+        self.stream.update(&self.dataset)
+    }
+
+    /// Marks a gap within the next packet, at the packet index of the gap.  It's written
+    /// along with that packet.
+    pub fn add_gap(&mut self, gap: calibration::Gap) {
+        self.dataset.gaps.push(gap);
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream.into_inner()
+    }
+}
+
+/// Writes the predictions of a model while inferring, one line per prediction:
+///
+/// ```text
+/// time,timestamp,packet_index,class,action
+/// 1760000000.123,12.3440,6172,3,Sound
+/// ```
+///
+/// The time is the wall clock in seconds since 1970, and the timestamp and packet index
+/// tell the sample of the dataset at which the model made the prediction.
+pub struct PredictionLog<W: Write> {
+    out: W,
+}
+
+impl PredictionLog<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PredictionLog<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        writeln!(out, "# PsyLink predictions")?;
+        writeln!(out, "time,timestamp,packet_index,class,action")?;
+        out.flush()?;
+        Ok(Self { out })
+    }
+
+    pub fn write(
+        &mut self,
+        timestamp: f64,
+        packet_index: usize,
+        class: i32,
+        action: &str,
+    ) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64());
+        writeln!(
+            self.out,
+            "{time:.3},{timestamp:.4},{packet_index},{class},{}",
+            csv_field(action)
+        )?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the comments and the column names at the top of a recording
+fn write_header<W: Write>(
+    out: &mut W,
+    source: &str,
+    started: &str,
+    layout: protocol::ChannelLayout,
+    action_names: &[String],
+) -> Result<()> {
+    writeln!(out, "# PsyLink recording")?;
+    writeln!(out, "# source: {source}")?;
+    writeln!(out, "# started: {started}")?;
+    writeln!(
+        out,
+        "# layout: {} device{} with {} EMG channels",
+        layout.devices,
+        if layout.devices == 1 { "" } else { "s" },
+        layout.emg_channels
+    )?;
+    for (label, name) in action_names.iter().enumerate() {
+        writeln!(out, "# action {label}: {name}")?;
+    }
+    writeln!(
+        out,
+        "index,timestamp,{},label",
+        layout.channel_names().join(",")
+    )?;
+    Ok(())
+}
+
+/// Exports a dataset as a recording, see the top of this file for the format
+pub fn write_csv<W: Write>(dataset: &calibration::PsyLinkDataset, mut out: W) -> Result<()> {
+    let info = &dataset.info;
+    let source = info.get_metadata("source").unwrap_or("dataset");
+    let started = info.get_metadata("started").unwrap_or("0.000");
+    write_header(
+        &mut out,
+        source,
+        started,
+        dataset.layout,
+        &info.action_names,
+    )?;
+
+    let mut labels: Vec<Option<u8>> = vec![None; dataset.all_packets.len()];
+    for datapoint in &dataset.datapoints {
+        if let Some(label) = labels.get_mut(datapoint.packet_index) {
+            *label = Some(datapoint.label);
+        }
+    }
+    let mut gaps = dataset.gaps.iter().peekable();
+    for (index, (packet, label)) in dataset.all_packets.iter().zip(labels).enumerate() {
+        while let Some(gap) = gaps.next_if(|gap| gap.packet_index <= index) {
+            write_gap_comment(&mut out, gap)?;
+        }
+        let timestamp = dataset
+            .timestamps
+            .get(index)
+            .copied()
+            .unwrap_or(index as f64 / firmware::SAMPLE_RATE);
+        let values: Vec<String> = packet.iter().map(u8::to_string).collect();
+        let label = match label {
+            Some(label) => match info.action_names.get(label as usize) {
+                Some(name) => csv_field(name),
+                None => label.to_string(),
+            },
+            None => String::new(),
+        };
+        writeln!(out, "{index},{timestamp:.4},{},{label}", values.join(","))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_gap_comment<W: Write>(out: &mut W, gap: &calibration::Gap) -> Result<()> {
+    match gap.inserted {
+        0 => writeln!(out, "# gap: {:.3}s", gap.duration)?,
+        inserted => writeln!(out, "# gap: {:.3}s, {inserted} inserted", gap.duration)?,
+    }
+    Ok(())
+}
+
+pub fn save_csv<P: AsRef<Path>>(dataset: &calibration::PsyLinkDataset, path: P) -> Result<()> {
+    write_csv(dataset, BufWriter::new(File::create(path)?))
+}
+
+/// Loads a recording as a dataset for calibration
+pub fn load_csv<P: AsRef<Path>>(path: P) -> Result<calibration::PsyLinkDataset> {
+    read_csv(BufReader::new(File::open(path)?))
+}
+
+/// Reads a recording, see the top of this file for the format
+pub fn read_csv<R: BufRead>(input: R) -> Result<calibration::PsyLinkDataset> {
+    let mut dataset = calibration::PsyLinkDataset::default();
+    dataset.info.action_names = vec![Action::None.to_string()];
+    let mut layout = None;
+    let mut columns: Option<Columns> = None;
+    let mut gap = None; // Seconds missing before the next sample, and inserted samples
+
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let invalid = |what: &str| Error::Parse(format!("Line {}: {what}", number + 1));
+        if let Some(comment) = line.strip_prefix('#') {
+            let comment = comment.trim();
+            if let Some(text) = comment.strip_prefix("layout:") {
+                layout = Some(parse_layout(text).ok_or_else(|| invalid("bad layout"))?);
+            } else if let Some(text) = comment.strip_prefix("source:") {
+                dataset.info.set_metadata("source", text.trim().into());
+            } else if let Some(text) = comment.strip_prefix("started:") {
+                dataset.info.set_metadata("started", text.trim().into());
+            } else if let Some(text) = comment.strip_prefix("gap:") {
+                let (seconds, inserted) = parse_gap(text).ok_or_else(|| invalid("bad gap"))?;
+                let (total, total_inserted) = gap.unwrap_or((0.0, 0));
+                gap = Some((total + seconds, total_inserted + inserted));
+            } else if let Some((label, name)) = comment
+                .strip_prefix("action ")
+                .and_then(|text| text.split_once(':'))
+            {
+                let label: usize = label.trim().parse().map_err(|_| invalid("bad action"))?;
+                let names = &mut dataset.info.action_names;
+                if names.len() <= label {
+                    names.resize(label + 1, String::new());
+                }
+                names[label] = name.trim().into();
+            }
+            continue;
+        }
+        let fields = split_csv_line(&line);
+        let Some(columns) = &columns else {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let found = Columns::find(&fields, layout).ok_or_else(|| invalid("bad header"))?;
+            dataset.layout = found.layout;
+            columns = Some(found);
+            continue;
+        };
+
+        let field = |column: usize| fields.get(column).map(|field| field.trim());
+        let timestamp = field(columns.timestamp)
+            .and_then(|field| field.parse::<f64>().ok())
+            .ok_or_else(|| invalid("bad timestamp"))?;
+        let values = columns
+            .channels
+            .iter()
+            .map(|column| field(*column).and_then(|field| field.parse::<u8>().ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| invalid("bad sample value"))?;
+        let label = columns.label.and_then(field).unwrap_or_default();
+
+        let index = dataset.all_packets.len();
+        if let Some((duration, inserted)) = gap.take() {
+            dataset.gaps.push(calibration::Gap {
+                packet_index: index,
+                inserted,
+                duration,
+            });
+        }
+        if !label.is_empty() {
+            let label = match label.parse::<u8>() {
+                Ok(number) => number,
+                Err(_) => dataset.info.label_for(label)?,
+            };
+            dataset.datapoints.push(calibration::Datapoint {
+                packet_index: index,
+                label,
+            });
+        }
+        dataset.all_packets.push(values);
+        dataset.timestamps.push(timestamp);
+    }
+
+    if columns.is_none() {
+        return Err(Error::Parse("The recording has no header line".into()));
+    }
+    // Recordings with numeric labels only don't know the names of their actions
+    if dataset.info.action_names.len() == 1 {
+        dataset.info.action_names.clear();
+    }
+    Ok(dataset)
+}
+
+/// Where the columns of a recording are
+struct Columns {
+    layout: protocol::ChannelLayout,
+    timestamp: usize,
+    channels: Vec<usize>, // channels[i] = the column of channel i of the layout
+    label: Option<usize>,
+}
+
+impl Columns {
+    /// Finds the columns by their names in the header line.  Without a layout comment,
+    /// the layout is guessed from the channel names.
+    fn find(header: &[String], layout: Option<protocol::ChannelLayout>) -> Option<Self> {
+        let header: Vec<&str> = header.iter().map(|column| column.trim()).collect();
+        let position = |name: &str| header.iter().position(|column| *column == name);
+        let layout = layout.unwrap_or_else(|| {
+            let devices = header.iter().filter(|c| c.ends_with("Gyro1")).count();
+            let emg = header.iter().filter(|c| c.contains("EMG")).count();
+            protocol::ChannelLayout {
+                emg_channels: emg / devices.max(1),
+                devices: devices.max(1),
+            }
+        });
+        let channels = layout
+            .channel_names()
+            .iter()
+            .map(|name| position(name))
+            .collect::<Option<Vec<usize>>>()?;
+        Some(Self {
+            layout,
+            timestamp: position("timestamp")?,
+            channels,
+            label: position("label"),
+        })
+    }
+}
+
+/// Splits a line of CSV into its fields, removing the quotes of quoted fields
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Parses the "0.520s" or "0.520s, 10 inserted" of the gap comment
+fn parse_gap(text: &str) -> Option<(f64, usize)> {
+    let (seconds, inserted) = match text.split_once(',') {
+        Some((seconds, inserted)) => {
+            let inserted = inserted.trim().strip_suffix("inserted")?;
+            (seconds, inserted.trim().parse().ok()?)
+        }
+        None => (text, 0),
+    };
+    Some((seconds.trim().strip_suffix('s')?.parse().ok()?, inserted))
+}
+
+/// Quotes a CSV field if necessary
+pub fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.into()
+    }
+}
+
+/// Parses the "1 device with 8 EMG channels" of the layout comment
+fn parse_layout(text: &str) -> Option<protocol::ChannelLayout> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words[..] {
+        [devices, _, "with", emg_channels, "EMG", "channels"] => Some(protocol::ChannelLayout {
+            emg_channels: emg_channels.parse().ok()?,
+            devices: devices.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Reads labels from stdin in the background.  The receiver gets None for "no label".
+fn read_labels() -> mpsc::Receiver<Option<String>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let label = match line.trim() {
+                "" | "-" => None,
+                label => Some(label.to_string()),
+            };
+            if sender.send(label).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Records the signal sources of `app` until the duration is over, the user presses
+/// Ctrl+C, or all sources are gone for good.  Returns the number of recorded samples.
+pub async fn record(app: &App, options: &RecordOptions) -> Result<usize> {
+    let mut acquisition = acquisition::MultiAcquisition::new(app, &app.source);
+    let devices = acquisition.devices();
+    let mut finished = 0;
+    let mut recorder: Option<Recorder<BufWriter<File>>> = None;
+    let mut device_infos = vec![session::DeviceInfo::default(); devices];
+    let mut deadline = None;
+    let labels = read_labels();
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
+    eprintln!("Connecting to {}...", app.source);
+
+    let result = loop {
+        let event = tokio::select! {
+            event = acquisition.next_event(true, true) => event,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break Ok(()),
+            _ = &mut interrupted => break Ok(()),
+        };
+        let written = match event {
+            acquisition::MultiEvent::Ready(layout) => match &recorder {
+                Some(recorder) if recorder.layout() != layout => {
+                    break Err(Error::ChannelMismatch {
+                        expected: recorder.layout().total_channels(),
+                        found: layout.total_channels(),
+                    })
+                }
+                Some(_) => Ok(()),
+                None => {
+                    eprintln!("Recording to {}", options.out.display());
+                    let source = app.source.to_string();
+                    deadline = options
+                        .duration
+                        .map(|seconds| Instant::now() + Duration::from_secs_f64(seconds));
+                    Recorder::create(&options.out, &source, layout, device_infos.clone()).map(
+                        |new| {
+                            recorder = Some(new);
+                        },
+                    )
+                }
+            },
+            acquisition::MultiEvent::Packet(packet) => match &mut recorder {
+                Some(recorder) if !packet.is_duplicate => {
+                    while let Ok(label) = labels.try_recv() {
+                        eprintln!("Label: {}", label.as_deref().unwrap_or("none"));
+                        recorder.label = label;
+                    }
+                    recorder.write_packet(&packet)
+                }
+                _ => Ok(()),
+            },
+            acquisition::MultiEvent::Gap(gap) => {
+                if let Some(recorder) = &mut recorder {
+                    recorder.add_gap(calibration::Gap {
+                        packet_index: recorder.samples() + gap.offset,
+                        inserted: gap.inserted,
+                        duration: gap.duration,
+                    });
+                }
+                Ok(())
+            }
+            acquisition::MultiEvent::Device(device, event) => {
+                let prefix = match devices {
+                    1 => String::new(),
+                    _ => format!("Device {}: ", device + 1),
+                };
+                match event {
+                    acquisition::Event::Connected {
+                        description,
+                        device: info,
+                        ..
+                    } => {
+                        eprintln!("{prefix}Connected to {description}");
+                        device_infos[device] = info;
+                        Ok(())
+                    }
+                    acquisition::Event::Reconnected {
+                        description,
+                        outage,
+                    } => {
+                        eprintln!("{prefix}Reconnected to {description} after {outage:.1}s");
+                        match &mut recorder {
+                            Some(recorder) => recorder.write_gap(outage),
+                            None => Ok(()),
+                        }
+                    }
+                    acquisition::Event::Disconnected {
+                        error, retry_in, ..
+                    } => {
+                        match retry_in {
+                            Some(delay) => eprintln!(
+                                "{prefix}Connection lost: {error}, reconnecting in {:.1}s",
+                                delay.as_secs_f32()
+                            ),
+                            None => {
+                                eprintln!("{prefix}Connection lost: {error}");
+                                finished += 1;
+                            }
+                        }
+                        if finished == devices {
+                            break Ok(());
+                        }
+                        Ok(())
+                    }
+                    acquisition::Event::DecodeFailed(err) => {
+                        eprintln!("{prefix}Failed to decode packet: {err}");
+                        Ok(())
+                    }
+                    acquisition::Event::Packet(_) => Ok(()),
+                }
+            }
+        };
+        if let Err(err) = written {
+            break Err(err);
+        }
+    };
+
+    acquisition.disconnect().await;
+    let samples = recorder.map_or(0, |recorder| recorder.samples());
+    eprintln!("Recorded {samples} samples to {}", options.out.display());
+    result.map(|_| samples)
+}
+
+#[test]
+fn test_recorder() {
+    let layout = protocol::ChannelLayout::new(2);
+    let device = session::DeviceInfo {
+        description: "Simulator".into(),
+        ..Default::default()
+    };
+    let mut recorder = Recorder::new(vec![], "sim", layout, vec![device.clone()]).unwrap();
+
+    let mut simulator = simulator::Simulator::new(2);
+    simulator.set_realtime(false);
+    let mut decoder = protocol::Decoder::new(2);
+    let mut read = || {
+        decoder
+            .decode_packet(simulator.next_payload(), true, true)
+            .unwrap()
+    };
+    recorder.write_packet(&read()).unwrap();
+    recorder.write_gap(0.5).unwrap();
+    recorder.label = Some("fist, tight".into());
+    let packet = read();
+    recorder.write_packet(&packet).unwrap();
+    assert_eq!(recorder.samples(), 50);
+
+    // Packets of a device with a different layout don't fit into this recording
+    let mut simulator = simulator::Simulator::new(3);
+    simulator.set_realtime(false);
+    let other = protocol::Decoder::new(3)
+        .decode_packet(simulator.next_payload(), true, true)
+        .unwrap();
+    assert!(recorder.write_packet(&other).is_err());
+
+    let bytes = recorder.into_inner();
+    let dataset = calibration::PsyLinkDataset::read_from(bytes.as_slice()).unwrap();
+    assert_eq!(dataset.layout, layout);
+    assert_eq!(dataset.info.devices, [device]);
+    assert_eq!(dataset.info.get_metadata("source"), Some("sim"));
+    assert!(dataset.info.get_metadata("started").is_some());
+    assert_eq!(dataset.all_packets.len(), 50);
+    assert_eq!(
+        dataset.all_packets[25],
+        packet.samples.iter().map(|c| c[0]).collect::<Vec<u8>>()
+    );
+    assert_eq!(dataset.timestamps[25], packet.timestamps[0]);
+    assert_eq!(dataset.gaps.len(), 1);
+    assert_eq!(dataset.gaps[0].packet_index, 25);
+    assert_eq!(dataset.gaps[0].duration, 0.5);
+    assert_eq!(dataset.info.action_names, ["(no action)", "fist, tight"]);
+    assert_eq!(dataset.datapoints.len(), 25);
+    assert_eq!(dataset.datapoints[0].packet_index, 25);
+    assert_eq!(dataset.datapoints[0].label, 1);
+}
+
+#[test]
+fn test_csv_export() {
+    let mut dataset = calibration::PsyLinkDataset::from_arrays(
+        &[(1, 0), (2, 1), (3, 2)],
+        &[
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            [11; 10],
+            [12; 10],
+            [13; 10],
+        ],
+    );
+    dataset.info.action_names = vec!["(no action)".into(), "Key \"w\"".into()];
+    dataset.info.set_metadata("source", "sim".into());
+    dataset
+        .info
+        .set_metadata("started", "1760000000.000".into());
+    dataset.gaps.push(calibration::Gap {
+        packet_index: 2,
+        inserted: 1,
+        duration: 0.25,
+    });
+    let mut bytes = vec![];
+    write_csv(&dataset, &mut bytes).unwrap();
+    let text = String::from_utf8(bytes).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[4], "# action 0: (no action)");
+    assert_eq!(lines[7], "0,0.0000,1,2,3,4,5,6,7,8,9,10,");
+    assert_eq!(
+        lines[8],
+        "1,0.0020,11,11,11,11,11,11,11,11,11,11,(no action)"
+    );
+    assert_eq!(lines[9], "# gap: 0.250s, 1 inserted");
+    assert!(lines[10].ends_with(",\"Key \"\"w\"\"\""));
+    assert!(lines[11].ends_with(",2"));
+    assert_eq!(read_csv(text.as_bytes()).unwrap(), dataset);
+
+    // The channel columns are found by their names
+    let moved = "index,Accel3,timestamp,EMG1,EMG2,EMG3,EMG4,Gyro1,Gyro2,Gyro3,Accel1,Accel2\n\
+                 0,10,0.5,1,2,3,4,5,6,7,8,9";
+    let dataset = read_csv(moved.as_bytes()).unwrap();
+    assert_eq!(dataset.layout, protocol::ChannelLayout::new(4));
+    assert_eq!(dataset.all_packets, [[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]]);
+    assert!(dataset.datapoints.is_empty());
+    assert!(read_csv("index,timestamp,EMG1,label\n".as_bytes()).is_err());
+
+    let mut log = PredictionLog::new(vec![]).unwrap();
+    log.write(1.5, 750, 1, "Key \"w\"").unwrap();
+    let text = String::from_utf8(log.out).unwrap();
+    let line = text.lines().nth(2).unwrap();
+    assert!(line.ends_with(",1.5000,750,1,\"Key \"\"w\"\"\""));
+}
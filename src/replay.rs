@@ -0,0 +1,340 @@
+// Plays a recorded dataset back as if it came from a PsyLink, to reproduce bugs and to
+// give demos without a device.  The samples are encoded into payloads in the firmware's
+// wire format again, so they take the same path through Acquisition, protocol::Decoder,
+// the plotter, the calibration and the inference as the signals of a real device.
+//
+// The payloads are paced by the stored timestamps, optionally sped up.  Samples that were
+// inserted for a gap are left out, and the gap becomes lost packets instead, which the
+// decoder fills according to its GapPolicy.  A recording of several devices is replayed
+// by one source per device, which MultiAcquisition merges again.
+//
+// As a signal source, a replay is written like this, with optional settings:
+//
+//     replay:/tmp/psylink_dataset.psylink
+//     replay,speed=2,loop,device=2:/tmp/psylink_dataset.psylink
+
+use crate::calibration::PsyLinkDataset;
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use tokio::time::{Duration, Instant};
+
+const SAMPLES_PER_PAYLOAD: usize = (firmware::SAMPLE_RATE / firmware::BLE_NOTIFY_RATE) as usize;
+const MAX_LOST_PAYLOADS: usize = 253; // More would wrap the tick counter around to the last tick
+
+/// What to replay and how
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayOptions {
+    pub path: PathBuf, // A session file, EDF/BDF file or CSV recording
+    pub speed: f64,    // 2.0 plays the recording twice as fast as it was recorded
+    pub looped: bool,  // Start over at the end instead of finishing
+    pub device: usize, // Which device of a recording with several devices, starting at 0
+}
+
+/// Replays the samples of one device of a dataset
+pub struct ReplaySource {
+    options: ReplayOptions,
+    dataset: PsyLinkDataset,
+    encoder: protocol::Encoder,
+    position: usize, // The next row of the dataset to send
+    tick: u8,
+    realtime: bool,
+    // When the current pass through the recording started, and its first timestamp
+    pass_start: Option<(Instant, f64)>,
+}
+
+impl ReplayOptions {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            speed: 1.0,
+            looped: false,
+            device: 0,
+        }
+    }
+
+    /// Parses the comma-separated settings between "replay" and the colon of a source
+    pub fn parse(settings: &str, path: &str) -> std::result::Result<Self, String> {
+        let mut options = Self::new(path);
+        for setting in settings.split(',').map(str::trim) {
+            match setting.split_once('=') {
+                None if setting.is_empty() => {}
+                None if setting == "loop" => options.looped = true,
+                Some(("speed", value)) => {
+                    options.speed = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|speed: &f64| speed.is_finite() && *speed > 0.0)
+                        .ok_or_else(|| format!("Invalid replay speed \"{value}\""))?;
+                }
+                Some(("device", value)) => {
+                    options.device = value
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|device| device.checked_sub(1))
+                        .ok_or_else(|| format!("Invalid device number \"{value}\""))?;
+                }
+                _ => {
+                    return Err(format!(
+                    "Unknown replay setting \"{setting}\", expected speed=FACTOR, loop or device=N"
+                ))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl fmt::Display for ReplayOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "replay")?;
+        if self.speed != 1.0 {
+            write!(f, ",speed={}", self.speed)?;
+        }
+        if self.looped {
+            write!(f, ",loop")?;
+        }
+        if self.device > 0 {
+            write!(f, ",device={}", self.device + 1)?;
+        }
+        write!(f, ":{}", self.path.display())
+    }
+}
+
+/// The signal sources that replay all devices of a recording at once
+pub fn sources(options: &ReplayOptions) -> Result<transport::SourceList> {
+    let dataset = session::load_dataset(&options.path)?;
+    let specs = (0..dataset.layout.devices)
+        .map(|device| {
+            transport::SourceSpec::Replay(ReplayOptions {
+                device,
+                ..options.clone()
+            })
+        })
+        .collect();
+    Ok(transport::SourceList(specs))
+}
+
+impl ReplaySource {
+    pub fn open(options: ReplayOptions) -> Result<Self> {
+        let dataset = session::load_dataset(&options.path)?;
+        Self::new(dataset, options)
+    }
+
+    pub fn new(dataset: PsyLinkDataset, options: ReplayOptions) -> Result<Self> {
+        let layout = dataset.layout;
+        if options.device >= layout.devices {
+            return Err(Error::Parse(format!(
+                "{} has no device {}, only {}",
+                options.path.display(),
+                options.device + 1,
+                layout.devices
+            )));
+        }
+        let inserted: usize = dataset.gaps.iter().map(|gap| gap.inserted).sum();
+        if dataset.all_packets.len() <= inserted {
+            return Err(Error::Parse(format!(
+                "{} contains no samples to replay",
+                options.path.display()
+            )));
+        }
+        Ok(Self {
+            encoder: protocol::Encoder::new(layout.emg_channels as i32),
+            options,
+            dataset,
+            position: 0,
+            tick: 0,
+            realtime: true,
+            pass_start: None,
+        })
+    }
+
+    /// When realtime is disabled, payloads are encoded as fast as they are read,
+    /// which is what tests want.  Otherwise, they follow the stored timestamps.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    pub fn description(&self) -> String {
+        let mut description = format!("replay of {}", self.options.path.display());
+        if self.dataset.layout.devices > 1 {
+            description.push_str(&format!(" (device {})", self.options.device + 1));
+        }
+        if self.options.speed != 1.0 {
+            description.push_str(&format!(" at {}x speed", self.options.speed));
+        }
+        description
+    }
+
+    pub fn channel_count(&self) -> i32 {
+        self.dataset.layout.emg_channels as i32
+    }
+
+    /// The recorded device, described as a replay
+    pub fn device_info(&self) -> session::DeviceInfo {
+        let recorded = self.dataset.info.devices.get(self.options.device);
+        session::DeviceInfo {
+            description: self.description(),
+            ..recorded.cloned().unwrap_or_default()
+        }
+    }
+
+    /// Waits until the next payload is due according to the recording, then encodes it
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        let len = self.dataset.all_packets.len();
+        let lost = loop {
+            if self.position >= len {
+                if !self.options.looped {
+                    return Err(Error::EndOfStream);
+                }
+                self.position = 0;
+                self.pass_start = None;
+            }
+            let lost = self.skip_gap();
+            if self.position < len {
+                break lost;
+            }
+        };
+        let rows = self.position..self.payload_end(self.position);
+
+        if self.realtime {
+            let timestamps = &self.dataset.timestamps;
+            let (start, first_timestamp) = *self
+                .pass_start
+                .get_or_insert_with(|| (Instant::now(), timestamps[rows.start]));
+            // The firmware sends a payload once its last sample was taken
+            let elapsed = (timestamps[rows.end - 1] - first_timestamp) / self.options.speed;
+            tokio::time::sleep_until(start + Duration::from_secs_f64(elapsed.max(0.0))).await;
+        }
+
+        // The firmware's tick counter skips 0 when it wraps around
+        self.tick = ((self.tick as usize + lost) % 255 + 1) as u8;
+        let payload = self.encode(rows.clone())?;
+        self.position = rows.end;
+        Ok(payload)
+    }
+
+    /// Skips the samples that were inserted for a gap at the current position, and
+    /// returns how many payloads the firmware would have sent in the meantime
+    fn skip_gap(&mut self) -> usize {
+        let position = self.position;
+        match self
+            .dataset
+            .gaps
+            .iter()
+            .find(|gap| gap.packet_index == position)
+        {
+            Some(gap) => {
+                self.position += gap.inserted;
+                let payloads = (gap.duration * firmware::BLE_NOTIFY_RATE).round();
+                (payloads.max(0.0) as usize).min(MAX_LOST_PAYLOADS)
+            }
+            None => 0,
+        }
+    }
+
+    /// A payload ends after SAMPLES_PER_PAYLOAD rows, or before the next gap
+    fn payload_end(&self, start: usize) -> usize {
+        let next_gap = self
+            .dataset
+            .gaps
+            .iter()
+            .map(|gap| gap.packet_index)
+            .filter(|index| *index > start)
+            .min();
+        (start + SAMPLES_PER_PAYLOAD)
+            .min(self.dataset.all_packets.len())
+            .min(next_gap.unwrap_or(usize::MAX))
+    }
+
+    fn encode(&self, rows: Range<usize>) -> Result<Vec<u8>> {
+        let layout = self.dataset.layout;
+        let first_column = self.options.device * layout.device_channels();
+        let emg_columns = first_column..first_column + layout.emg_channels;
+        let packets = &self.dataset.all_packets[rows.clone()];
+        let samples: Vec<Vec<u8>> = emg_columns
+            .map(|column| packets.iter().map(|row| row[column]).collect())
+            .collect();
+        let mut imu = [0; firmware::IMU_CHANNELS as usize];
+        let imu_columns =
+            first_column + layout.emg_channels..first_column + layout.device_channels();
+        imu.copy_from_slice(&packets[0][imu_columns]);
+
+        let timestamps = &self.dataset.timestamps[rows];
+        let interval = match timestamps {
+            [first, .., last] => (last - first) / (timestamps.len() - 1) as f64,
+            _ => 1.0 / firmware::SAMPLE_RATE,
+        };
+        let delay = interval * 1_000_000.0;
+        let mut payload = self
+            .encoder
+            .encode_packet(self.tick, delay, delay, imu, &samples)?;
+        // The firmware compresses the delays too coarsely to reproduce the stored
+        // timestamps, so use the delay byte that the decoder turns into the right interval
+        payload[1] = protocol::delay_byte_for_interval(interval);
+        Ok(payload)
+    }
+}
+
+#[tokio::test]
+async fn test_replay() {
+    let mut dataset = PsyLinkDataset::from_arrays(
+        &[],
+        &[[0u8; 14]; 120].map(|mut row| {
+            row[8] = 100;
+            row
+        }),
+    );
+    for (index, row) in dataset.all_packets.iter_mut().enumerate() {
+        row[0] = index as u8 + 1;
+    }
+    // Rows 50 to 54 were inserted for 0.1 seconds of lost signal
+    dataset.gaps.push(calibration::Gap {
+        packet_index: 50,
+        inserted: 5,
+        duration: 0.1,
+    });
+
+    let options: ReplayOptions = match "replay,loop:/tmp/test.psylink".parse() {
+        Ok(transport::SourceSpec::Replay(options)) => options,
+        _ => panic!("Should parse replay sources"),
+    };
+    assert!(options.looped);
+    let mut source = ReplaySource::new(dataset, options).unwrap();
+    source.set_realtime(false);
+    assert_eq!(source.channel_count(), 8);
+
+    let mut decoder = protocol::Decoder::new(source.channel_count());
+    let mut packets = vec![];
+    for _ in 0..7 {
+        let payload = source.read().await.unwrap();
+        packets.push(decoder.decode_packet(payload, true, true).unwrap());
+    }
+    let sample_counts: Vec<i32> = packets.iter().map(|packet| packet.sample_count).collect();
+    assert_eq!(sample_counts, [25, 25, 25, 25, 15, 25, 25]);
+    assert_eq!(packets[0].samples[0][..3], [1, 2, 3]);
+    assert_eq!(packets[0].samples[8], vec![100; 25]);
+    approx_eq::assert_approx_eq!(packets[1].timestamps[0], 0.05);
+
+    // The gap comes back as two lost packets, and the inserted rows are left out
+    assert_eq!(packets[2].lost_packets, 2);
+    assert_eq!(packets[2].samples[0][0], 56);
+    // After the last row, the replay starts over
+    assert_eq!(packets[4].samples[0][14], 120);
+    assert_eq!(packets[5].samples[0][0], 1);
+    assert_eq!(packets[5].lost_packets, 0);
+
+    let options = ReplayOptions::parse("speed=2.5, device=2", "/tmp/a:b.csv").unwrap();
+    assert_eq!(options.device, 1);
+    assert_eq!(
+        options.to_string(),
+        "replay,speed=2.5,device=2:/tmp/a:b.csv"
+    );
+    assert!(ReplayOptions::parse("speed=0", "x").is_err());
+    assert!(ReplayOptions::parse("device=0", "x").is_err());
+    assert!(ReplayOptions::parse("fast", "x").is_err());
+}
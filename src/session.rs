@@ -0,0 +1,420 @@
+// The on-disk format of recorded sessions, i.e. of a calibration::PsyLinkDataset
+// together with information about where it came from.
+//
+// A session file starts with the 8 bytes "PSYLINK\0" and the format version as a u16.
+// Then follows a sequence of chunks, each consisting of a 4-byte ASCII tag, the length of
+// the chunk body as a u32, and the body.  All numbers are little-endian, and strings are
+// stored as their length in bytes (u32) followed by UTF-8.  The chunks are:
+//
+//     LAYT  u16 EMG channels per device, u16 devices
+//     DEVC  string description, string address, string firmware: one device, in order
+//     ACTN  u32 count, that many strings: the name of each label, starting with label 0
+//     META  string key, string value: free-form information like the date or the subject
+//     SMPL  u32 rows, u32 channels, rows * f64 timestamps, rows * channels u8 samples
+//     DPTS  u32 count, that many times u64 packet index and u8 label
+//     GAP   u64 packet index, u64 inserted packets, f64 missing seconds (tag "GAP ")
+//
+// Samples, datapoints and gaps may be spread over any number of chunks, so sessions can
+// be written while they are being recorded.  The packet indices refer to the rows of all
+// SMPL chunks together.  Readers skip chunks with unknown tags, so a newer writer may add
+// chunks without bumping the version.  The version changes when existing chunks change.
+
+use crate::calibration::{Datapoint, Gap};
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 8] = b"PSYLINK\0";
+pub const VERSION: u16 = 1;
+
+/// The PsyLink or other signal source that a session was recorded with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub description: String,
+    pub address: String,  // MAC address, empty if not applicable
+    pub firmware: String, // What the device told us about itself
+}
+
+/// Everything about a session except the signals and the labels
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionInfo {
+    pub action_names: Vec<String>, // action_names[label]
+    pub devices: Vec<DeviceInfo>,
+    pub metadata: Vec<(String, String)>,
+}
+
+impl SessionInfo {
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The label of the action with the given name.  Unknown names become new actions,
+    /// unless all labels are taken.
+    pub fn label_for(&mut self, name: &str) -> Result<u8> {
+        let label = match self.action_names.iter().position(|known| known == name) {
+            Some(label) => label,
+            None => self.action_names.len(),
+        };
+        let label = u8::try_from(label).map_err(|_| Error::TooManyActions {
+            found: label + 1,
+            max: u8::MAX as usize + 1,
+        })?;
+        if label as usize == self.action_names.len() {
+            self.action_names.push(name.into());
+        }
+        Ok(label)
+    }
+
+    /// Sets a metadata entry, replacing an earlier value of the same key
+    pub fn set_metadata(&mut self, key: &str, value: String) {
+        match self.metadata.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.metadata.push((key.into(), value)),
+        }
+    }
+}
+
+/// One piece of a session file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Chunk {
+    Layout(protocol::ChannelLayout),
+    Device(DeviceInfo),
+    Actions(Vec<String>),
+    Metadata(String, String),
+    Samples {
+        timestamps: Vec<f64>,
+        rows: Vec<Vec<u8>>,
+    },
+    Datapoints(Vec<Datapoint>),
+    Gap(Gap),
+}
+
+pub struct SessionWriter<W: Write> {
+    out: W,
+}
+
+pub struct SessionReader<R: Read> {
+    input: R,
+    pub version: u16,
+}
+
+impl<W: Write> SessionWriter<W> {
+    /// Starts a session file by writing the magic bytes and the version
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { out })
+    }
+
+    pub fn write(&mut self, chunk: &Chunk) -> Result<()> {
+        let mut body = vec![];
+        let tag = match chunk {
+            Chunk::Layout(layout) => {
+                body.extend((layout.emg_channels as u16).to_le_bytes());
+                body.extend((layout.devices as u16).to_le_bytes());
+                b"LAYT"
+            }
+            Chunk::Device(device) => {
+                put_string(&mut body, &device.description);
+                put_string(&mut body, &device.address);
+                put_string(&mut body, &device.firmware);
+                b"DEVC"
+            }
+            Chunk::Actions(names) => {
+                body.extend((names.len() as u32).to_le_bytes());
+                for name in names {
+                    put_string(&mut body, name);
+                }
+                b"ACTN"
+            }
+            Chunk::Metadata(key, value) => {
+                put_string(&mut body, key);
+                put_string(&mut body, value);
+                b"META"
+            }
+            Chunk::Samples { timestamps, rows } => {
+                let channels = rows.first().map_or(0, |row| row.len());
+                if timestamps.len() != rows.len() {
+                    return Err(Error::Parse(format!(
+                        "{} timestamps for {} rows",
+                        timestamps.len(),
+                        rows.len()
+                    )));
+                }
+                body.extend((rows.len() as u32).to_le_bytes());
+                body.extend((channels as u32).to_le_bytes());
+                for timestamp in timestamps {
+                    body.extend(timestamp.to_le_bytes());
+                }
+                for row in rows {
+                    if row.len() != channels {
+                        return Err(Error::ChannelMismatch {
+                            expected: channels,
+                            found: row.len(),
+                        });
+                    }
+                    body.extend(row);
+                }
+                b"SMPL"
+            }
+            Chunk::Datapoints(datapoints) => {
+                body.extend((datapoints.len() as u32).to_le_bytes());
+                for datapoint in datapoints {
+                    body.extend((datapoint.packet_index as u64).to_le_bytes());
+                    body.push(datapoint.label);
+                }
+                b"DPTS"
+            }
+            Chunk::Gap(gap) => {
+                body.extend((gap.packet_index as u64).to_le_bytes());
+                body.extend((gap.inserted as u64).to_le_bytes());
+                body.extend(gap.duration.to_le_bytes());
+                b"GAP "
+            }
+        };
+        self.out.write_all(tag)?;
+        self.out.write_all(&(body.len() as u32).to_le_bytes())?;
+        self.out.write_all(&body)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<R: Read> SessionReader<R> {
+    /// Checks the magic bytes and the version of a session file
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Parse("Not a PsyLink session file".into()));
+        }
+        let mut version = [0; 2];
+        input.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version > VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Ok(Self { input, version })
+    }
+
+    /// Reads the next chunk, or returns None at the end of the file
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        loop {
+            let mut header = [0; 8];
+            match self.input.read(&mut header[..1])? {
+                0 => return Ok(None),
+                _ => self.input.read_exact(&mut header[1..]).map_err(truncated)?,
+            }
+            let tag: [u8; 4] = header[..4].try_into().unwrap();
+            let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+            // The length can't be trusted, so the body only grows as far as the file goes
+            let mut body = vec![];
+            self.input
+                .by_ref()
+                .take(length)
+                .read_to_end(&mut body)
+                .map_err(truncated)?;
+            if (body.len() as u64) < length {
+                return Err(truncated(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let mut body = Body(&body);
+
+            let chunk = match &tag {
+                b"LAYT" => Chunk::Layout(protocol::ChannelLayout {
+                    emg_channels: body.u16()? as usize,
+                    devices: body.u16()? as usize,
+                }),
+                b"DEVC" => Chunk::Device(DeviceInfo {
+                    description: body.string()?,
+                    address: body.string()?,
+                    firmware: body.string()?,
+                }),
+                b"ACTN" => {
+                    let count = body.u32()?;
+                    Chunk::Actions((0..count).map(|_| body.string()).collect::<Result<_>>()?)
+                }
+                b"META" => Chunk::Metadata(body.string()?, body.string()?),
+                b"SMPL" => {
+                    let rows = body.u32()? as usize;
+                    let channels = body.u32()? as usize;
+                    let timestamps = (0..rows).map(|_| body.f64()).collect::<Result<_>>()?;
+                    let rows = (0..rows)
+                        .map(|_| body.bytes(channels).map(<[u8]>::to_vec))
+                        .collect::<Result<_>>()?;
+                    Chunk::Samples { timestamps, rows }
+                }
+                b"DPTS" => {
+                    let count = body.u32()?;
+                    let datapoints = (0..count)
+                        .map(|_| {
+                            Ok(Datapoint {
+                                packet_index: body.u64()? as usize,
+                                label: body.bytes(1)?[0],
+                            })
+                        })
+                        .collect::<Result<_>>()?;
+                    Chunk::Datapoints(datapoints)
+                }
+                b"GAP " => Chunk::Gap(Gap {
+                    packet_index: body.u64()? as usize,
+                    inserted: body.u64()? as usize,
+                    duration: body.f64()?,
+                }),
+                // Written by a newer version, but not essential
+                _ => continue,
+            };
+            return Ok(Some(chunk));
+        }
+    }
+}
+
+impl<R: Read> Iterator for SessionReader<R> {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Loads a dataset from a session file, an EDF/BDF file or a CSV recording of
+/// recording::Recorder, depending on how the file starts, or from a run directory
+/// of the Python version
+pub fn load_dataset<P: AsRef<Path>>(path: P) -> Result<calibration::PsyLinkDataset> {
+    if legacy::is_legacy_run(path.as_ref()) {
+        return legacy::load_run(path);
+    }
+    let mut input = BufReader::new(File::open(path)?);
+    let start = input.fill_buf()?;
+    if start.starts_with(MAGIC) {
+        calibration::PsyLinkDataset::read_from(input)
+    } else if edf::is_edf(start) {
+        edf::read_edf(input)
+    } else {
+        recording::read_csv(input)
+    }
+}
+
+/// Reads the fields of a chunk body one after the other
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.0.len() < count {
+            return Err(Error::Parse("Session file chunk is too short".into()));
+        }
+        let (bytes, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec())
+            .map_err(|_| Error::Parse("Invalid UTF-8 in session file".into()))
+    }
+}
+
+fn put_string(body: &mut Vec<u8>, text: &str) {
+    body.extend((text.len() as u32).to_le_bytes());
+    body.extend(text.as_bytes());
+}
+
+fn truncated(err: std::io::Error) -> Error {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::Parse("Session file is truncated".into()),
+        _ => Error::Io(err),
+    }
+}
+
+#[test]
+fn test_session_chunks() {
+    let chunks = vec![
+        Chunk::Layout(protocol::ChannelLayout::new(2)),
+        Chunk::Device(DeviceInfo {
+            description: "PsyLink with MAC address AA:BB:CC:DD:EE:FF".into(),
+            address: "AA:BB:CC:DD:EE:FF".into(),
+            firmware: "PsyLink, 2 EMG channels".into(),
+        }),
+        Chunk::Actions(vec!["(no action)".into(), "Key \"w\"".into()]),
+        Chunk::Metadata("subject".into(), "Ünïcödé".into()),
+        Chunk::Samples {
+            timestamps: vec![0.0, 0.002],
+            rows: vec![
+                vec![1, 2, 3, 4, 5, 6, 7, 8],
+                vec![9, 10, 11, 12, 13, 14, 15, 16],
+            ],
+        },
+        Chunk::Datapoints(vec![Datapoint {
+            packet_index: 1,
+            label: 1,
+        }]),
+        Chunk::Gap(Gap {
+            packet_index: 2,
+            inserted: 0,
+            duration: 0.5,
+        }),
+    ];
+    let mut writer = SessionWriter::new(vec![]).unwrap();
+    for chunk in &chunks {
+        writer.write(chunk).unwrap();
+    }
+    let mut bytes = writer.into_inner();
+
+    // Chunks with unknown tags are skipped
+    let mut unknown = b"NEW?".to_vec();
+    unknown.extend(3u32.to_le_bytes());
+    unknown.extend([1, 2, 3]);
+    bytes.splice(10..10, unknown);
+
+    let reader = SessionReader::new(bytes.as_slice()).unwrap();
+    assert_eq!(reader.version, VERSION);
+    let read: Vec<Chunk> = reader.collect::<Result<_>>().unwrap();
+    assert_eq!(read, chunks);
+
+    // A crash in the middle of a chunk is noticed
+    let mut reader = SessionReader::new(&bytes[..bytes.len() - 3]).unwrap();
+    assert!(reader.any(|chunk| chunk.is_err()));
+
+    // So is a chunk length that goes beyond the end of the file
+    let mut huge = bytes[..10].to_vec();
+    huge.extend(b"SMPL");
+    huge.extend(u32::MAX.to_le_bytes());
+    let mut reader = SessionReader::new(huge.as_slice()).unwrap();
+    assert!(reader.next_chunk().is_err());
+
+    let mut newer = bytes.clone();
+    newer[8] = 99;
+    assert!(matches!(
+        SessionReader::new(newer.as_slice()),
+        Err(Error::UnsupportedVersion(99))
+    ));
+    assert!(SessionReader::new(&b"PSYLONK\0\x01\x00"[..]).is_err());
+}
@@ -0,0 +1,418 @@
+// A stand-in for a real PsyLink, for demos and for testing without hardware.
+// It produces payloads in the firmware's wire format, so they take the same
+// path through protocol::Decoder as the payloads of a real device.
+//
+// What the simulated user does is described by a script of statements separated
+// by semicolons or newlines, for example:
+//
+//     gesture 2 active from t=5s to t=8s; gesture 1 from 10s to 12s; loop 15s
+//     loss 0.02; duplicate 0.01; channels 8; seed 42
+//
+// Times refer to the simulated signal time, which starts at 0 and advances by
+// the duration of every generated payload, whether it gets lost or not.
+
+use crate::error::{Error, Result};
+use crate::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+
+const NOISE_AMPLITUDE: f64 = 3.0; // Resting EMG noise, in sample value units
+const BURST_AMPLITUDE: f64 = 90.0; // EMG amplitude of a fully contracted muscle
+const RAMP_TIME: f64 = 0.15; // Seconds it takes to tense or relax a muscle
+const TILT_ANGLE: f64 = 0.35; // How far the arm rotates during a gesture, in radians
+const GYRO_SCALE: f64 = 20.0; // Gyroscope units per radian per second
+
+/// A parsed simulator script
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    pub gestures: Vec<GestureEvent>,
+    pub loss: f64,             // Probability that a payload gets lost, below 1
+    pub duplicate: f64,        // Probability that a payload is sent twice, below 1
+    pub period: Option<f64>,   // Restart the gestures after this many seconds
+    pub channels: Option<i32>, // Number of EMG channels, defaults to the reference design
+    pub seed: Option<u64>,     // Makes the noise reproducible
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GestureEvent {
+    pub gesture: usize, // 1 and up. Gesture 0 is the resting state
+    pub start: f64,
+    pub end: f64,
+}
+
+pub struct Simulator {
+    channel_count: i32,
+    encoder: protocol::Encoder,
+    script: Script,
+    tick: u8,
+    time: f64,
+    last_payload: Option<Vec<u8>>,
+    rng: StdRng,
+    realtime: bool,
+    next_deadline: Option<Instant>,
+    pub lost_packets: usize,       // How many payloads were dropped so far
+    pub duplicated_packets: usize, // How many payloads were sent twice so far
+}
+
+impl Simulator {
+    pub fn new(channel_count: i32) -> Self {
+        Self::with_script(Script {
+            channels: Some(channel_count),
+            ..Script::default()
+        })
+    }
+
+    pub fn with_script(script: Script) -> Self {
+        let channel_count = script.channels.unwrap_or(firmware::DEFAULT_CHANNEL_COUNT);
+        let rng = match script.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            channel_count,
+            encoder: protocol::Encoder::new(channel_count),
+            script,
+            tick: 0,
+            time: 0.0,
+            last_payload: None,
+            rng,
+            realtime: true,
+            next_deadline: None,
+            lost_packets: 0,
+            duplicated_packets: 0,
+        }
+    }
+
+    /// When realtime is disabled, payloads are generated as fast as they are read,
+    /// which is what tests want.  Otherwise, they arrive at the firmware's rate.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    pub fn channel_count(&self) -> i32 {
+        self.channel_count
+    }
+
+    /// The simulated signal time in seconds
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Returns the gesture that the script asks for at the given time, or 0 for none
+    pub fn gesture_at(&self, time: f64) -> usize {
+        self.activation(time).map_or(0, |(gesture, _)| gesture)
+    }
+
+    /// Generates the next payload that makes it to the receiver, without waiting
+    pub fn next_payload(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(payload) = self.step() {
+                return payload;
+            }
+        }
+    }
+
+    /// Waits until the next payload is due, then generates it
+    pub async fn read(&mut self) -> Vec<u8> {
+        loop {
+            if self.realtime {
+                let now = Instant::now();
+                let deadline = self.next_deadline.unwrap_or(now);
+                tokio::time::sleep_until(deadline).await;
+                // Don't try to catch up after a long pause, like a real device wouldn't either
+                let interval = Duration::from_secs_f64(1.0 / firmware::BLE_NOTIFY_RATE);
+                self.next_deadline = Some(deadline.max(now) + interval);
+            }
+            if let Some(payload) = self.step() {
+                return payload;
+            }
+        }
+    }
+
+    /// Advances the simulation by one notification interval.  Returns None if the
+    /// payload of this interval got lost.
+    fn step(&mut self) -> Option<Vec<u8>> {
+        if let Some(last_payload) = &self.last_payload {
+            if self.rng.gen_bool(self.script.duplicate.clamp(0.0, 1.0)) {
+                self.duplicated_packets += 1;
+                return Some(last_payload.clone());
+            }
+        }
+
+        let payload = self.generate();
+        if self.rng.gen_bool(self.script.loss.clamp(0.0, 1.0)) {
+            self.lost_packets += 1;
+            return None;
+        }
+        self.last_payload = Some(payload.clone());
+        Some(payload)
+    }
+
+    fn generate(&mut self) -> Vec<u8> {
+        // The firmware's tick counter skips 0 when it wraps around
+        self.tick = self.tick.checked_add(1).unwrap_or(1);
+
+        let sample_count = (firmware::SAMPLE_RATE / firmware::BLE_NOTIFY_RATE) as usize;
+        let interval = 1.0 / firmware::SAMPLE_RATE;
+        let mut samples = vec![Vec::with_capacity(sample_count); self.channel_count as usize];
+        for timestep in 0..sample_count {
+            let time = self.time + timestep as f64 * interval;
+            let activation = self.activation(time);
+            for (channel, channel_samples) in samples.iter_mut().enumerate() {
+                let amplitude = match activation {
+                    Some((gesture, level)) => {
+                        NOISE_AMPLITUDE + level * BURST_AMPLITUDE * self.weight(gesture, channel)
+                    }
+                    None => NOISE_AMPLITUDE,
+                };
+                channel_samples.push(self.noise(127.0, amplitude));
+            }
+        }
+
+        let imu = self.imu(self.time);
+        self.time += sample_count as f64 * interval;
+
+        let delay = 1_000_000.0 / firmware::SAMPLE_RATE;
+        self.encoder
+            .encode_packet(self.tick, delay, delay, imu, &samples)
+            .expect("Simulator should generate valid payloads")
+    }
+
+    /// Returns the active gesture at the given time, along with how strongly
+    /// the muscles are tensed, between 0 and 1.
+    fn activation(&self, time: f64) -> Option<(usize, f64)> {
+        let time = match self.script.period {
+            Some(period) if period > 0.0 => time % period,
+            _ => time,
+        };
+        self.script
+            .gestures
+            .iter()
+            .filter(|event| event.gesture > 0 && event.start <= time && time < event.end)
+            .map(|event| {
+                let level = ((time - event.start).min(event.end - time) / RAMP_TIME).min(1.0);
+                (event.gesture, level)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// How much a channel picks up of a gesture.  Every gesture has its own center
+    /// electrode, and the signal fades with the distance around the arm.
+    fn weight(&self, gesture: usize, channel: usize) -> f64 {
+        let channels = self.channel_count as usize;
+        let center = (gesture * 3) % channels;
+        let distance = center.abs_diff(channel);
+        let distance = distance.min(channels - distance) as f64;
+        (-distance * distance / 2.0).exp()
+    }
+
+    /// Gyroscope and accelerometer bytes at the given time.  The arm rotates a bit
+    /// during each gesture, alternating the direction from gesture to gesture.
+    fn imu(&self, time: f64) -> [u8; 6] {
+        let angle = |time: f64| match self.activation(time) {
+            Some((gesture, level)) if gesture % 2 == 0 => -level * TILT_ANGLE,
+            Some((_, level)) => level * TILT_ANGLE,
+            None => 0.0,
+        };
+        let dt = 1.0 / firmware::SAMPLE_RATE;
+        let angle_now = angle(time);
+        let angular_velocity = (angle(time + dt) - angle_now) / dt;
+
+        let gyro = |x: f64| (x + 127.0).round().clamp(0.0, 255.0) as u8;
+        let accel = |x: f64| (128.0 * x + 127.0).round().clamp(0.0, 255.0) as u8;
+        [
+            gyro(angular_velocity * GYRO_SCALE),
+            gyro(0.0),
+            gyro(0.0),
+            accel(angle_now.sin()),
+            accel(0.0),
+            accel(angle_now.cos()),
+        ]
+    }
+
+    fn noise(&mut self, center: f64, amplitude: f64) -> u8 {
+        let value = center + self.rng.gen_range(-amplitude..=amplitude);
+        // 0 is never sent by the firmware
+        value.round().clamp(1.0, 255.0) as u8
+    }
+}
+
+impl Script {
+    /// Reads a script from a file if the text starts with '@', or parses the text itself
+    pub fn load(text: &str) -> Result<Self> {
+        match text.strip_prefix('@') {
+            Some(path) => std::fs::read_to_string(path)?.parse(),
+            None => text.parse(),
+        }
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut script = Script::default();
+        for statement in text.split([';', '\n']) {
+            let words: Vec<&str> = statement
+                .split_whitespace()
+                .filter(|word| !matches!(*word, "active" | "from" | "to"))
+                .collect();
+            let invalid = || Error::Parse(format!("Invalid simulator statement: {statement}"));
+            match words.as_slice() {
+                [] => {}
+                ["gesture", gesture, start, end] => {
+                    script.gestures.push(GestureEvent {
+                        gesture: gesture.parse().map_err(|_| invalid())?,
+                        start: parse_time(start).ok_or_else(invalid)?,
+                        end: parse_time(end).ok_or_else(invalid)?,
+                    });
+                }
+                ["loss", probability] => {
+                    script.loss = parse_probability(probability).ok_or_else(invalid)?
+                }
+                ["duplicate", probability] => {
+                    script.duplicate = parse_probability(probability).ok_or_else(invalid)?
+                }
+                ["loop", period] => script.period = Some(parse_time(period).ok_or_else(invalid)?),
+                ["channels", count] => {
+                    let count: i32 = count.parse().map_err(|_| invalid())?;
+                    if !(1..=255).contains(&count) {
+                        return Err(Error::InvalidChannelCount(count));
+                    }
+                    script.channels = Some(count);
+                }
+                ["seed", seed] => script.seed = Some(seed.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(script)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut statements = vec![];
+        for event in &self.gestures {
+            statements.push(format!(
+                "gesture {} from {}s to {}s",
+                event.gesture, event.start, event.end
+            ));
+        }
+        if self.loss > 0.0 {
+            statements.push(format!("loss {}", self.loss));
+        }
+        if self.duplicate > 0.0 {
+            statements.push(format!("duplicate {}", self.duplicate));
+        }
+        if let Some(period) = self.period {
+            statements.push(format!("loop {period}s"));
+        }
+        if let Some(channels) = self.channels {
+            statements.push(format!("channels {channels}"));
+        }
+        if let Some(seed) = self.seed {
+            statements.push(format!("seed {seed}"));
+        }
+        write!(f, "{}", statements.join("; "))
+    }
+}
+
+/// Parses times like "5", "5s", "t=5s", "500ms", "10m" or "2h" into seconds
+pub fn parse_time(text: &str) -> Option<f64> {
+    let text = text.strip_prefix("t=").unwrap_or(text);
+    let (number, unit) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(minutes) = text.strip_suffix('m') {
+        (minutes, 60.0)
+    } else if let Some(hours) = text.strip_suffix('h') {
+        (hours, 3600.0)
+    } else {
+        (text.strip_suffix('s').unwrap_or(text), 1.0)
+    };
+    number.parse::<f64>().ok().map(|number| number * unit)
+}
+
+// A probability of 1 would never let a fresh payload through
+fn parse_probability(text: &str) -> Option<f64> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|probability| (0.0..1.0).contains(probability))
+}
+
+#[test]
+fn test_script() {
+    let script: Script = "gesture 2 active from t=5s to t=8s; gesture 1 from 500ms to 1\n\
+        loss 0.1; duplicate 0.05; loop 10s; channels 4; seed 7"
+        .parse()
+        .unwrap();
+    assert_eq!(script.gestures.len(), 2);
+    assert_eq!(
+        script.gestures[0],
+        GestureEvent {
+            gesture: 2,
+            start: 5.0,
+            end: 8.0
+        }
+    );
+    assert_eq!(script.gestures[1].start, 0.5);
+    assert_eq!(parse_time("10m"), Some(600.0));
+    assert_eq!(parse_time("2h"), Some(7200.0));
+    assert_eq!(parse_time("2 h"), None);
+    assert_eq!(script.period, Some(10.0));
+    assert_eq!(script.channels, Some(4));
+    assert_eq!(script.to_string().parse::<Script>().unwrap(), script);
+
+    assert!("gesture 2 from 5s".parse::<Script>().is_err());
+    assert!("wiggle".parse::<Script>().is_err());
+    assert!("channels 0".parse::<Script>().is_err());
+    assert!("loss 1".parse::<Script>().is_err());
+    assert!("duplicate -0.1".parse::<Script>().is_err());
+    assert!("loss NaN".parse::<Script>().is_err());
+
+    let simulator = Simulator::with_script(script);
+    assert_eq!(simulator.gesture_at(4.9), 0);
+    assert_eq!(simulator.gesture_at(6.0), 2);
+    assert_eq!(simulator.gesture_at(16.0), 2);
+}
+
+#[test]
+fn test_simulator_through_decoder() {
+    let script: Script = "gesture 1 from 1s to 3s; loss 0.1; duplicate 0.1; seed 1"
+        .parse()
+        .unwrap();
+    let mut simulator = Simulator::with_script(script);
+    let mut decoder = protocol::Decoder::new(simulator.channel_count());
+
+    let (mut lost, mut duplicates) = (0, 0);
+    let mut rest = [0.0; 8];
+    let mut active = [0.0; 8];
+    while simulator.time() < 4.0 {
+        let payload = simulator.next_payload();
+        let packet = decoder.decode_packet(payload, true, true).unwrap();
+        lost += packet.lost_packets as usize;
+        if packet.is_duplicate {
+            duplicates += 1;
+            continue;
+        }
+        for (channel, samples) in packet.samples.iter().take(8).enumerate() {
+            for (sample, timestamp) in samples.iter().zip(&packet.timestamps) {
+                let energy = (*sample as f64 - 127.0).powi(2);
+                if (1.5..2.5).contains(timestamp) {
+                    active[channel] += energy;
+                } else if *timestamp < 0.9 {
+                    rest[channel] += energy;
+                }
+            }
+        }
+    }
+    assert_eq!(lost, simulator.lost_packets);
+    assert_eq!(duplicates, simulator.duplicated_packets);
+    assert!(lost > 0 && duplicates > 0);
+
+    // Gesture 1 is centered on channel 3 and barely reaches the opposite side of the arm
+    assert!(active[3] > 20.0 * rest[3]);
+    assert!(active[3] > 20.0 * active[7]);
+}
@@ -0,0 +1,252 @@
+// Spectral analysis of the signals, to check the contact of the electrodes and the mains
+// interference, which are much easier to see in the frequency domain than in the time
+// domain: a loose electrode shows broadband noise and a strong peak at 50 or 60 Hz.
+//
+// The power spectral density (PSD) is estimated with Welch's method: the signal is cut
+// into segments of SEGMENT samples that overlap by half, every segment is multiplied with
+// a Hann window and transformed with an FFT, and the periodograms are averaged.  The
+// spectrogram streams the same periodograms as columns, one for every HOP new samples.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+pub const SEGMENT: usize = 256; // Samples per FFT, a power of two
+pub const HOP: usize = SEGMENT / 2; // New samples between two columns of a spectrogram
+pub const MAX_COLUMNS: usize = 120; // How many columns a spectrogram keeps
+
+/// A power spectral density, in signal units squared per Hz
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Psd {
+    pub frequencies: Vec<f64>, // Hz, from 0 up to the Nyquist frequency
+    pub density: Vec<f64>,
+}
+
+/// The periodograms of the latest segments of several channels, averaged over the channels
+#[derive(Clone, Debug, Default)]
+pub struct Spectrogram {
+    pub columns: VecDeque<Vec<f64>>, // The density of every frequency bin, oldest first
+    pub times: VecDeque<f64>,        // The timestamp of the last sample of each column
+    pub sample_rate: f64,            // Of the latest column
+    pending: usize,                  // Samples that arrived since the latest column
+}
+
+/// An in-place radix-2 FFT of a complex signal, given as real and imaginary parts whose
+/// length is a power of two
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let len = re.len();
+    assert!(len.is_power_of_two() && im.len() == len);
+
+    // Bit reversal permutation
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits() >> (usize::BITS - bits) as usize;
+        if bits > 0 && j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut size = 2;
+    while size <= len {
+        let angle = -2.0 * PI / size as f64;
+        for start in (0..len).step_by(size) {
+            for k in 0..size / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + size / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        size *= 2;
+    }
+}
+
+fn hann(len: usize) -> Vec<f64> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / len as f64).cos())
+        .collect()
+}
+
+/// The one-sided PSD of a single segment, whose length must be a power of two.  The mean
+/// is removed first, so the DC offset doesn't leak into the lowest frequencies.
+pub fn periodogram(segment: &[f64], sample_rate: f64) -> Vec<f64> {
+    let len = segment.len();
+    let window = hann(len);
+    let mean = segment.iter().sum::<f64>() / len as f64;
+    let mut re: Vec<f64> = segment
+        .iter()
+        .zip(&window)
+        .map(|(x, w)| (x - mean) * w)
+        .collect();
+    let mut im = vec![0.0; len];
+    fft(&mut re, &mut im);
+
+    let scale = 1.0 / (sample_rate * window.iter().map(|w| w * w).sum::<f64>());
+    (0..=len / 2)
+        .map(|bin| {
+            let power = (re[bin] * re[bin] + im[bin] * im[bin]) * scale;
+            // Fold the negative frequencies onto the positive ones
+            match bin == 0 || bin == len / 2 {
+                true => power,
+                false => 2.0 * power,
+            }
+        })
+        .collect()
+}
+
+/// The frequencies of the bins of a periodogram
+pub fn frequencies(segment_len: usize, sample_rate: f64) -> Vec<f64> {
+    (0..=segment_len / 2)
+        .map(|bin| bin as f64 * sample_rate / segment_len as f64)
+        .collect()
+}
+
+/// Estimates the PSD with Welch's method.  Signals shorter than SEGMENT are analyzed in
+/// a single segment of the largest power of two that fits.
+pub fn welch(signal: &[f64], sample_rate: f64) -> Psd {
+    let segment_len = match signal.len() {
+        len if len >= SEGMENT => SEGMENT,
+        len if len >= 2 => 1 << len.ilog2(),
+        _ => return Psd::default(),
+    };
+    let hop = segment_len / 2;
+    let mut density = vec![0.0; segment_len / 2 + 1];
+    let mut count = 0;
+    // Align the segments to the end, so the latest samples are always included
+    let offset = (signal.len() - segment_len) % hop;
+    for start in (offset..=signal.len() - segment_len).step_by(hop) {
+        let periodogram = periodogram(&signal[start..start + segment_len], sample_rate);
+        density
+            .iter_mut()
+            .zip(periodogram)
+            .for_each(|(sum, power)| *sum += power);
+        count += 1;
+    }
+    density.iter_mut().for_each(|sum| *sum /= count as f64);
+    Psd {
+        frequencies: frequencies(segment_len, sample_rate),
+        density,
+    }
+}
+
+impl Psd {
+    /// The total power between two frequencies
+    pub fn power(&self, low: f64, high: f64) -> f64 {
+        let resolution = match self.frequencies.as_slice() {
+            [_, second, ..] => *second,
+            _ => return 0.0,
+        };
+        self.frequencies
+            .iter()
+            .zip(&self.density)
+            .filter(|(frequency, _)| (low..=high).contains(*frequency))
+            .map(|(_, density)| density * resolution)
+            .sum()
+    }
+}
+
+impl Spectrogram {
+    /// Adds the columns that are due after new_samples were appended to the buffers.
+    /// The buffers are the ring buffers of the plotter, one per channel, and only the
+    /// given channels are analyzed.
+    pub fn update(
+        &mut self,
+        buffers: &[VecDeque<f64>],
+        channels: &[usize],
+        timestamps: &VecDeque<f64>,
+        new_samples: usize,
+        sample_rate: f64,
+    ) {
+        self.pending += new_samples;
+        self.sample_rate = sample_rate;
+        let len = timestamps.len();
+        while self.pending >= HOP {
+            self.pending -= HOP;
+            let Some(end) = len.checked_sub(self.pending) else {
+                continue;
+            };
+            if end < SEGMENT || channels.is_empty() {
+                continue;
+            }
+            let mut column = vec![0.0; SEGMENT / 2 + 1];
+            for channel in channels {
+                let segment: Vec<f64> = buffers[*channel]
+                    .range(end - SEGMENT..end)
+                    .copied()
+                    .collect();
+                let periodogram = periodogram(&segment, sample_rate);
+                column
+                    .iter_mut()
+                    .zip(periodogram)
+                    .for_each(|(sum, power)| *sum += power / channels.len() as f64);
+            }
+            if self.columns.len() >= MAX_COLUMNS {
+                self.columns.pop_front();
+                self.times.pop_front();
+            }
+            self.columns.push_back(column);
+            self.times.push_back(timestamps[end - 1]);
+        }
+    }
+
+    pub fn frequencies(&self) -> Vec<f64> {
+        frequencies(SEGMENT, self.sample_rate)
+    }
+}
+
+#[test]
+fn test_spectrum() {
+    let rate = 1000.0;
+    // Hum at 50 Hz with an amplitude of 0.5, and a weaker signal at 120 Hz
+    let signal: Vec<f64> = (0..2000)
+        .map(|i| {
+            let t = i as f64 / rate;
+            0.5 * (2.0 * PI * 50.0 * t).sin() + 0.1 * (2.0 * PI * 120.0 * t).sin() + 0.3
+        })
+        .collect();
+
+    let mut re = vec![0.0; 8];
+    let mut im = vec![0.0; 8];
+    re[1] = 1.0;
+    fft(&mut re, &mut im);
+    for (k, (re, im)) in re.iter().zip(&im).enumerate() {
+        let angle = -2.0 * PI * k as f64 / 8.0;
+        assert!((re - angle.cos()).abs() < 1e-12 && (im - angle.sin()).abs() < 1e-12);
+    }
+
+    let psd = welch(&signal, rate);
+    assert_eq!(psd.frequencies.len(), SEGMENT / 2 + 1);
+    assert_eq!(psd.frequencies[SEGMENT / 2], 500.0);
+    let peak = (0..psd.density.len())
+        .max_by(|a, b| psd.density[*a].total_cmp(&psd.density[*b]))
+        .unwrap();
+    assert!((psd.frequencies[peak] - 50.0).abs() < rate / SEGMENT as f64);
+    // The power of a sine is half its squared amplitude, and the offset is removed
+    let hum = psd.power(40.0, 60.0);
+    assert!((hum - 0.125).abs() < 0.01, "{hum}");
+    assert!((psd.power(110.0, 130.0) - 0.005).abs() < 0.001);
+    assert!(psd.power(0.0, 5.0) < 0.001);
+    assert_eq!(welch(&signal[..100], rate).density.len(), 33);
+
+    // Streamed in packets of 25 samples, the spectrogram gets a column every HOP samples
+    let mut spectrogram = Spectrogram::default();
+    let mut buffers = vec![VecDeque::new(); 2];
+    let mut timestamps = VecDeque::new();
+    for packet in signal.chunks(25) {
+        for value in packet {
+            buffers[0].push_back(*value);
+            buffers[1].push_back(0.0);
+            timestamps.push_back(timestamps.len() as f64 / rate);
+        }
+        spectrogram.update(&buffers, &[0], &timestamps, packet.len(), rate);
+    }
+    assert_eq!(spectrogram.columns.len(), (2000 - SEGMENT) / HOP + 1);
+    // The latest column ends at the last multiple of HOP
+    assert_eq!(spectrogram.times.back(), Some(&1.919));
+    let column = spectrogram.columns.back().unwrap();
+    assert_eq!(column, &periodogram(&signal[1920 - SEGMENT..1920], rate));
+}
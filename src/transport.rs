@@ -0,0 +1,821 @@
+// Everything that can deliver PsyLink payloads.  The rest of the application only
+// deals with a transport::Source, so it doesn't matter whether the signals come from
+// a real PsyLink over Bluetooth or USB, from a recording, from a replayed dataset
+// (see replay.rs), or from the simulator.
+//
+// Serial links and recordings use a line-based text format: one payload per line,
+// hex-encoded, exactly as the firmware sends it over BLE.  Lines starting with '#'
+// are comments, and a "# channels: N" comment announces the number of EMG channels.
+// The firmware writes this format to its USB serial port if SEND_SERIAL is true.
+
+use crate::error::{DecodeError, Error, Result};
+use crate::prelude::*;
+use crate::replay::{ReplayOptions, ReplaySource};
+use crate::simulator::Simulator;
+use futures::stream::{self, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+const LINE_BUFFER: usize = 64; // How many lines the reader thread may read ahead
+const RATE_WINDOW: f64 = 2.0; // Seconds over which RateMeter averages
+
+pub trait Transport {
+    /// A human-readable description of where the payloads come from
+    fn description(&self) -> String;
+
+    /// The number of EMG channels in each payload
+    fn channel_count(&self) -> i32;
+
+    /// Waits for the next raw payload, in the format that the firmware sends over BLE
+    fn read(&mut self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    fn disconnect(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    fn layout(&self) -> protocol::ChannelLayout {
+        protocol::ChannelLayout::new(self.channel_count() as usize)
+    }
+
+    /// What a session file should remember about where its signals came from
+    fn device_info(&self) -> session::DeviceInfo {
+        session::DeviceInfo {
+            description: self.description(),
+            ..Default::default()
+        }
+    }
+
+    /// Waits for the next payload and decodes it
+    fn read_packet(
+        &mut self,
+        decoder: &mut protocol::Decoder,
+        enable_accelerometer: bool,
+        enable_gyroscope: bool,
+    ) -> impl Future<Output = Result<protocol::Packet>> + Send
+    where
+        Self: Send,
+    {
+        async move {
+            let payload = self.read().await?;
+            Ok(decoder.decode_packet(payload, enable_accelerometer, enable_gyroscope)?)
+        }
+    }
+}
+
+/// Which source to open, as chosen on the command line or in the GUI
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceSpec {
+    Bluetooth(bluetooth::DeviceFilter),
+    Serial(PathBuf),
+    File(PathBuf),
+    Replay(ReplayOptions),
+    Simulator(Option<String>), // The simulator script, see simulator.rs
+}
+
+impl Default for SourceSpec {
+    fn default() -> Self {
+        SourceSpec::Bluetooth(bluetooth::DeviceFilter::default())
+    }
+}
+
+/// Several sources that are acquired at the same time, e.g. a PsyLink on each forearm.
+/// In the GUI, they are separated by '+', and a '+' within a source, e.g. in a path, is
+/// written as "++".  On the command line, --source is given once per source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceList(pub Vec<SourceSpec>);
+
+impl Default for SourceList {
+    fn default() -> Self {
+        SourceList(vec![SourceSpec::default()])
+    }
+}
+
+/// An opened source of payloads
+pub enum Source {
+    Bluetooth(bluetooth::Device),
+    Lines(LineSource),
+    Replay(Box<ReplaySource>),
+    Simulator(Box<Simulator>),
+}
+
+/// Reads hex-encoded payloads line by line from a serial port or a file
+pub struct LineSource {
+    description: String,
+    channel_count: i32,
+    lines: mpsc::Receiver<std::io::Result<String>>,
+    first_payload: Option<Vec<u8>>,
+    interval: Option<Duration>, // Pause between payloads, for replaying at the original pace
+}
+
+impl SourceSpec {
+    /// Opens the source.  For Bluetooth, this scans until a PsyLink is found.
+    pub async fn open(&self, app: &App) -> Result<Source> {
+        match self {
+            SourceSpec::Bluetooth(filter) => {
+                let mut device = bluetooth::find_peripheral(app, filter, None).await?;
+                device.find_characteristics().await?;
+                if let Err(err) = bluetooth::save_last_device(&device.address) {
+                    eprintln!("Failed to remember the PsyLink: {err}");
+                }
+                Ok(Source::Bluetooth(device))
+            }
+            SourceSpec::Serial(path) => {
+                let port = open_serial(path)?;
+                let description = format!("serial port {}", path.display());
+                let source = LineSource::open(description, port, None).await?;
+                Ok(Source::Lines(source))
+            }
+            SourceSpec::File(path) => {
+                let file = std::fs::File::open(path)?;
+                let description = format!("recording {}", path.display());
+                let interval = Duration::from_secs_f64(1.0 / firmware::BLE_NOTIFY_RATE);
+                let source = LineSource::open(description, file, Some(interval)).await?;
+                Ok(Source::Lines(source))
+            }
+            SourceSpec::Replay(options) => {
+                let source = ReplaySource::open(options.clone())?;
+                Ok(Source::Replay(Box::new(source)))
+            }
+            SourceSpec::Simulator(script) => {
+                let script = match script {
+                    Some(script) => simulator::Script::load(script)?,
+                    None => simulator::Script::default(),
+                };
+                Ok(Source::Simulator(Box::new(Simulator::with_script(script))))
+            }
+        }
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let (kind, path) = match spec.split_once(':') {
+            Some((kind, path)) => (kind, Some(path)),
+            None => (spec, None),
+        };
+        // Only replays have settings before the colon
+        let (kind, settings) = kind.split_once(',').unwrap_or((kind, ""));
+        match (kind.trim().to_lowercase().as_str(), path) {
+            ("replay", Some(path)) if !path.is_empty() => {
+                Ok(SourceSpec::Replay(ReplayOptions::parse(settings, path)?))
+            }
+            _ if !settings.is_empty() => Err(format!(
+                "Unknown signal source \"{spec}\", only replay:PATH takes settings like replay,speed=2:PATH"
+            )),
+            ("ble" | "bluetooth", None) => Ok(SourceSpec::default()),
+            ("ble" | "bluetooth", Some(filter)) => Ok(SourceSpec::Bluetooth(filter.parse()?)),
+            ("sim" | "simulator", None) => Ok(SourceSpec::Simulator(None)),
+            ("sim" | "simulator", Some(script)) => {
+                // Scripts in files are checked when the source is opened
+                if !script.starts_with('@') {
+                    script
+                        .parse::<simulator::Script>()
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(SourceSpec::Simulator(Some(script.into())))
+            }
+            ("serial", Some(path)) if !path.is_empty() => Ok(SourceSpec::Serial(path.into())),
+            ("file", Some(path)) if !path.is_empty() => Ok(SourceSpec::File(path.into())),
+            _ => Err(format!(
+                "Unknown signal source \"{spec}\", expected ble, ble:FILTER, sim, sim:SCRIPT, serial:PATH, file:PATH or replay:PATH"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceSpec::Bluetooth(filter) => match filter.to_string().as_str() {
+                "" => write!(f, "ble"),
+                filter => write!(f, "ble:{filter}"),
+            },
+            SourceSpec::Serial(path) => write!(f, "serial:{}", path.display()),
+            SourceSpec::File(path) => write!(f, "file:{}", path.display()),
+            SourceSpec::Replay(options) => write!(f, "{options}"),
+            SourceSpec::Simulator(None) => write!(f, "sim"),
+            SourceSpec::Simulator(Some(script)) => write!(f, "sim:{script}"),
+        }
+    }
+}
+
+impl SourceList {
+    pub fn first(&self) -> &SourceSpec {
+        &self.0[0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SourceSpec> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut SourceSpec> {
+        self.0.iter_mut()
+    }
+}
+
+impl FromStr for SourceList {
+    type Err = String;
+
+    fn from_str(list: &str) -> std::result::Result<Self, Self::Err> {
+        let mut specs = vec![String::new()];
+        let mut chars = list.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '+' if chars.next_if_eq(&'+').is_some() => specs.last_mut().unwrap().push('+'),
+                '+' => specs.push(String::new()),
+                c => specs.last_mut().unwrap().push(c),
+            }
+        }
+        let specs = specs
+            .iter()
+            .map(|spec| spec.trim().parse())
+            .collect::<std::result::Result<Vec<SourceSpec>, String>>()?;
+        Ok(SourceList(specs))
+    }
+}
+
+impl fmt::Display for SourceList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let specs: Vec<String> = self
+            .iter()
+            .map(|spec| spec.to_string().replace('+', "++"))
+            .collect();
+        write!(f, "{}", specs.join(" + "))
+    }
+}
+
+impl Transport for Source {
+    fn description(&self) -> String {
+        match self {
+            Source::Bluetooth(device) => format!(
+                "PsyLink with MAC address {} (using {})",
+                device.address, device.mode
+            ),
+            Source::Lines(source) => source.description.clone(),
+            Source::Replay(source) => source.description(),
+            Source::Simulator(_) => "simulated PsyLink".into(),
+        }
+    }
+
+    fn channel_count(&self) -> i32 {
+        match self {
+            Source::Bluetooth(device) => device.channel_count,
+            Source::Lines(source) => source.channel_count,
+            Source::Replay(source) => source.channel_count(),
+            Source::Simulator(simulator) => simulator.channel_count(),
+        }
+    }
+
+    fn device_info(&self) -> session::DeviceInfo {
+        if let Source::Replay(source) = self {
+            return source.device_info();
+        }
+        let mut info = session::DeviceInfo {
+            description: self.description(),
+            ..Default::default()
+        };
+        if let Source::Bluetooth(device) = self {
+            info.address = device.address.clone();
+            info.firmware = format!("{}, {} EMG channels", device.name, device.channel_count);
+        }
+        info
+    }
+
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        match self {
+            Source::Bluetooth(device) => device.read().await,
+            Source::Lines(source) => source.read().await,
+            Source::Replay(source) => source.read().await,
+            Source::Simulator(simulator) => Ok(simulator.read().await),
+        }
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        match self {
+            Source::Bluetooth(device) => device.disconnect().await,
+            // Dropping the receiver ends the reader thread after its next line
+            Source::Lines(_) | Source::Replay(_) | Source::Simulator(_) => Ok(()),
+        }
+    }
+}
+
+impl LineSource {
+    /// Starts reading lines in the background and waits for the first payload,
+    /// so that the channel count from the header comments is known afterwards.
+    pub async fn open<R: std::io::Read + Send + 'static>(
+        description: String,
+        reader: R,
+        interval: Option<Duration>,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel(LINE_BUFFER);
+        // Serial ports only offer blocking reads, so they get a thread of their own
+        std::thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                let failed = line.is_err();
+                if sender.blocking_send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut source = Self {
+            description,
+            channel_count: firmware::DEFAULT_CHANNEL_COUNT,
+            lines: receiver,
+            first_payload: None,
+            interval,
+        };
+        source.first_payload = Some(source.next_payload().await?);
+        Ok(source)
+    }
+
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        if let Some(payload) = self.first_payload.take() {
+            return Ok(payload);
+        }
+        if let Some(interval) = self.interval {
+            tokio::time::sleep(interval).await;
+        }
+        self.next_payload().await
+    }
+
+    async fn next_payload(&mut self) -> Result<Vec<u8>> {
+        loop {
+            let line = self.lines.recv().await.ok_or(Error::EndOfStream)??;
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(count) = comment.trim().strip_prefix("channels:") {
+                    self.channel_count = count
+                        .trim()
+                        .parse()
+                        .map_err(|_| Error::Parse(format!("Invalid channel count: {line}")))?;
+                }
+            } else if !line.is_empty() {
+                return parse_hex(line);
+            }
+        }
+    }
+}
+
+/// Measures how many packets per second actually arrive, to compare it with the
+/// firmware's firmware::BLE_NOTIFY_RATE
+#[derive(Clone, Debug, Default)]
+pub struct RateMeter {
+    arrivals: VecDeque<Instant>,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the arrival of a packet
+    pub fn tick(&mut self) {
+        self.tick_at(Instant::now());
+    }
+
+    fn tick_at(&mut self, now: Instant) {
+        self.arrivals.push_back(now);
+        while let Some(first) = self.arrivals.front() {
+            if now.duration_since(*first).as_secs_f64() <= RATE_WINDOW {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+    }
+
+    /// Packets per second over the last few seconds
+    pub fn rate(&self) -> f64 {
+        match (self.arrivals.front(), self.arrivals.back()) {
+            (Some(first), Some(last)) if self.arrivals.len() > 1 => {
+                let elapsed = last.duration_since(*first).as_secs_f64();
+                (self.arrivals.len() - 1) as f64 / elapsed.max(f64::EPSILON)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// The measured rate as a fraction of the rate that the firmware sends at
+    pub fn ratio(&self) -> f64 {
+        self.rate() / firmware::BLE_NOTIFY_RATE
+    }
+}
+
+/// Turns a transport into a stream of payloads, which ends after the first error
+pub fn into_stream<T: Transport + Send>(
+    transport: T,
+) -> impl Stream<Item = Result<Vec<u8>>> + Send {
+    stream::unfold(Some(transport), |transport| async move {
+        let mut transport = transport?;
+        match transport.read().await {
+            Ok(payload) => Some((Ok(payload), Some(transport))),
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+}
+
+/// Opens a serial port and, on Unix, switches it to raw mode, so that the terminal driver
+/// doesn't echo or translate anything.  Files and pipes, e.g. in tests, are opened as they are.
+fn open_serial(path: &Path) -> Result<std::fs::File> {
+    let port = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        let fd = port.as_raw_fd();
+        // SAFETY: fd stays open while `port` lives, and termios is a plain C struct
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                // The baud rate of the firmware's Serial.begin
+                libc::cfsetispeed(&mut termios, libc::B115200);
+                libc::cfsetospeed(&mut termios, libc::B115200);
+                termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+                termios.c_cc[libc::VMIN] = 1;
+                termios.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+            }
+        }
+    }
+    Ok(port)
+}
+
+/// Writes the header comment that LineSource expects at the start of a recording
+pub fn write_header(output: &mut impl Write, channel_count: i32) -> std::io::Result<()> {
+    writeln!(output, "# channels: {channel_count}")
+}
+
+/// Writes a payload as one line of the format that LineSource reads
+pub fn write_payload(output: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    writeln!(output, "{}", format_hex(payload))
+}
+
+pub fn format_hex(payload: &[u8]) -> String {
+    payload.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn parse_hex(line: &str) -> Result<Vec<u8>> {
+    let digits: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err(Error::Parse(format!("Odd number of hex digits: {line}")));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16)
+                .map_err(|_| Error::Parse(format!("Invalid hex payload: {line}")))
+        })
+        .collect()
+}
+
+/// How `psylink print` writes what it reads
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PrintFormat {
+    #[default]
+    Raw, // The bytes of each payload
+    Hex,     // The recording format described at the top, for use with file:PATH
+    Decoded, // One line per sample: the timestamp and the channel values
+    Csv,     // Like Decoded, with a header line
+    Jsonl,   // One JSON object per packet
+}
+
+/// The settings of `psylink print`
+#[derive(Clone, Debug, Default)]
+pub struct PrintOptions {
+    pub format: PrintFormat,
+    pub imu: bool, // Also print the gyroscope and accelerometer channels
+    pub max_packets: Option<usize>, // Stop after this many payloads
+    pub duration: Option<f64>, // Stop after this many seconds
+}
+
+/// What happened while printing, for the summary at the end
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrintSummary {
+    pub packets: usize,
+    pub lost_packets: usize,
+    pub duplicates: usize,
+    pub decode_errors: usize,
+}
+
+impl PrintFormat {
+    /// The line before the first sample, if the format has one
+    pub fn header(&self, names: &[String]) -> Option<String> {
+        match self {
+            PrintFormat::Csv => Some(format!("timestamp,{}", names.join(","))),
+            _ => None,
+        }
+    }
+
+    /// Formats the first `names.len()` channels of a decoded packet.  Raw and Hex
+    /// show payloads instead, so they return nothing here.
+    pub fn format_packet(&self, packet: &protocol::Packet, names: &[String]) -> Vec<String> {
+        let channels = &packet.samples[..names.len().min(packet.samples.len())];
+        let rows = |separator: &str| -> Vec<String> {
+            packet
+                .timestamps
+                .iter()
+                .enumerate()
+                .map(|(index, timestamp)| {
+                    let values: Vec<String> = channels
+                        .iter()
+                        .map(|channel| channel[index].to_string())
+                        .collect();
+                    format!("{timestamp:.4}{separator}{}", values.join(separator))
+                })
+                .collect()
+        };
+        match self {
+            PrintFormat::Raw | PrintFormat::Hex => vec![],
+            PrintFormat::Decoded => rows(" "),
+            PrintFormat::Csv => rows(","),
+            PrintFormat::Jsonl => {
+                let timestamps: Vec<f64> = packet
+                    .timestamps
+                    .iter()
+                    .map(|timestamp| (timestamp * 1e4).round() / 1e4)
+                    .collect();
+                let channels: serde_json::Map<String, serde_json::Value> = names
+                    .iter()
+                    .zip(channels)
+                    .map(|(name, channel)| (name.clone(), channel.clone().into()))
+                    .collect();
+                vec![serde_json::json!({
+                    "tick": packet.tick,
+                    "lost_packets": packet.lost_packets,
+                    "filled_samples": packet.filled_samples,
+                    "timestamps": timestamps,
+                    "channels": channels,
+                })
+                .to_string()]
+            }
+        }
+    }
+}
+
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> std::result::Result<Self, Self::Err> {
+        match text.to_lowercase().as_str() {
+            "raw" => Ok(PrintFormat::Raw),
+            "hex" => Ok(PrintFormat::Hex),
+            "decoded" => Ok(PrintFormat::Decoded),
+            "csv" => Ok(PrintFormat::Csv),
+            "jsonl" => Ok(PrintFormat::Jsonl),
+            _ => Err(format!(
+                "Unknown format \"{text}\", expected raw, hex, decoded, csv or jsonl"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PrintSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Received {} packets, {} lost, {} duplicates, {} undecodable",
+            self.packets, self.lost_packets, self.duplicates, self.decode_errors
+        )
+    }
+}
+
+/// Writes the signals of a source to the console until it runs dry, one of the limits
+/// in `options` is reached, or the user presses Ctrl+C.  Then prints a summary of the
+/// lost and duplicate packets.  Status messages go to stderr, so stdout can be piped.
+pub async fn stream(app: &App, options: &PrintOptions) -> Result<PrintSummary> {
+    if app.source.len() > 1 {
+        eprintln!(
+            "Only reading from the first of {} sources",
+            app.source.len()
+        );
+    }
+    let mut source = app.source.first().open(app).await?;
+    eprintln!("Reading from {}", source.description());
+
+    let layout = source.layout();
+    let mut decoder = protocol::Decoder::new(source.channel_count());
+    let mut names = layout.channel_names();
+    if !options.imu {
+        names.truncate(layout.emg_channels);
+    }
+    let mut out = std::io::stdout();
+    if options.format == PrintFormat::Hex {
+        write_header(&mut out, source.channel_count())?;
+    }
+    if let Some(header) = options.format.header(&names) {
+        writeln!(out, "{header}")?;
+    }
+
+    let deadline = options
+        .duration
+        .map(|seconds| Instant::now() + Duration::from_secs_f64(seconds));
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
+    let mut summary = PrintSummary::default();
+    let result = loop {
+        if options
+            .max_packets
+            .is_some_and(|max_packets| summary.packets >= max_packets)
+        {
+            break Ok(());
+        }
+        let payload = tokio::select! {
+            payload = source.read() => payload,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break Ok(()),
+            _ = &mut interrupted => break Ok(()),
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(Error::EndOfStream) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+        summary.packets += 1;
+        let written = match options.format {
+            PrintFormat::Raw => writeln!(out, "{payload:?}"),
+            PrintFormat::Hex => write_payload(&mut out, &payload),
+            _ => Ok(()),
+        };
+        if let Err(err) = written {
+            break Err(err.into());
+        }
+
+        let packet = match decoder.decode_packet(payload, true, true) {
+            Ok(packet) => packet,
+            Err(err) => {
+                eprintln!("Failed to decode packet: {err}");
+                summary.decode_errors += 1;
+                if let DecodeError::ImpossibleTickJump { .. } = err {
+                    decoder.reset();
+                }
+                continue;
+            }
+        };
+        summary.lost_packets += packet.lost_packets as usize;
+        if packet.is_duplicate {
+            summary.duplicates += 1;
+            continue;
+        }
+        let lines = options.format.format_packet(&packet, &names);
+        if let Err(err) = lines.iter().try_for_each(|line| writeln!(out, "{line}")) {
+            break Err(err.into());
+        }
+    };
+
+    let _ = source.disconnect().await;
+    eprintln!("{summary}");
+    match result {
+        // The reader of the output went away, e.g. `psylink print | head`
+        Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(summary),
+        result => result.map(|_| summary),
+    }
+}
+
+#[test]
+fn test_source_spec() {
+    assert_eq!("ble".parse(), Ok(SourceSpec::default()));
+    assert_eq!("sim".parse(), Ok(SourceSpec::Simulator(None)));
+    assert!("sim:gesture 1 from 2s to 4s".parse::<SourceSpec>().is_ok());
+    assert!("sim:gesture 1".parse::<SourceSpec>().is_err());
+    assert_eq!(
+        "serial:/dev/ttyACM0".parse(),
+        Ok(SourceSpec::Serial("/dev/ttyACM0".into()))
+    );
+    assert!("file:".parse::<SourceSpec>().is_err());
+    assert!("usb".parse::<SourceSpec>().is_err());
+    assert!("sim,loop".parse::<SourceSpec>().is_err());
+    assert!("replay".parse::<SourceSpec>().is_err());
+    for spec in [
+        "ble",
+        "ble:address=AA:BB:CC:DD:EE:FF,adapter=hci1",
+        "ble:last",
+        "sim",
+        "serial:/dev/ttyACM0",
+        "file:/tmp/rec.txt",
+        "replay:/tmp/rec.psylink",
+        "replay,speed=0.5,loop:C:\\rec.csv",
+    ] {
+        assert_eq!(spec.parse::<SourceSpec>().unwrap().to_string(), spec);
+    }
+
+    let list: SourceList = "ble:address=AA:BB:CC:DD:EE:FF+ ble:address=11:22:33:44:55:66"
+        .parse()
+        .unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(
+        list.to_string(),
+        "ble:address=AA:BB:CC:DD:EE:FF + ble:address=11:22:33:44:55:66"
+    );
+    assert_eq!("ble".parse(), Ok(SourceList::default()));
+    assert!("sim + usb".parse::<SourceList>().is_err());
+
+    // A doubled '+' belongs to the source
+    let list: SourceList = "file:/tmp/a++b.txt + sim".parse().unwrap();
+    assert_eq!(list.first(), &SourceSpec::File("/tmp/a+b.txt".into()));
+    assert_eq!(list.to_string(), "file:/tmp/a++b.txt + sim");
+    assert_eq!(list.to_string().parse(), Ok(list));
+}
+
+#[tokio::test]
+async fn test_line_source() {
+    let mut simulator = Simulator::new(4);
+    simulator.set_realtime(false);
+    let payloads: Vec<Vec<u8>> = (0..3).map(|_| simulator.next_payload()).collect();
+
+    let mut recording = vec![];
+    write_header(&mut recording, 4).unwrap();
+    for payload in &payloads {
+        write_payload(&mut recording, payload).unwrap();
+    }
+
+    let reader = std::io::Cursor::new(recording);
+    let mut source = Source::Lines(LineSource::open("test".into(), reader, None).await.unwrap());
+    assert_eq!(source.channel_count(), 4);
+
+    let mut decoder = protocol::Decoder::new(source.channel_count());
+    for payload in &payloads {
+        assert_eq!(&source.read().await.unwrap(), payload);
+    }
+    assert!(matches!(source.read().await, Err(Error::EndOfStream)));
+
+    let mut simulator = Source::Simulator(Box::new(simulator));
+    let packet = simulator
+        .read_packet(&mut decoder, true, true)
+        .await
+        .unwrap();
+    assert_eq!(packet.channel_count, 4 + firmware::IMU_CHANNELS);
+    assert_eq!(packet.sample_count, 25);
+}
+
+#[test]
+fn test_rate_meter() {
+    let mut meter = RateMeter::new();
+    assert_eq!(meter.rate(), 0.0);
+    let start = Instant::now();
+    for i in 0..100 {
+        meter.tick_at(start + Duration::from_millis(i * 50));
+    }
+    // Only the last RATE_WINDOW seconds count
+    assert_eq!(meter.arrivals.len(), 41);
+    assert!((meter.rate() - 20.0).abs() < 1e-9);
+    assert!((meter.ratio() - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_print_format() {
+    let mut simulator = Simulator::new(2);
+    simulator.set_realtime(false);
+    let mut decoder = protocol::Decoder::new(2);
+    let packet = decoder
+        .decode_packet(simulator.next_payload(), true, true)
+        .unwrap();
+    let layout = protocol::ChannelLayout::new(2);
+    let names: Vec<String> = layout.channel_names()[..2].to_vec();
+
+    assert_eq!(
+        PrintFormat::Csv.header(&names),
+        Some("timestamp,EMG1,EMG2".into())
+    );
+    let lines = PrintFormat::Csv.format_packet(&packet, &names);
+    assert_eq!(lines.len(), packet.timestamps.len());
+    assert_eq!(
+        lines[1],
+        format!(
+            "{:.4},{},{}",
+            packet.timestamps[1], packet.samples[0][1], packet.samples[1][1]
+        )
+    );
+    let lines = PrintFormat::Decoded.format_packet(&packet, &layout.channel_names());
+    assert_eq!(lines[0].split(' ').count(), 1 + layout.total_channels());
+    let lines = PrintFormat::Jsonl.format_packet(&packet, &names);
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(&format!("{{\"tick\":{},", packet.tick)));
+    assert!(lines[0].contains("\"EMG2\":["));
+    let object: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(object["channels"]["EMG2"][1], packet.samples[1][1]);
+    assert_eq!(
+        object["timestamps"].as_array().unwrap().len(),
+        packet.timestamps.len()
+    );
+    assert!(PrintFormat::Hex.format_packet(&packet, &names).is_empty());
+    assert_eq!("jsonl".parse(), Ok(PrintFormat::Jsonl));
+    assert!("yaml".parse::<PrintFormat>().is_err());
+}